@@ -0,0 +1,917 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for alerting on the metrics of devices.
+//!
+//! Alert rules are given in the configuration and compare a metric of
+//! the devices matching a pattern with a threshold, for example:
+//!
+//! ```toml
+//! [[alerts]]
+//! name = "disk-full"
+//! device = "disk:*"
+//! metric = "used_percent"
+//! above = 90
+//! clear = 85
+//! for = 300
+//! ```
+//!
+//! Each agent evaluates the rules against the devices it owns. An
+//! alert starts firing when the threshold has been crossed for the
+//! given time, and is resolved when the value crosses the clear
+//! threshold back, which keeps a value hovering around the threshold
+//! from flapping the alert. The rules should be the same on all
+//! agents.
+//!
+//! Transitions between firing and resolved are gossiped to the
+//! cluster and exchanged during anti-entropy, so that all agents agree
+//! on which alerts are firing. Like device updates, the transitions
+//! carry a version issued by the owner of the device and are signed
+//! by the owner.
+
+use crate::clock::{SharedClock, Version};
+use crate::config::seconds;
+use crate::error::Error;
+use crate::gossip::Action;
+use crate::signing::{Signature, Signer, TrustedKeys};
+use crate::state::State;
+use crate::sync;
+use crate::watch::{Event, Watchers};
+use futures::sync::mpsc::UnboundedSender;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Default time that resolved alerts are remembered.
+pub const DEFAULT_RESOLVED_HORIZON: Duration = Duration::from_secs(3600);
+
+/// State of an alert.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+impl fmt::Display for AlertState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AlertState::Firing => write!(f, "firing"),
+            AlertState::Resolved => write!(f, "resolved"),
+        }
+    }
+}
+
+fn any_device() -> String {
+    "*".to_string()
+}
+
+/// Rule comparing a metric with a threshold.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Name of the rule, unique among the rules.
+    pub name: String,
+
+    /// Pattern matching the names of the devices the rule applies to.
+    /// A `*` matches any number of characters and a `?` matches a
+    /// single character.
+    #[serde(default = "any_device")]
+    pub device: String,
+
+    /// Name of the metric to compare. The metric has to be numeric.
+    pub metric: String,
+
+    /// Fire when the value is above this threshold.
+    pub above: Option<f64>,
+
+    /// Fire when the value is below this threshold.
+    pub below: Option<f64>,
+
+    /// Resolve when the value crosses this threshold back, if not the
+    /// threshold firing the alert.
+    pub clear: Option<f64>,
+
+    /// Time that the threshold has to be crossed before the alert
+    /// fires.
+    #[serde(default, rename = "for", deserialize_with = "seconds")]
+    pub duration: Duration,
+}
+
+impl Rule {
+    /// Check that the rule has exactly one threshold and that the clear
+    /// threshold is on the right side of it.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.above, self.below, self.clear) {
+            (Some(_), Some(_), _) | (None, None, _) => {
+                Err("exactly one of above and below has to be given".to_string())
+            }
+            (Some(above), None, Some(clear)) if clear > above => {
+                Err("clear has to be at most the above threshold".to_string())
+            }
+            (None, Some(below), Some(clear)) if clear < below => {
+                Err("clear has to be at least the below threshold".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check if the rule applies to a device.
+    pub fn matches(&self, device: &str) -> bool {
+        glob_matches(&self.device, device)
+    }
+
+    /// Check if a value breaches the rule.
+    ///
+    /// An alert that is not firing needs the value to cross the
+    /// threshold, while an alert that is firing stays firing until the
+    /// value crosses the clear threshold.
+    pub fn is_breached(&self, value: f64, firing: bool) -> bool {
+        match (self.above, self.below) {
+            (Some(above), _) => {
+                let threshold = if firing {
+                    self.clear.unwrap_or(above)
+                } else {
+                    above
+                };
+                value > threshold
+            }
+            (None, Some(below)) => {
+                let threshold = if firing {
+                    self.clear.unwrap_or(below)
+                } else {
+                    below
+                };
+                value < threshold
+            }
+            (None, None) => false,
+        }
+    }
+}
+
+/// Match a name against a pattern where `*` matches any number of
+/// characters and `?` matches a single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Transition of an alert, gossiped by the agent owning the device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertUpdate {
+    /// The agent owning the device and evaluating the rule.
+    pub origin: Uuid,
+    pub rule: String,
+    pub device: String,
+    pub metric: String,
+    pub state: AlertState,
+
+    /// Value of the metric at the transition.
+    pub value: f64,
+    pub version: Version,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+}
+
+impl AlertUpdate {
+    /// Construct an unsigned transition without a version.
+    fn new(origin: Uuid, rule: &Rule, device: &str, state: AlertState, value: f64) -> AlertUpdate {
+        AlertUpdate {
+            origin,
+            rule: rule.name.clone(),
+            device: device.to_string(),
+            metric: rule.metric.clone(),
+            state,
+            value,
+            version: Version::default(),
+            signature: None,
+        }
+    }
+}
+
+/// Alerts of all agents, as last reported by the owners.
+#[derive(Debug)]
+pub struct AlertCollection {
+    alerts: HashMap<Uuid, HashMap<(String, String), AlertUpdate>>,
+
+    /// Version of the alerts for each agent, which is the version of
+    /// the latest transition for the agent.
+    versions: HashMap<Uuid, Version>,
+
+    /// Versions of resolved alerts that have been collected, so that
+    /// older transitions arriving late are ignored and collecting an
+    /// alert does not change the fingerprint of the agent. Tombstones
+    /// are removed after another horizon.
+    tombstones: HashMap<Uuid, HashMap<(String, String), Version>>,
+
+    /// Time that resolved alerts are remembered.
+    horizon: Duration,
+
+    /// Keys used to verify that transitions are signed by the owner.
+    /// Transitions are not verified if there are no keys.
+    trusted_keys: Option<TrustedKeys>,
+
+    /// Subscribers to transitions of alerts.
+    watchers: Arc<Watchers>,
+
+    /// Queue of transitions for the notifier. Unlike the buffers of
+    /// the subscribers, the queue is unbounded, so no transitions are
+    /// lost when metrics change in bursts.
+    transitions: Option<UnboundedSender<Event>>,
+}
+
+impl AlertCollection {
+    pub fn new() -> AlertCollection {
+        AlertCollection {
+            alerts: HashMap::new(),
+            versions: HashMap::new(),
+            tombstones: HashMap::new(),
+            horizon: DEFAULT_RESOLVED_HORIZON,
+            trusted_keys: None,
+            watchers: Arc::new(Watchers::new()),
+            transitions: None,
+        }
+    }
+
+    /// Set the time that resolved alerts are remembered.
+    pub fn set_horizon(&mut self, horizon: Duration) {
+        self.horizon = horizon;
+    }
+
+    /// Set the keys used to verify transitions.
+    pub fn set_trusted_keys(&mut self, trusted_keys: Option<TrustedKeys>) {
+        self.trusted_keys = trusted_keys;
+    }
+
+    /// Set the subscribers that transitions are published to.
+    pub fn set_watchers(&mut self, watchers: Arc<Watchers>) {
+        self.watchers = watchers;
+    }
+
+    /// Set the queue that transitions are sent to for notification.
+    pub fn set_transitions(&mut self, transitions: UnboundedSender<Event>) {
+        self.transitions = Some(transitions);
+    }
+
+    /// Get an alert.
+    pub fn get(&self, origin: &Uuid, rule: &str, device: &str) -> Option<&AlertUpdate> {
+        self.alerts
+            .get(origin)
+            .and_then(|map| map.get(&(rule.to_string(), device.to_string())))
+    }
+
+    /// Iterate over all alerts, both firing and resolved.
+    pub fn iter(&self) -> impl Iterator<Item = &AlertUpdate> {
+        self.alerts.values().flat_map(|map| map.values())
+    }
+
+    /// Get all alerts of an agent.
+    pub fn owned_by(&self, origin: &Uuid) -> Vec<&AlertUpdate> {
+        self.alerts
+            .get(origin)
+            .map(|map| map.values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Apply a transition sent by `sender`, unless the current state
+    /// of the alert is newer. Returns `true` if the transition was
+    /// applied.
+    ///
+    /// Without trusted keys, signatures cannot be verified, so only
+    /// transitions sent by the owner of the alert are accepted.
+    pub fn update(&mut self, update: &AlertUpdate, sender: &Uuid) -> bool {
+        if self.trusted_keys.is_none() && update.origin != *sender {
+            warn!("Rejected alert of {} sent by {}", update.origin, sender);
+            return false;
+        }
+        self.apply(update)
+    }
+
+    /// Apply a transition, unless the current state of the alert is
+    /// newer, or the alert was collected with a newer version.
+    fn apply(&mut self, update: &AlertUpdate) -> bool {
+        if let Some(ref trusted_keys) = self.trusted_keys {
+            if let Err(err) = trusted_keys.verify_alert(update) {
+                warn!("Rejected alert of {}: {}", update.origin, err);
+                return false;
+            }
+        }
+        let key = (update.rule.clone(), update.device.clone());
+        if let Some(tombstones) = self.tombstones.get_mut(&update.origin) {
+            match tombstones.get(&key) {
+                Some(version) if *version >= update.version => return false,
+                Some(_) => {
+                    tombstones.remove(&key);
+                }
+                None => (),
+            }
+        }
+        let alerts = self.alerts.entry(update.origin).or_default();
+        let previous = match alerts.get(&key) {
+            Some(current) if current.version >= update.version => return false,
+            Some(current) => Some(current.state),
+            None => None,
+        };
+        alerts.insert(key, update.clone());
+        let current = self.versions.entry(update.origin).or_default();
+        *current = std::cmp::max(*current, update.version);
+
+        match (previous, update.state) {
+            (Some(AlertState::Firing), AlertState::Firing)
+            | (None, AlertState::Resolved)
+            | (Some(AlertState::Resolved), AlertState::Resolved) => (),
+            (_, AlertState::Firing) => {
+                warn!(
+                    "Alert {} is firing for device {} on {}: {} is {}",
+                    update.rule, update.device, update.origin, update.metric, update.value
+                );
+                self.publish(Event::AlertFiring {
+                    owner: update.origin,
+                    rule: update.rule.clone(),
+                    device: update.device.clone(),
+                    metric: update.metric.clone(),
+                    value: update.value,
+                    version: update.version,
+                });
+            }
+            (Some(AlertState::Firing), AlertState::Resolved) => {
+                info!(
+                    "Alert {} is resolved for device {} on {}: {} is {}",
+                    update.rule, update.device, update.origin, update.metric, update.value
+                );
+                self.publish(Event::AlertResolved {
+                    owner: update.origin,
+                    rule: update.rule.clone(),
+                    device: update.device.clone(),
+                    metric: update.metric.clone(),
+                    value: update.value,
+                    version: update.version,
+                });
+            }
+        }
+        true
+    }
+
+    /// Publish a transition to the subscribers and the notifier.
+    fn publish(&self, event: Event) {
+        if let Some(ref transitions) = self.transitions {
+            if transitions.unbounded_send(event.clone()).is_err() {
+                debug!("Notifier is gone, not queueing {}", event.name());
+            }
+        }
+        self.watchers.publish(event);
+    }
+
+    /// Get the version of the alerts for each agent.
+    pub fn digest(&self) -> HashMap<Uuid, Version> {
+        self.versions.clone()
+    }
+
+    /// Get a fingerprint of the alerts of each agent, covering the
+    /// version of each alert, whether collected or not.
+    pub fn fingerprints(&self) -> HashMap<Uuid, u64> {
+        self.versions
+            .keys()
+            .map(|origin| (*origin, self.fingerprint(origin)))
+            .collect()
+    }
+
+    fn fingerprint(&self, origin: &Uuid) -> u64 {
+        let alerts = self
+            .owned_by(origin)
+            .into_iter()
+            .map(|alert| ((&alert.rule, &alert.device), alert.version));
+        let tombstones = self
+            .tombstones
+            .get(origin)
+            .into_iter()
+            .flat_map(|map| map.iter())
+            .map(|((rule, device), version)| ((rule, device), *version));
+        sync::fingerprint(
+            alerts
+                .chain(tombstones)
+                .map(|((rule, device), version)| (format!("{}\0{}", rule, device), version)),
+        )
+    }
+
+    /// Get all alerts of the agents with a version newer than in the
+    /// digest, or with a fingerprint that differs from the digest.
+    pub fn delta(
+        &self,
+        digest: &HashMap<Uuid, Version>,
+        fingerprints: &HashMap<Uuid, u64>,
+    ) -> Vec<AlertUpdate> {
+        self.versions
+            .iter()
+            .filter(|(origin, version)| {
+                digest.get(origin).is_none_or(|v| v < *version)
+                    || fingerprints
+                        .get(origin)
+                        .is_some_and(|fingerprint| *fingerprint != self.fingerprint(origin))
+            })
+            .flat_map(|(origin, _)| self.owned_by(origin))
+            .cloned()
+            .collect()
+    }
+
+    /// Merge alerts sent by `sender` during anti-entropy.
+    ///
+    /// Without trusted keys, only the alerts of `sender` itself are
+    /// merged, like in `update`.
+    pub fn merge(&mut self, alerts: &[AlertUpdate], sender: &Uuid) {
+        for alert in alerts {
+            if self.trusted_keys.is_none() && alert.origin != *sender {
+                debug!("Ignoring alert of {} sent by {}", alert.origin, sender);
+                continue;
+            }
+            self.apply(alert);
+        }
+    }
+
+    /// Replace resolved alerts older than the horizon with tombstones,
+    /// and remove tombstones older than another horizon. The version
+    /// of an agent is forgotten with its last alert or tombstone.
+    pub fn collect_garbage(&mut self, now_millis: i64) {
+        let horizon = self.horizon.as_millis() as i64;
+        let oldest = now_millis - horizon;
+        for (origin, alerts) in self.alerts.iter_mut() {
+            let tombstones = self.tombstones.entry(*origin).or_default();
+            alerts.retain(|key, alert| {
+                let collected =
+                    alert.state == AlertState::Resolved && alert.version.millis < oldest;
+                if collected {
+                    tombstones.insert(key.clone(), alert.version);
+                }
+                !collected
+            });
+        }
+        for tombstones in self.tombstones.values_mut() {
+            tombstones.retain(|_, version| version.millis >= oldest - horizon);
+        }
+        self.alerts.retain(|_, alerts| !alerts.is_empty());
+        self.tombstones
+            .retain(|_, tombstones| !tombstones.is_empty());
+
+        let alerts = &self.alerts;
+        let tombstones = &self.tombstones;
+        self.versions
+            .retain(|origin, _| alerts.contains_key(origin) || tombstones.contains_key(origin));
+    }
+}
+
+impl Default for AlertCollection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Evaluator of the alert rules against the devices owned by the
+/// agent.
+pub struct Evaluator {
+    signer: Arc<Signer>,
+    clock: SharedClock,
+    rules: Vec<Rule>,
+
+    /// Time when the threshold was first crossed, for alerts that do
+    /// not fire yet.
+    pending: HashMap<(String, String), i64>,
+}
+
+impl Evaluator {
+    pub fn new(signer: Arc<Signer>, clock: SharedClock, rules: Vec<Rule>) -> Evaluator {
+        Evaluator {
+            signer,
+            clock,
+            rules,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Replace the rules. Alerts of rules that are removed are
+    /// resolved on the next evaluation.
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.rules = rules;
+        self.pending.clear();
+    }
+
+    /// Evaluate the rules against the current value of the metrics.
+    ///
+    /// Returns the transitions of alerts to publish. Firing alerts
+    /// whose rule, device, or metric is gone are resolved.
+    pub fn tick(&mut self, state: &State, now_millis: i64) -> Vec<Action> {
+        let uuid = *self.signer.uuid();
+        let devices = state
+            .devices
+            .lock()
+            .expect("unable to lock device collection for alerts");
+        let alerts = state.alerts.lock().expect("unable to lock alerts");
+        let mut evaluated = HashSet::new();
+        let mut transitions = Vec::new();
+
+        for rule in &self.rules {
+            for info in devices.owned_by(&uuid) {
+                if !rule.matches(&info.name) {
+                    continue;
+                }
+                let value = match info
+                    .metrics
+                    .get(&rule.metric)
+                    .and_then(|e| e.value.as_f64())
+                {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let key = (rule.name.clone(), info.name.clone());
+                let current = alerts.get(&uuid, &rule.name, &info.name);
+                let firing = current.is_some_and(|alert| alert.state == AlertState::Firing);
+                evaluated.insert(key.clone());
+
+                if rule.is_breached(value, firing) {
+                    if firing {
+                        continue;
+                    }
+                    let since = *self.pending.entry(key.clone()).or_insert(now_millis);
+                    if now_millis - since >= rule.duration.as_millis() as i64 {
+                        self.pending.remove(&key);
+                        transitions.push((
+                            AlertUpdate::new(uuid, rule, &info.name, AlertState::Firing, value),
+                            current.map(|alert| alert.version),
+                        ));
+                    }
+                } else {
+                    self.pending.remove(&key);
+                    if firing {
+                        transitions.push((
+                            AlertUpdate::new(uuid, rule, &info.name, AlertState::Resolved, value),
+                            current.map(|alert| alert.version),
+                        ));
+                    }
+                }
+            }
+        }
+
+        for alert in alerts.owned_by(&uuid) {
+            if alert.state == AlertState::Firing
+                && !evaluated.contains(&(alert.rule.clone(), alert.device.clone()))
+            {
+                let mut update = alert.clone();
+                update.state = AlertState::Resolved;
+                transitions.push((update, Some(alert.version)));
+            }
+        }
+        self.pending.retain(|key, _| evaluated.contains(key));
+
+        transitions
+            .into_iter()
+            .map(|(mut update, previous)| {
+                let mut clock = self.clock.lock().expect("unable to lock clock");
+                if let Some(ref version) = previous {
+                    clock.observe(version);
+                }
+                update.version = clock.tick();
+                self.signer.sign_alert(&mut update);
+                Action::Alert(update)
+            })
+            .collect()
+    }
+}
+
+/// Check that the rules are valid and have unique names.
+pub fn validate_rules(rules: &[Rule]) -> Result<(), Error> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        rule.validate()
+            .map_err(|msg| Error::ConfigError(format!("alert {}: {}", rule.name, msg)))?;
+        if !names.insert(&rule.name) {
+            return Err(Error::ConfigError(format!(
+                "alert {} is defined more than once",
+                rule.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::devices::{DeviceUpdate, Metric};
+    use std::sync::Mutex;
+
+    fn rule(clear: Option<f64>, duration: u64) -> Rule {
+        Rule {
+            name: "disk-full".to_string(),
+            device: "disk:*".to_string(),
+            metric: "used".to_string(),
+            above: Some(90.0),
+            below: None,
+            clear,
+            duration: Duration::from_secs(duration),
+        }
+    }
+
+    struct Agent {
+        uuid: Uuid,
+        state: State,
+        evaluator: Evaluator,
+    }
+
+    impl Agent {
+        fn new(rule: Rule) -> Agent {
+            let signer = Arc::new(Signer::generate(Uuid::new_v4()));
+            let uuid = *signer.uuid();
+            let clock = Arc::new(Mutex::new(Clock::new()));
+            let mut state = State::new();
+            let added = DeviceUpdate::DeviceAdded {
+                origin: uuid,
+                name: "disk:/".to_string(),
+                description: "Root disk".to_string(),
+                version: Version::new(1, 0),
+                signature: None,
+            };
+            state.update_devices(&added, &uuid, 0);
+            Agent {
+                uuid,
+                state,
+                evaluator: Evaluator::new(signer, clock, vec![rule]),
+            }
+        }
+
+        /// Report a value at a time in seconds and evaluate the rules,
+        /// returning the new state of the alert if it changed.
+        fn report(&mut self, value: f64, secs: i64) -> Option<AlertState> {
+            let millis = secs * 1000;
+            let mut metrics = HashMap::new();
+            metrics.insert("used".to_string(), Metric::Float(value));
+            let status = DeviceUpdate::DeviceStatus {
+                origin: self.uuid,
+                name: "disk:/".to_string(),
+                metrics,
+                version: Version::new(millis + 1, 0),
+                signatures: HashMap::new(),
+            };
+            self.state.update_devices(&status, &self.uuid, millis);
+            let actions = self.evaluator.tick(&self.state, millis);
+            assert!(actions.len() <= 1);
+            actions.into_iter().next().map(|action| match action {
+                Action::Alert(update) => {
+                    self.state.update_alerts(&update, &self.uuid);
+                    update.state
+                }
+                other => panic!("unexpected action {:?}", other),
+            })
+        }
+    }
+
+    #[test]
+    fn clear_threshold_keeps_alert_firing() {
+        let rule = rule(Some(85.0), 0);
+        assert!(!rule.is_breached(88.0, false));
+        assert!(rule.is_breached(91.0, false));
+        assert!(rule.is_breached(88.0, true));
+        assert!(!rule.is_breached(84.0, true));
+
+        let below = Rule {
+            above: None,
+            below: Some(10.0),
+            clear: Some(15.0),
+            ..rule
+        };
+        assert!(!below.is_breached(12.0, false));
+        assert!(below.is_breached(9.0, false));
+        assert!(below.is_breached(12.0, true));
+        assert!(!below.is_breached(16.0, true));
+    }
+
+    #[test]
+    fn alert_does_not_flap_around_threshold() {
+        let mut agent = Agent::new(rule(Some(85.0), 0));
+        assert_eq!(agent.report(80.0, 0), None);
+        assert_eq!(agent.report(91.0, 10), Some(AlertState::Firing));
+        assert_eq!(agent.report(89.0, 20), None);
+        assert_eq!(agent.report(91.0, 30), None);
+        assert_eq!(agent.report(86.0, 40), None);
+        assert_eq!(agent.report(84.0, 50), Some(AlertState::Resolved));
+        assert_eq!(agent.report(89.0, 60), None);
+    }
+
+    #[test]
+    fn alert_fires_after_threshold_crossed_for_duration() {
+        let mut agent = Agent::new(rule(None, 300));
+        assert_eq!(agent.report(95.0, 0), None);
+        assert_eq!(agent.report(95.0, 200), None);
+        assert_eq!(agent.report(95.0, 299), None);
+        assert_eq!(agent.report(95.0, 300), Some(AlertState::Firing));
+        assert_eq!(agent.report(95.0, 400), None);
+    }
+
+    #[test]
+    fn duration_restarts_when_value_recovers() {
+        let mut agent = Agent::new(rule(None, 300));
+        assert_eq!(agent.report(95.0, 0), None);
+        assert_eq!(agent.report(80.0, 200), None);
+        assert_eq!(agent.report(95.0, 250), None);
+        assert_eq!(agent.report(95.0, 500), None);
+        assert_eq!(agent.report(95.0, 550), Some(AlertState::Firing));
+    }
+
+    #[test]
+    fn alert_of_removed_rule_is_resolved() {
+        let mut agent = Agent::new(rule(None, 0));
+        assert_eq!(agent.report(95.0, 0), Some(AlertState::Firing));
+        agent.evaluator.set_rules(Vec::new());
+        assert_eq!(agent.report(95.0, 10), Some(AlertState::Resolved));
+    }
+
+    fn transition(origin: Uuid, device: &str, state: AlertState, version: Version) -> AlertUpdate {
+        AlertUpdate {
+            origin,
+            rule: "disk-full".to_string(),
+            device: device.to_string(),
+            metric: "used".to_string(),
+            state,
+            value: 95.0,
+            version,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn merge_ignores_alerts_of_others_without_trusted_keys() {
+        let sender = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let mut alerts = AlertCollection::new();
+        alerts.merge(
+            &[
+                transition(sender, "disk:/", AlertState::Firing, Version::new(1, 0)),
+                transition(other, "disk:/", AlertState::Firing, Version::new(1, 0)),
+            ],
+            &sender,
+        );
+        assert!(alerts.get(&sender, "disk-full", "disk:/").is_some());
+        assert!(alerts.get(&other, "disk-full", "disk:/").is_none());
+        assert!(!alerts.digest().contains_key(&other));
+    }
+
+    #[test]
+    fn collected_alerts_keep_fingerprint_and_reject_older_transitions() {
+        let origin = Uuid::new_v4();
+        let mut alerts = AlertCollection::new();
+        alerts.set_horizon(Duration::from_millis(100));
+        alerts.apply(&transition(
+            origin,
+            "disk:/",
+            AlertState::Resolved,
+            Version::new(1000, 0),
+        ));
+        alerts.apply(&transition(
+            origin,
+            "disk:/var",
+            AlertState::Resolved,
+            Version::new(1050, 0),
+        ));
+        let fingerprints = alerts.fingerprints();
+
+        alerts.collect_garbage(1120);
+        assert!(alerts.get(&origin, "disk-full", "disk:/").is_none());
+        assert!(alerts.get(&origin, "disk-full", "disk:/var").is_some());
+        assert_eq!(alerts.fingerprints(), fingerprints);
+
+        // Transitions older than the collected alert arriving late do
+        // not bring it back, but newer ones do.
+        let late = transition(origin, "disk:/", AlertState::Firing, Version::new(900, 0));
+        assert!(!alerts.apply(&late));
+        assert!(!alerts.apply(&transition(
+            origin,
+            "disk:/",
+            AlertState::Resolved,
+            Version::new(1000, 0)
+        )));
+        let newer = transition(origin, "disk:/", AlertState::Firing, Version::new(1200, 0));
+        assert!(alerts.apply(&newer));
+        assert_eq!(alerts.get(&origin, "disk-full", "disk:/"), Some(&newer));
+    }
+
+    #[test]
+    fn tombstones_and_versions_are_collected_after_horizon() {
+        let origin = Uuid::new_v4();
+        let mut alerts = AlertCollection::new();
+        alerts.set_horizon(Duration::from_millis(100));
+        alerts.apply(&transition(
+            origin,
+            "disk:/",
+            AlertState::Resolved,
+            Version::new(1000, 0),
+        ));
+
+        alerts.collect_garbage(1150);
+        assert_eq!(alerts.iter().count(), 0);
+        assert!(alerts.digest().contains_key(&origin));
+
+        alerts.collect_garbage(1250);
+        assert!(alerts.digest().is_empty());
+        assert!(alerts.fingerprints().is_empty());
+    }
+
+    #[test]
+    fn transitions_are_queued_for_notifier_during_bursts() {
+        use crate::watch::SUBSCRIBER_BUFFER;
+        use futures::sync::mpsc::unbounded;
+        use futures::Stream;
+
+        let origin = Uuid::new_v4();
+        let mut state = State::new();
+        let (queue, transitions) = unbounded();
+        state.alerts.lock().unwrap().set_transitions(queue);
+        let _events = state.subscribe();
+
+        let firing = transition(origin, "disk:/", AlertState::Firing, Version::new(1, 0));
+        state.update_alerts(&firing, &origin);
+        state.update_devices(
+            &DeviceUpdate::DeviceAdded {
+                origin,
+                name: "disk:/".to_string(),
+                description: String::new(),
+                version: Version::new(2, 0),
+                signature: None,
+            },
+            &origin,
+            0,
+        );
+        for value in 0..=SUBSCRIBER_BUFFER as i64 {
+            let mut metrics = HashMap::new();
+            metrics.insert("used".to_string(), Metric::Integer(value));
+            let status = DeviceUpdate::DeviceStatus {
+                origin,
+                name: "disk:/".to_string(),
+                metrics,
+                version: Version::new(3 + value, 0),
+                signatures: HashMap::new(),
+            };
+            state.update_devices(&status, &origin, 0);
+        }
+        assert!(state.watchers.is_empty());
+
+        let resolved = transition(
+            origin,
+            "disk:/",
+            AlertState::Resolved,
+            Version::new(5000, 0),
+        );
+        state.update_alerts(&resolved, &origin);
+        state.update_alerts(&resolved, &origin);
+        drop(state);
+        let events: Vec<&str> = transitions
+            .wait()
+            .map(|event| event.unwrap().name())
+            .collect();
+        assert_eq!(events, vec!["alert-firing", "alert-resolved"]);
+    }
+
+    #[test]
+    fn glob_matches_patterns() {
+        assert!(glob_matches("disk:*", "disk:/var"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("d?sk", "disk"));
+        assert!(glob_matches("*:*r", "disk:/var"));
+        assert!(!glob_matches("disk:*", "net:eth0"));
+        assert!(!glob_matches("d?sk", "dsk"));
+    }
+}
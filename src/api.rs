@@ -0,0 +1,467 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for the HTTP API of the agent.
+//!
+//! The API is read-only and serves the state of the agent as JSON:
+//!
+//! * `GET /members`: the servers in the view of the agent.
+//!
+//! * `GET /devices`: all devices known to the agent.
+//!
+//! * `GET /devices/<owner>`: the devices owned by an agent.
+//!
+//! * `GET /devices/<owner>/<name>`: a single device. Names containing
+//!   `/` have to be percent-encoded.
+//!
+//! * `GET /alerts`: the firing alerts and the recently resolved
+//!   alerts.
+//!
+//! The metrics of all devices and the counters of the agent are also
+//! served in the Prometheus text format on `GET /metrics`.
+//!
+//! Changes to the state are streamed as server-sent events on `GET
+//! /events`, see the `watch` module. The name of each event is given
+//! in the `event` field and the data is the event as JSON.
+
+use crate::alert::{AlertState, AlertUpdate};
+use crate::clock::Version;
+use crate::devices::{DeviceInfo, Metric};
+use crate::prometheus;
+use crate::state::State;
+use crate::view::ServerStatus;
+use crate::watch::Event;
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+/// Server in the view of the agent.
+#[derive(Serialize, Debug)]
+pub struct Member {
+    pub uuid: Uuid,
+    pub address: SocketAddr,
+    pub status: ServerStatus,
+    pub incarnation: u64,
+    pub last_seen: DateTime<Utc>,
+    pub version: i64,
+}
+
+/// Device with the current value of its metrics.
+#[derive(Serialize, Debug)]
+pub struct Device {
+    pub owner: Uuid,
+    pub name: String,
+    pub description: String,
+    pub version: Version,
+    pub metrics: BTreeMap<String, DeviceMetric>,
+}
+
+/// Alert of a device.
+#[derive(Serialize, Debug)]
+pub struct Alert {
+    pub owner: Uuid,
+    pub rule: String,
+    pub device: String,
+    pub metric: String,
+    pub state: AlertState,
+    pub value: f64,
+
+    /// Time of the latest transition of the alert.
+    pub since: DateTime<Utc>,
+}
+
+impl Alert {
+    fn new(alert: &AlertUpdate) -> Alert {
+        Alert {
+            owner: alert.origin,
+            rule: alert.rule.clone(),
+            device: alert.device.clone(),
+            metric: alert.metric.clone(),
+            state: alert.state,
+            value: alert.value,
+            since: DateTime::from_timestamp_millis(alert.version.millis).unwrap_or_default(),
+        }
+    }
+}
+
+/// Current value of a metric of a device.
+#[derive(Serialize, Debug)]
+pub struct DeviceMetric {
+    pub value: Metric,
+    pub version: Version,
+}
+
+impl Device {
+    fn new(info: &DeviceInfo) -> Device {
+        Device {
+            owner: info.owner,
+            name: info.name.clone(),
+            description: info.description.clone(),
+            version: info.version,
+            metrics: info
+                .metrics
+                .iter()
+                .map(|(name, entry)| {
+                    (
+                        name.clone(),
+                        DeviceMetric {
+                            value: entry.value.clone(),
+                            version: entry.version,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+/// Get the servers in the view, ordered by UUID.
+pub fn members(state: &State) -> Vec<Member> {
+    let view = state.view.lock().expect("unable to lock view for API");
+    let mut members: Vec<Member> = view
+        .servers
+        .iter()
+        .map(|(uuid, info)| Member {
+            uuid: *uuid,
+            address: info.address,
+            status: info.status,
+            incarnation: info.incarnation,
+            last_seen: info.last_seen.and_utc(),
+            version: info.version,
+        })
+        .collect();
+    members.sort_by_key(|member| member.uuid);
+    members
+}
+
+/// Get the devices, optionally only those of one owner, ordered by
+/// owner and name.
+pub fn devices(state: &State, owner: Option<&Uuid>) -> Vec<Device> {
+    let collection = state
+        .devices
+        .lock()
+        .expect("unable to lock device collection for API");
+    let mut devices: Vec<Device> = collection
+        .iter()
+        .filter(|info| owner.is_none_or(|owner| info.owner == *owner))
+        .map(Device::new)
+        .collect();
+    devices.sort_by(|a, b| (a.owner, &a.name).cmp(&(b.owner, &b.name)));
+    devices
+}
+
+/// Get a single device.
+pub fn device(state: &State, owner: &Uuid, name: &str) -> Option<Device> {
+    state
+        .devices
+        .lock()
+        .expect("unable to lock device collection for API")
+        .get(owner, name)
+        .map(Device::new)
+}
+
+/// Get the alerts, ordered by owner, device, and rule.
+pub fn alerts(state: &State) -> Vec<Alert> {
+    let mut alerts: Vec<Alert> = state
+        .alerts
+        .lock()
+        .expect("unable to lock alerts for API")
+        .iter()
+        .map(Alert::new)
+        .collect();
+    alerts.sort_by(|a, b| (a.owner, &a.device, &a.rule).cmp(&(b.owner, &b.device, &b.rule)));
+    alerts
+}
+
+/// Decode a percent-encoded path segment. Returns `None` if an escape
+/// is not followed by two hexadecimal digits, or if the result is not
+/// UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            let value = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+            bytes.push(value);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Build a response with a JSON body.
+pub fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    match serde_json::to_vec_pretty(body) {
+        Ok(bytes) => {
+            let mut response = Response::new(Body::from(bytes));
+            *response.status_mut() = status;
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(err) => {
+            error!("Unable to serialize response: {}", err);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
+
+/// Build a response with a plain text body.
+pub fn text(status: StatusCode, content_type: &'static str, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// Build a response streaming the changes of the state as server-sent
+/// events.
+pub fn events(state: &State) -> Response<Body> {
+    let events = state.subscribe().map(|event: Event| {
+        let data = serde_json::to_string(&event).expect("events can always be serialized");
+        format!("event: {}\ndata: {}\n\n", event.name(), data)
+    });
+    let mut response = Response::new(Body::wrap_stream(
+        events.map_err(|()| std::io::Error::other("event stream failed")),
+    ));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
+
+/// Build a response with an error message.
+pub fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, &ErrorBody { error: message })
+}
+
+/// Handle a request to the API.
+pub fn handle(request: &Request<Body>, state: &State) -> Response<Body> {
+    if request.method() != Method::GET {
+        return error(StatusCode::METHOD_NOT_ALLOWED, "only GET is supported");
+    }
+    let segments: Option<Vec<String>> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect();
+    let segments = match segments {
+        Some(segments) => segments,
+        None => return error(StatusCode::BAD_REQUEST, "invalid path"),
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    debug!("Handling API request for {}", request.uri());
+
+    match segments.as_slice() {
+        ["members"] => json(StatusCode::OK, &members(state)),
+        ["events"] => events(state),
+        ["alerts"] => json(StatusCode::OK, &alerts(state)),
+        ["metrics"] => text(
+            StatusCode::OK,
+            prometheus::CONTENT_TYPE,
+            prometheus::render(state),
+        ),
+        ["devices"] => json(StatusCode::OK, &devices(state, None)),
+        ["devices", owner] => match owner.parse::<Uuid>() {
+            Ok(owner) => json(StatusCode::OK, &devices(state, Some(&owner))),
+            Err(_) => error(StatusCode::BAD_REQUEST, "invalid owner UUID"),
+        },
+        ["devices", owner, name] => match owner.parse::<Uuid>() {
+            Ok(owner) => match device(state, &owner, name) {
+                Some(device) => json(StatusCode::OK, &device),
+                None => error(StatusCode::NOT_FOUND, "no such device"),
+            },
+            Err(_) => error(StatusCode::BAD_REQUEST, "invalid owner UUID"),
+        },
+        _ => error(StatusCode::NOT_FOUND, "no such resource"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::DeviceUpdate;
+    use crate::view::ViewUpdate;
+    use futures::Future;
+    use std::collections::HashMap;
+
+    fn populated() -> (State, Uuid, Uuid) {
+        let mut state = State::new();
+        let owner = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        for (origin, name) in &[(owner, "disk/var"), (owner, "cpu"), (other, "cpu")] {
+            state.update_devices(
+                &DeviceUpdate::DeviceAdded {
+                    origin: *origin,
+                    name: name.to_string(),
+                    description: format!("device {}", name),
+                    version: Version::new(1000, 0),
+                    signature: None,
+                },
+                origin,
+                0,
+            );
+        }
+        let mut metrics = HashMap::new();
+        metrics.insert("used_percent".to_string(), Metric::Float(93.5));
+        state.update_devices(
+            &DeviceUpdate::DeviceStatus {
+                origin: owner,
+                name: "disk/var".to_string(),
+                metrics,
+                version: Version::new(2000, 0),
+                signatures: HashMap::new(),
+            },
+            &owner,
+            0,
+        );
+        state.update_view(
+            &ViewUpdate::ServerAdded {
+                uuid: owner,
+                addr: "127.0.0.1:2428".parse().unwrap(),
+            },
+            &owner,
+            1000,
+        );
+        state.update_alerts(
+            &AlertUpdate {
+                origin: owner,
+                rule: "disk-full".to_string(),
+                device: "disk/var".to_string(),
+                metric: "used_percent".to_string(),
+                state: AlertState::Firing,
+                value: 93.5,
+                version: Version::new(3000, 0),
+                signature: None,
+            },
+            &owner,
+        );
+        (state, owner, other)
+    }
+
+    fn get(state: &State, path: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let response = handle(&request, state);
+        let status = response.status();
+        let body = response.into_body().concat2().wait().unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn percent_decode_requires_two_hex_digits() {
+        assert_eq!(percent_decode("disk%2Fvar").as_deref(), Some("disk/var"));
+        assert_eq!(percent_decode("%c3%a5").as_deref(), Some("\u{e5}"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+        for invalid in &["%+1", "%-1", "% 1", "%g0", "%2", "%", "abc%", "%ff"] {
+            assert_eq!(percent_decode(invalid), None, "decoded {:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn serves_members_devices_and_alerts() {
+        let (state, owner, other) = populated();
+
+        let (status, members) = get(&state, "/members");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(members[0]["uuid"], owner.to_string());
+        assert_eq!(members[0]["address"], "127.0.0.1:2428");
+        assert_eq!(members[0]["status"], "Alive");
+
+        let (status, devices) = get(&state, "/devices");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(devices.as_array().unwrap().len(), 3);
+
+        let (_, devices) = get(&state, &format!("/devices/{}", other));
+        assert_eq!(devices.as_array().unwrap().len(), 1);
+        assert_eq!(devices[0]["name"], "cpu");
+
+        let (status, device) = get(&state, &format!("/devices/{}/disk%2Fvar", owner));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(device["owner"], owner.to_string());
+        assert_eq!(device["description"], "device disk/var");
+        assert_eq!(device["metrics"]["used_percent"]["value"]["Float"], 93.5);
+        assert_eq!(device["metrics"]["used_percent"]["version"]["millis"], 2000);
+
+        let (status, alerts) = get(&state, "/alerts");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(alerts[0]["rule"], "disk-full");
+        assert_eq!(alerts[0]["state"], "firing");
+        assert_eq!(alerts[0]["since"], "1970-01-01T00:00:03Z");
+    }
+
+    #[test]
+    fn reports_errors_as_json() {
+        let (state, owner, _) = populated();
+        let missing = format!("/devices/{}/disk", owner);
+        let cases = vec![
+            ("/nothing", StatusCode::NOT_FOUND),
+            ("/devices/a/b/c", StatusCode::NOT_FOUND),
+            (&missing[..], StatusCode::NOT_FOUND),
+            ("/devices/not-a-uuid", StatusCode::BAD_REQUEST),
+            ("/devices/not-a-uuid/cpu", StatusCode::BAD_REQUEST),
+            ("/devices/%2", StatusCode::BAD_REQUEST),
+            ("/devices/%ff", StatusCode::BAD_REQUEST),
+        ];
+        for (path, expected) in cases {
+            let (status, body) = get(&state, path);
+            assert_eq!(status, expected, "{}", path);
+            assert!(body["error"].is_string(), "{}", path);
+        }
+
+        let request = Request::post("/devices").body(Body::empty()).unwrap();
+        assert_eq!(
+            handle(&request, &state).status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+
+    #[test]
+    fn serves_metrics_as_text() {
+        let (state, _, _) = populated();
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = handle(&request, &state);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            HeaderValue::from_static(prometheus::CONTENT_TYPE)
+        );
+        let body = response.into_body().concat2().wait().unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("chatter_used_percent{"));
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for authenticating messages using a shared secret.
+//!
+//! Each authenticated message carries an HMAC-SHA256 tag computed
+//! over the envelope header and the serialized message. The tag is
+//! computed using the primary key. A received message is accepted if
+//! the tag matches either the primary or the secondary key, which
+//! allows the key to be rotated without stopping the cluster:
+//!
+//! 1. Add the new key as secondary key on all agents.
+//!
+//! 2. Make the new key the primary key and the old key the secondary
+//!    key on all agents.
+//!
+//! 3. Remove the old key from all agents.
+
+use crate::error::Error;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs;
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the authentication tag.
+pub const TAG_SIZE: usize = 32;
+
+/// Minimum size of a key.
+pub const MIN_KEY_SIZE: usize = 16;
+
+/// Keys used to authenticate messages.
+#[derive(Clone)]
+pub struct AuthKeys {
+    primary: Vec<u8>,
+    secondary: Option<Vec<u8>>,
+}
+
+impl AuthKeys {
+    /// Construct authentication keys.
+    ///
+    /// # Parameters
+    ///
+    /// * `primary` - The key used to authenticate outgoing messages.
+    ///
+    /// * `secondary` - Additional key accepted for incoming messages
+    ///   while rotating keys.
+    ///
+    pub fn new(primary: Vec<u8>, secondary: Option<Vec<u8>>) -> Result<AuthKeys, Error> {
+        for key in std::iter::once(&primary).chain(secondary.iter()) {
+            if key.len() < MIN_KEY_SIZE {
+                return Err(Error::KeyError(format!(
+                    "key is {} bytes, but should be at least {} bytes",
+                    key.len(),
+                    MIN_KEY_SIZE
+                )));
+            }
+        }
+        Ok(AuthKeys { primary, secondary })
+    }
+
+    /// Load keys from files.
+    ///
+    /// The contents of each file, with leading and trailing whitespace
+    /// removed, is used as key.
+    pub fn load(primary: &Path, secondary: Option<&Path>) -> Result<AuthKeys, Error> {
+        AuthKeys::new(read_key(primary)?, secondary.map(read_key).transpose()?)
+    }
+
+    /// Compute the tag for data consisting of one or more parts using
+    /// the primary key.
+    pub fn sign(&self, parts: &[&[u8]]) -> [u8; TAG_SIZE] {
+        let mut tag = [0; TAG_SIZE];
+        tag.copy_from_slice(&mac(&self.primary, parts).finalize().into_bytes());
+        tag
+    }
+
+    /// Verify the tag of data consisting of one or more parts using
+    /// any of the keys.
+    pub fn verify(&self, parts: &[&[u8]], tag: &[u8]) -> Result<(), Error> {
+        let verified = std::iter::once(&self.primary)
+            .chain(self.secondary.iter())
+            .any(|key| mac(key, parts).verify_slice(tag).is_ok());
+        if verified {
+            Ok(())
+        } else {
+            Err(Error::AuthenticationError(
+                "message authentication failed".to_string(),
+            ))
+        }
+    }
+}
+
+fn read_key(path: &Path) -> Result<Vec<u8>, Error> {
+    let contents = fs::read(path)?;
+    let start = contents
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(contents.len());
+    let end = contents
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |pos| pos + 1);
+    Ok(contents[start..end].to_vec())
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIMARY: &[u8] = b"0123456789abcdef";
+    const SECONDARY: &[u8] = b"fedcba9876543210";
+    const FOREIGN: &[u8] = b"some other secret";
+
+    fn keys(primary: &[u8], secondary: Option<&[u8]>) -> AuthKeys {
+        AuthKeys::new(primary.to_vec(), secondary.map(|key| key.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn verifies_own_tags() {
+        let keys = keys(PRIMARY, None);
+        let tag = keys.sign(&[b"header", b"message"]);
+        assert!(keys.verify(&[b"header", b"message"], &tag).is_ok());
+        assert!(keys.verify(&[b"headermessage"], &tag).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_messages() {
+        let keys = keys(PRIMARY, None);
+        let tag = keys.sign(&[b"header", b"message"]);
+        assert!(keys.verify(&[b"header", b"massage"], &tag).is_err());
+        assert!(keys.verify(&[b"header"], &tag).is_err());
+
+        let mut tampered = tag;
+        tampered[0] ^= 1;
+        assert!(keys.verify(&[b"header", b"message"], &tampered).is_err());
+        assert!(keys.verify(&[b"header", b"message"], &tag[1..]).is_err());
+        assert!(keys.verify(&[b"header", b"message"], &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_foreign_keys() {
+        let tag = keys(FOREIGN, None).sign(&[b"message"]);
+        assert!(keys(PRIMARY, Some(SECONDARY))
+            .verify(&[b"message"], &tag)
+            .is_err());
+    }
+
+    #[test]
+    fn accepts_secondary_key_during_rotation() {
+        let old = keys(PRIMARY, None);
+        let rotating = keys(SECONDARY, Some(PRIMARY));
+        let new = keys(SECONDARY, None);
+
+        let tag = old.sign(&[b"message"]);
+        assert!(rotating.verify(&[b"message"], &tag).is_ok());
+        assert!(new.verify(&[b"message"], &tag).is_err());
+
+        let tag = rotating.sign(&[b"message"]);
+        assert!(new.verify(&[b"message"], &tag).is_ok());
+        assert!(old.verify(&[b"message"], &tag).is_err());
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        assert!(AuthKeys::new(b"short".to_vec(), None).is_err());
+        assert!(AuthKeys::new(PRIMARY.to_vec(), Some(b"short".to_vec())).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 extern crate chatter;
 
+use chatter::clock::Clock;
 use chatter::devices::DeviceUpdate;
 use chatter::gossip::Gossip;
 use chatter::view::ViewUpdate;
@@ -22,9 +23,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         name: "gateway".to_string(),
         origin: uuid,
         description: "ASUS Router model RT-N55U ".to_string(),
+        version: Clock::new().tick(),
+        signature: None,
     }));
     print_json(Gossip::ViewGossip(ViewUpdate::ServerAdded {
-        uuid: uuid,
+        uuid,
         addr: "127.0.0.1:8080".to_string().parse::<SocketAddr>()?,
     }));
     Ok(())
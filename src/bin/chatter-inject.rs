@@ -17,6 +17,15 @@
 //! This utility can be used to inject messages into the gossip
 //! network and do this by sending gossip to a server on the gossip
 //! port.  Currently, it can only inject debug messages.
+//!
+//! If the cluster authenticates gossip, set `CHATTER_AUTH_KEY` to the
+//! name of the file containing the key. If the cluster encrypts
+//! gossip, set `CHATTER_ENCRYPTION_KEY` to the name of the file
+//! containing the cluster key.
+//!
+//! If the cluster verifies device updates, set `CHATTER_DATA_DIR` to
+//! the data directory of the agent owning the devices. Device updates
+//! are then signed with the key of that agent.
 
 extern crate bytes;
 extern crate chatter;
@@ -26,12 +35,18 @@ extern crate env_logger;
 extern crate futures;
 extern crate serde_json;
 
-use chatter::gossip::{Gossip, Message};
-use chrono::Utc;
+use bytes::BytesMut;
+use chatter::auth::AuthKeys;
+use chatter::crypto::{ClusterKey, EncryptionMode};
+use chatter::gossip::{Gossip, GossipCodec, Message, DEFAULT_HOPS};
+use chatter::identity;
+use chatter::signing::Signer;
 use std::env;
 use std::io;
 use std::io::{stdin, Read};
 use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use tokio::codec::Encoder;
 use uuid::Uuid;
 
 fn read_from_stdin() -> Result<String, io::Error> {
@@ -52,19 +67,40 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "[::]:0"
         }
         .parse()?;
-        UdpSocket::bind(&local_addr)?
+        UdpSocket::bind(local_addr)?
     };
 
-    let json: Gossip = serde_json::from_str(&input)?;
+    let mut json: Gossip = serde_json::from_str(&input)?;
     debug!("Saw JSON:\n{:#?}", json);
-    let message = Message {
-        timestamp_millis: Utc::now().timestamp_millis(),
-        sender: Uuid::new_v4(),
-        hops: 5,
-        payload: Some(json),
+    let sender = match env::var_os("CHATTER_DATA_DIR") {
+        Some(dir) => {
+            let uuid = identity::load_or_create(Path::new(&dir))?;
+            let signer = Signer::load_or_create(uuid, Path::new(&dir))?;
+            if let Gossip::DeviceGossip(ref mut update) = json {
+                signer.sign(update);
+            }
+            uuid
+        }
+        // Agents without trusted keys only accept updates sent by
+        // their owner, so send them on behalf of the owner.
+        None => match json {
+            Gossip::DeviceGossip(ref update) => *update.origin(),
+            Gossip::AlertGossip(ref update) => update.origin,
+            _ => Uuid::new_v4(),
+        },
     };
+    let message = Message::new(sender, DEFAULT_HOPS, Some(json));
     debug!("Sending message:\n{:#?}", &message);
-    let bytes = serde_cbor::to_vec(&message)?;
-    socket.send_to(&bytes, &remote_addr)?;
+    let mut bytes = BytesMut::new();
+    let mut codec = match env::var_os("CHATTER_AUTH_KEY") {
+        Some(path) => GossipCodec::new().with_keys(AuthKeys::load(Path::new(&path), None)?)?,
+        None => GossipCodec::new(),
+    };
+    if let Some(path) = env::var_os("CHATTER_ENCRYPTION_KEY") {
+        let key = ClusterKey::load(Path::new(&path), None)?;
+        codec = codec.with_encryption(EncryptionMode::Optional, key)?;
+    }
+    codec.encode(message, &mut bytes)?;
+    socket.send_to(&bytes, remote_addr)?;
     Ok(())
 }
@@ -0,0 +1,183 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Command-line utility to administer an agent.
+//!
+//! The utility sends a request to the control socket of an agent and
+//! prints the reply as JSON. The path of the socket is given using
+//! `--socket` or in `CHATTER_CONTROL_SOCKET`.
+//!
+//! Values given to `set-metric` are read as JSON, so `42` is an
+//! integer, `true` a boolean, and `{"Duration": {"secs": 3, "nanos":
+//! 0}}` a typed metric. Values that are not valid JSON are text.
+
+extern crate chatter;
+extern crate serde_json;
+
+use chatter::control::{Request, Response};
+use chatter::devices::Metric;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+use uuid::Uuid;
+
+/// Build the request from the subcommand given.
+fn request(options: &ArgMatches) -> Result<Request, Box<dyn std::error::Error>> {
+    let request = match options.subcommand() {
+        ("members", _) => Request::Members,
+        ("devices", Some(args)) => Request::Devices {
+            owner: args.value_of("OWNER").map(str::parse::<Uuid>).transpose()?,
+        },
+        ("device", Some(args)) => Request::Device {
+            owner: args.value_of("OWNER").unwrap().parse()?,
+            name: args.value_of("NAME").unwrap().to_string(),
+        },
+        ("add-device", Some(args)) => Request::AddDevice {
+            name: args.value_of("NAME").unwrap().to_string(),
+            description: args.value_of("DESCRIPTION").unwrap_or("").to_string(),
+        },
+        ("remove-device", Some(args)) => Request::RemoveDevice {
+            name: args.value_of("NAME").unwrap().to_string(),
+        },
+        ("set-metric", Some(args)) => {
+            let value = args.value_of("VALUE").unwrap();
+            let value = match serde_json::from_str(value) {
+                Ok(json) => Metric::from_json(json).ok_or("invalid metric value")?,
+                Err(_) => Metric::Text(value.to_string()),
+            };
+            Request::SetMetric {
+                device: args.value_of("DEVICE").unwrap().to_string(),
+                metric: args.value_of("METRIC").unwrap().to_string(),
+                value,
+            }
+        }
+        ("join", Some(args)) => Request::Join {
+            address: args.value_of("ADDRESS").unwrap().parse()?,
+        },
+        ("leave", _) => Request::Leave,
+        ("stats", _) => Request::Stats,
+        ("alerts", _) => Request::Alerts,
+        (command, _) => return Err(format!("unknown command {}", command).into()),
+    };
+    Ok(request)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = App::new("Chatter Control")
+        .version("0.1")
+        .about("Administer a chatter agent through its control socket")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("socket")
+                .short("s")
+                .long("socket")
+                .value_name("PATH")
+                .env("CHATTER_CONTROL_SOCKET")
+                .help("Path of the control socket of the agent")
+                .required(true)
+                .takes_value(true),
+        )
+        .subcommand(SubCommand::with_name("members").about("Show the servers in the view"))
+        .subcommand(
+            SubCommand::with_name("devices")
+                .about("Show all devices, or the devices of one owner")
+                .arg(Arg::with_name("OWNER").help("UUID of the owner")),
+        )
+        .subcommand(
+            SubCommand::with_name("device")
+                .about("Show a device")
+                .arg(
+                    Arg::with_name("OWNER")
+                        .required(true)
+                        .help("UUID of the owner"),
+                )
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .help("Name of the device"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add-device")
+                .about("Add a device owned by the agent")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .help("Name of the device"),
+                )
+                .arg(Arg::with_name("DESCRIPTION").help("Description of the device")),
+        )
+        .subcommand(
+            SubCommand::with_name("remove-device")
+                .about("Remove a device owned by the agent")
+                .arg(
+                    Arg::with_name("NAME")
+                        .required(true)
+                        .help("Name of the device"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-metric")
+                .about("Set a metric of a device owned by the agent")
+                .arg(
+                    Arg::with_name("DEVICE")
+                        .required(true)
+                        .help("Name of the device"),
+                )
+                .arg(
+                    Arg::with_name("METRIC")
+                        .required(true)
+                        .help("Name of the metric"),
+                )
+                .arg(
+                    Arg::with_name("VALUE")
+                        .required(true)
+                        .help("Value of the metric, as JSON or text"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("join")
+                .about("Join a cluster through a server")
+                .arg(
+                    Arg::with_name("ADDRESS")
+                        .required(true)
+                        .help("Gossip address of the server"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("leave").about("Leave the cluster and stop the agent"))
+        .subcommand(SubCommand::with_name("stats").about("Show the counters of the agent"))
+        .subcommand(
+            SubCommand::with_name("alerts").about("Show the firing and recently resolved alerts"),
+        )
+        .get_matches();
+
+    let request = request(&options)?;
+    let path = options.value_of("socket").unwrap();
+    let mut stream = UnixStream::connect(path)
+        .map_err(|err| format!("unable to connect to {}: {}", path, err))?;
+    writeln!(stream, "{}", serde_json::to_string(&request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match serde_json::from_str(&line)? {
+        Response::Ok(serde_json::Value::Null) => {}
+        Response::Ok(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+        Response::Error(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    }
+    Ok(())
+}
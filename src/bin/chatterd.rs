@@ -20,22 +20,453 @@ extern crate bytes;
 extern crate chatter;
 extern crate futures;
 
-use chatter::gossip::{GossipCodec, Message};
+use chatter::alert::Evaluator;
+use chatter::api;
+use chatter::auth::AuthKeys;
+use chatter::cache::MessageCache;
+use chatter::clock::Clock;
+use chatter::config::{CheckConfig, Config, ProbeConfig};
+use chatter::control::{self, Controller};
+use chatter::crypto::{ClusterKey, EncryptionMode};
+use chatter::error::Error;
+use chatter::failure::{DetectorConfig, FailureDetector};
+use chatter::gossip::{
+    Action, DatagramSink, Gossip, GossipCodec, Message, Outbox, Security, SharedSecurity,
+};
+use chatter::identity;
+use chatter::join::Joiner;
+use chatter::leave;
+use chatter::notify::Notifier;
+use chatter::probe::command::{CommandProbe, OutputFormat};
+use chatter::probe::{system, Probe, Prober};
+use chatter::signing::{Signer, TrustedKeys};
 use chatter::state::State;
+use chatter::sync;
+use chatter::view::{PeerSelector, RandomFanout};
+use chrono::Utc;
+use futures::stream;
+use futures::sync::mpsc;
+use hyper::service::service_fn_ok;
+use hyper::Server;
+use std::fs;
+use std::io;
 use std::net::SocketAddr;
+use std::num::ParseIntError;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::result::Result;
-use tokio::net::{UdpFramed, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::codec::{Framed, LinesCodec};
+use tokio::net::{UdpFramed, UdpSocket, UnixListener};
 use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio::runtime::Runtime;
+use tokio::timer::{Delay, Interval};
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
+use uuid::Uuid;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+/// Maximum number of message identifiers remembered for duplicate
+/// detection.
+const MESSAGE_CACHE_SIZE: usize = 10_000;
+
+/// Time that a message identifier is remembered for duplicate
+/// detection.
+const MESSAGE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Time given to send the leave messages before shutting down.
+const LEAVE_FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+/// Time between join requests to the seeds.
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time between checks for notifications that other agents failed to
+/// deliver.
+const FAILOVER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum length of a control request.
+const MAX_CONTROL_REQUEST: usize = 65_536;
+
+/// Perform actions requested by protocol handlers.
+fn dispatch(actions: Vec<Action>, state: &mut State, outbox: &Outbox) {
+    for action in actions {
+        match action {
+            Action::Send(gossip, addr) => outbox.send(gossip, addr),
+            Action::Disseminate(update) => {
+                state.update_view(&update, outbox.uuid(), Utc::now().timestamp_millis());
+                outbox.broadcast(
+                    Gossip::ViewGossip(update),
+                    &state
+                        .view
+                        .lock()
+                        .expect("unable to lock view for dissemination"),
+                );
+            }
+            Action::Alert(update) => {
+                state.update_alerts(&update, outbox.uuid());
+                outbox.broadcast(
+                    Gossip::AlertGossip(update),
+                    &state.view.lock().expect("unable to lock view for alerting"),
+                );
+            }
+            Action::Publish(update) => {
+                state.update_devices(&update, outbox.uuid(), Utc::now().timestamp_millis());
+                outbox.broadcast(
+                    Gossip::DeviceGossip(update),
+                    &state
+                        .view
+                        .lock()
+                        .expect("unable to lock view for publishing"),
+                );
+            }
+        }
+    }
+}
 
-    let options = App::new("Chatter Agent")
+/// Bind the control socket. Only the user running the agent can
+/// connect to the socket. The socket is created with these
+/// permissions, so there is no window where others can connect.
+///
+/// A socket left behind by an agent that did not shut down cleanly is
+/// replaced, but not a socket that another agent is listening on.
+fn bind_control_socket(path: &Path) -> Result<UnixListener, io::Error> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(mask) };
+    listener
+}
+
+/// Parse a duration given in seconds.
+fn seconds(value: &str) -> Result<Duration, ParseIntError> {
+    value.parse::<u64>().map(Duration::from_secs)
+}
+
+/// Build the configuration of the agent from the configuration file,
+/// if one is given, and the options. Options override the settings in
+/// the file, except checks, which are added to the checks in the file.
+fn configure(options: &ArgMatches) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut config = match options.value_of("config") {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::default(),
+    };
+
+    if let Some(listen) = options.value_of("listen") {
+        config.listen = listen.parse()?;
+    }
+    if let Some(listen) = options.value_of("http-listen") {
+        config.http_listen = Some(listen.parse()?);
+    }
+    if let Some(path) = options.value_of("control-socket") {
+        config.control_socket = Some(PathBuf::from(path));
+    }
+    if let Some(dir) = options.value_of("data-dir") {
+        config.data_dir = Some(PathBuf::from(dir));
+    }
+    if let Some(seeds) = options.values_of("seed") {
+        config.seeds = seeds
+            .map(|seed| seed.parse::<SocketAddr>())
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(fanout) = options.value_of("fanout") {
+        config.fanout = fanout.parse()?;
+    }
+    if let Some(hops) = options.value_of("hops") {
+        config.hops = hops.parse()?;
+    }
+    if let Some(interval) = options.value_of("sync-interval") {
+        config.sync_interval = seconds(interval)?;
+    }
+    if let Some(horizon) = options.value_of("tombstone-horizon") {
+        config.tombstone_horizon = seconds(horizon)?;
+    }
+    if let Some(version) = options.value_of("protocol-version") {
+        config.protocol_version = Some(version.parse()?);
+    }
+    if let Some(level) = options.value_of("log-level") {
+        config.log_level = Some(level.to_string());
+    }
+
+    if let Some(samples) = options.value_of("history-samples") {
+        config.history.samples = samples.parse()?;
+    }
+    if let Some(age) = options.value_of("history-age") {
+        config.history.age = seconds(age)?;
+    }
+
+    if let Some(interval) = options.value_of("probe-interval") {
+        config.probes.interval = seconds(interval)?;
+    }
+    if options.is_present("no-system-probes") {
+        config.probes.system = false;
+    }
+    if let Some(disks) = options.values_of("disk") {
+        config.probes.disks = disks.map(PathBuf::from).collect();
+    }
+    if let Some(timeout) = options.value_of("check-timeout") {
+        config.probes.timeout = seconds(timeout)?;
+    }
+    for (option, format) in &[
+        ("check", OutputFormat::Nagios),
+        ("json-check", OutputFormat::Json),
+    ] {
+        for check in options.values_of(option).into_iter().flatten() {
+            let (device, command) = check
+                .split_once('=')
+                .ok_or_else(|| format!("expected DEVICE=COMMAND for --{}: {}", option, check))?;
+            config.probes.checks.push(CheckConfig {
+                device: device.to_string(),
+                command: command.to_string(),
+                format: *format,
+                interval: None,
+                timeout: None,
+            });
+        }
+    }
+
+    if let Some(path) = options.value_of("auth-key") {
+        config.security.auth_key = Some(PathBuf::from(path));
+    }
+    if let Some(path) = options.value_of("auth-key-secondary") {
+        config.security.auth_key_secondary = Some(PathBuf::from(path));
+    }
+    if let Some(mode) = options.value_of("encryption") {
+        config.security.encryption = mode.parse()?;
+    }
+    if let Some(path) = options.value_of("encryption-key") {
+        config.security.encryption_key = Some(PathBuf::from(path));
+    }
+    if let Some(path) = options.value_of("encryption-key-secondary") {
+        config.security.encryption_key_secondary = Some(PathBuf::from(path));
+    }
+    if let Some(dir) = options.value_of("trusted-keys") {
+        config.security.trusted_keys = Some(PathBuf::from(dir));
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Load the keys and encryption mode used for gossip.
+fn load_security(config: &Config) -> Result<Security, Error> {
+    let keys = match config.security.auth_key {
+        Some(ref primary) => Some(AuthKeys::load(
+            primary,
+            config.security.auth_key_secondary.as_deref(),
+        )?),
+        None => None,
+    };
+    let cluster_key = match config.security.encryption_key {
+        Some(ref path) if config.security.encryption != EncryptionMode::None => Some(
+            ClusterKey::load(path, config.security.encryption_key_secondary.as_deref())?,
+        ),
+        _ => None,
+    };
+    Ok(Security {
+        keys,
+        mode: config.security.encryption,
+        cluster_key,
+    })
+}
+
+/// Load the public keys of the agents whose device updates are
+/// accepted, which includes this agent.
+fn load_trusted_keys(config: &Config, signer: &Signer) -> Result<Option<TrustedKeys>, Error> {
+    match config.security.trusted_keys {
+        Some(ref dir) => {
+            let mut trusted_keys = TrustedKeys::load(dir)?;
+            trusted_keys.insert(*signer.uuid(), signer.verifying_key());
+            Ok(Some(trusted_keys))
+        }
+        None => Ok(None),
+    }
+}
+
+fn apply_trusted_keys(state: &State, trusted_keys: Option<TrustedKeys>) {
+    match trusted_keys {
+        Some(ref keys) => info!("Accepting device updates signed by {} agents", keys.len()),
+        None => warn!(
+            "No trusted keys given, device updates are not verified and only accepted from their owner"
+        ),
+    }
+    state
+        .devices
+        .lock()
+        .expect("unable to lock device collection")
+        .set_trusted_keys(trusted_keys.clone());
+    state
+        .alerts
+        .lock()
+        .expect("unable to lock alerts")
+        .set_trusted_keys(trusted_keys);
+}
+
+/// Set how long removed devices, resolved alerts, and metric samples
+/// are remembered.
+fn apply_limits(state: &State, config: &Config) {
+    let mut devices = state
+        .devices
+        .lock()
+        .expect("unable to lock device collection");
+    devices.set_tombstone_horizon(config.tombstone_horizon);
+    devices.set_history_limits(config.history_limits());
+    state
+        .alerts
+        .lock()
+        .expect("unable to lock alerts")
+        .set_horizon(config.tombstone_horizon);
+}
+
+/// Construct the configured probes.
+fn build_probes(config: &ProbeConfig) -> Result<Vec<Box<dyn Probe>>, Error> {
+    let mut probes = if config.system {
+        system::probes(&config.disks)?
+    } else {
+        Vec::new()
+    };
+    for check in &config.checks {
+        probes.push(Box::new(CommandProbe::new(
+            &check.device,
+            &check.command,
+            check.format,
+            check.interval.unwrap_or(config.interval),
+            check.timeout.unwrap_or(config.timeout),
+        )));
+    }
+    Ok(probes)
+}
+
+/// Stream of instants separated by an interval that can be changed
+/// while the stream is running.
+fn ticks(
+    start: Instant,
+    interval: Arc<Mutex<Duration>>,
+) -> impl Stream<Item = Instant, Error = tokio::timer::Error> {
+    stream::unfold(None, move |last: Option<Instant>| {
+        let next = match last {
+            Some(last) => last + *interval.lock().expect("unable to lock interval"),
+            None => start,
+        };
+        Some(Delay::new(next).map(move |_| (next, Some(next))))
+    })
+}
+
+/// Parts of the agent that are changed when the configuration is
+/// reloaded.
+struct Reloader {
+    options: ArgMatches<'static>,
+    config: Config,
+    signer: Arc<Signer>,
+    version: u8,
+    state: State,
+    outbox: Outbox,
+    security: SharedSecurity,
+    prober: Arc<Mutex<Prober>>,
+    evaluator: Arc<Mutex<Evaluator>>,
+    notifier: Arc<Mutex<Notifier>>,
+    sync_interval: Arc<Mutex<Duration>>,
+    probe_interval: Arc<Mutex<Duration>>,
+}
+
+impl Reloader {
+    /// Read the configuration again and apply the changes.
+    ///
+    /// Everything that can fail is loaded before anything is changed,
+    /// so that the old configuration is kept if the reload fails.
+    fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let config = configure(&self.options)?;
+        let security = load_security(&config)?;
+        security.validate(self.version)?;
+        let trusted_keys = load_trusted_keys(&config, &self.signer)?;
+        let probes = if config.probes != self.config.probes {
+            Some(build_probes(&config.probes)?)
+        } else {
+            None
+        };
+        let sinks = if config.notifications != self.config.notifications {
+            Some(config.notifications.build_sinks()?)
+        } else {
+            None
+        };
+
+        if config.listen != self.config.listen
+            || config.http_listen != self.config.http_listen
+            || config.control_socket != self.config.control_socket
+            || config.data_dir != self.config.data_dir
+            || config.protocol_version != self.config.protocol_version
+            || config.log_level != self.config.log_level
+        {
+            warn!("Changes to listen addresses, control socket, data directory, protocol version, or log level require a restart");
+        }
+
+        info!("Encryption mode is {}", security.mode);
+        if security.keys.is_none() {
+            warn!("No authentication key given, gossip is not authenticated");
+        }
+        *self
+            .security
+            .write()
+            .expect("unable to lock security settings for reload") = security;
+        apply_trusted_keys(&self.state, trusted_keys);
+        apply_limits(&self.state, &config);
+        self.outbox.set_fanout(config.fanout);
+        self.outbox.set_hops(config.hops);
+        *self.sync_interval.lock().expect("unable to lock interval") = config.sync_interval;
+        *self.probe_interval.lock().expect("unable to lock interval") = config.probes.interval;
+        if let Some(probes) = probes {
+            let actions = self
+                .prober
+                .lock()
+                .expect("unable to lock prober for reload")
+                .replace(probes);
+            dispatch(actions, &mut self.state, &self.outbox);
+        }
+        if config.alerts != self.config.alerts {
+            self.evaluator
+                .lock()
+                .expect("unable to lock evaluator for reload")
+                .set_rules(config.alerts.clone());
+        }
+        if let Some(sinks) = sinks {
+            let mut notifier = self
+                .notifier
+                .lock()
+                .expect("unable to lock notifier for reload");
+            notifier.set_max_age(config.notifications.max_age);
+            notifier.set_failover_delay(config.notifications.failover_delay);
+            notifier.set_sinks(sinks, config.notifications.retry_policy());
+        }
+        self.config = config;
+        info!("Reloaded configuration");
+        Ok(())
+    }
+}
+
+/// Command-line options of the agent.
+fn app() -> App<'static, 'static> {
+    App::new("Chatter Agent")
         .version("0.1")
         .author("Mats Kindahl <mats.kindahl@gmail.com>")
         .about("Monitoring agent for distributed systems.")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Configuration file, which the other options override")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("listen")
                 .short("l")
@@ -44,21 +475,508 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Address to listen for gossip on")
                 .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("http-listen")
+                .long("http-listen")
+                .value_name("ADDRESS")
+                .help("Address to serve the HTTP API on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("control-socket")
+                .long("control-socket")
+                .value_name("PATH")
+                .help("Path of the Unix-domain socket to accept control requests on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fanout")
+                .short("f")
+                .long("fanout")
+                .value_name("COUNT")
+                .help("Number of servers to forward each message to")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hops")
+                .long("hops")
+                .value_name("COUNT")
+                .help("Number of hops that gossip from the agent travel")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sync-interval")
+                .long("sync-interval")
+                .value_name("SECONDS")
+                .help("Interval between anti-entropy synchronizations")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tombstone-horizon")
+                .long("tombstone-horizon")
+                .value_name("SECONDS")
+                .help("Time that removed devices are remembered")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history-samples")
+                .long("history-samples")
+                .value_name("COUNT")
+                .help("Number of samples to keep in the history of each metric")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history-age")
+                .long("history-age")
+                .value_name("SECONDS")
+                .help("Time that samples are kept in the history of each metric")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("probe-interval")
+                .long("probe-interval")
+                .value_name("SECONDS")
+                .help("Time between samples of the devices of the machine")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("disk")
+                .long("disk")
+                .value_name("PATH")
+                .help("Mount point of file system to monitor (default /)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("no-system-probes")
+                .long("no-system-probes")
+                .help("Do not monitor the CPU, memory, disks, and network of the machine"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .value_name("DEVICE=COMMAND")
+                .help("Nagios plugin to run to check a device")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("json-check")
+                .long("json-check")
+                .value_name("DEVICE=COMMAND")
+                .help("Command printing the metrics of a device as JSON")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("check-timeout")
+                .long("check-timeout")
+                .value_name("SECONDS")
+                .help("Time that a check may run before it is killed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .short("s")
+                .long("seed")
+                .value_name("ADDRESS")
+                .help("Address of seed server to join the cluster through")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("protocol-version")
+                .long("protocol-version")
+                .value_name("VERSION")
+                .help("Protocol version to send gossip with while upgrading a cluster")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auth-key")
+                .long("auth-key")
+                .value_name("FILE")
+                .help("File with the key used to authenticate gossip")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auth-key-secondary")
+                .long("auth-key-secondary")
+                .value_name("FILE")
+                .help("File with an additional key accepted while rotating keys")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("encryption")
+                .long("encryption")
+                .value_name("MODE")
+                .help("How gossip is encrypted")
+                .takes_value(true)
+                .possible_values(&["none", "accept", "optional", "required"]),
+        )
+        .arg(
+            Arg::with_name("encryption-key")
+                .long("encryption-key")
+                .value_name("FILE")
+                .help("File with the cluster key used to encrypt gossip")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("encryption-key-secondary")
+                .long("encryption-key-secondary")
+                .value_name("FILE")
+                .help("File with an additional cluster key used to decrypt gossip while rotating keys")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trusted-keys")
+                .long("trusted-keys")
+                .value_name("DIRECTORY")
+                .help("Directory with the public keys of agents whose device updates are accepted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("FILTER")
+                .help("Log level, unless given in RUST_LOG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .short("d")
+                .long("data-dir")
+                .value_name("DIRECTORY")
+                .help("Directory to store the agent state in")
+                .takes_value(true),
+        )
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = app().get_matches();
+    let config = configure(&options)?;
+    let env = env_logger::Env::default();
+    let env = match config.log_level {
+        Some(ref level) => env.filter_or("RUST_LOG", level.as_str()),
+        None => env,
+    };
+    env_logger::Builder::from_env(env).init();
 
-    let socket = {
-        let sockaddr = options
-            .value_of("listen")
-            .unwrap_or("0.0.0.0:2428")
-            .parse::<SocketAddr>()?;
-        UdpSocket::bind(&sockaddr)?
+    let socket = std::net::UdpSocket::bind(config.listen)?;
+    let local_addr = socket.local_addr()?;
+    info!("Listening on {}", local_addr);
+
+    let uuid = match config.data_dir {
+        Some(ref dir) => identity::load_or_create(dir)?,
+        None => {
+            warn!("No data directory given, agent UUID will change on restart");
+            Uuid::new_v4()
+        }
     };
+    info!("Agent UUID is {}", uuid);
 
-    info!("Listening on {}", socket.local_addr()?);
+    let signer = Arc::new(match config.data_dir {
+        Some(ref dir) => Signer::load_or_create(uuid, dir)?,
+        None => Signer::generate(uuid),
+    });
+    info!("Agent public key is {}", signer.public_key());
 
     let shared_state = State::new();
+    apply_limits(&shared_state, &config);
+    apply_trusted_keys(&shared_state, load_trusted_keys(&config, &signer)?);
+
+    let codec = match config.protocol_version {
+        Some(version) => GossipCodec::with_version(version)?,
+        None => GossipCodec::new(),
+    };
+    let codec = codec.with_security(load_security(&config)?)?;
+    info!("Sending gossip using protocol version {}", codec.version());
+    info!("Encryption mode is {}", codec.encryption_mode());
+    if !codec.is_authenticated() {
+        warn!("No authentication key given, gossip is not authenticated");
+    }
+    let security = codec.security();
+    let version = codec.version();
+
+    // All updates issued by the agent are versioned using the same
+    // clock, so that no two updates get the same version.
+    let clock = Arc::new(Mutex::new(Clock::new()));
+    let mut prober = Prober::new(signer.clone(), clock.clone());
+    for probe in build_probes(&config.probes)? {
+        prober.add(probe);
+    }
+    let prober = Arc::new(Mutex::new(prober));
+    let evaluator = Arc::new(Mutex::new(Evaluator::new(
+        signer.clone(),
+        clock.clone(),
+        config.alerts.clone(),
+    )));
+    let (delivered_queue, delivered) = mpsc::unbounded();
+    let mut notifier = Notifier::new(uuid, delivered_queue);
+    notifier.set_max_age(config.notifications.max_age);
+    notifier.set_failover_delay(config.notifications.failover_delay);
+    notifier.set_sinks(
+        config.notifications.build_sinks()?,
+        config.notifications.retry_policy(),
+    );
+    let notifier = Arc::new(Mutex::new(notifier));
+    let (transitions_queue, transitions) = mpsc::unbounded();
+    shared_state
+        .alerts
+        .lock()
+        .expect("unable to lock alerts for notifications")
+        .set_transitions(transitions_queue);
+    let sync_interval = Arc::new(Mutex::new(config.sync_interval));
+    let probe_interval = Arc::new(Mutex::new(config.probes.interval));
+
+    // The socket is used by separate tasks for receiving and sending.
+    let writer = DatagramSink::new(
+        UdpSocket::from_std(socket.try_clone()?, &Handle::default())?,
+        codec.clone(),
+    );
+    let reader = UdpFramed::new(UdpSocket::from_std(socket, &Handle::default())?, codec);
+    let (queue, outgoing) = mpsc::unbounded();
+    let outbox = Outbox::new(uuid, config.fanout, queue).with_hops(config.hops);
+
+    let detector_config = DetectorConfig::default();
+    let tick_interval = detector_config.ping_timeout;
+    let detector = Arc::new(Mutex::new(FailureDetector::new(uuid, detector_config)));
+
+    let joiner = Arc::new(Mutex::new(Joiner::new(
+        uuid,
+        local_addr,
+        config.seeds.clone(),
+    )));
+    let controller = Arc::new(Mutex::new(Controller::new(
+        signer.clone(),
+        joiner.clone(),
+        clock.clone(),
+    )));
+
+    // Future writing all outgoing messages to the socket.
+    let writer_future = writer
+        .send_all(outgoing.map_err(|_| io::Error::other("outbox closed")))
+        .map(|_| ())
+        .map_err(|e| error!("error: {:?}", e));
+
+    // Future running the failure detector. It is run each ping
+    // timeout so that probes without acknowledgement are detected in
+    // time.
+    let detector_future = {
+        let mut state = shared_state.clone();
+        let detector = detector.clone();
+        let outbox = outbox.clone();
+        Interval::new(Instant::now() + tick_interval, tick_interval)
+            .for_each(move |now| {
+                let actions = detector
+                    .lock()
+                    .expect("unable to lock failure detector")
+                    .tick(
+                        &state.view.lock().expect("unable to lock view for probing"),
+                        now,
+                    );
+                dispatch(actions, &mut state, &outbox);
+                Ok(())
+            })
+            .map_err(|e| error!("error: {:?}", e))
+    };
+
+    // Future sending join requests to the seeds until the agent has
+    // joined the cluster.
+    let join_future = {
+        let mut state = shared_state.clone();
+        let joiner = joiner.clone();
+        let outbox = outbox.clone();
+        Interval::new(Instant::now(), JOIN_RETRY_INTERVAL)
+            .take_while({
+                let joiner = joiner.clone();
+                move |_| Ok(!joiner.lock().expect("unable to lock joiner").is_joined())
+            })
+            .for_each(move |_| {
+                let actions = joiner.lock().expect("unable to lock joiner").tick();
+                dispatch(actions, &mut state, &outbox);
+                Ok(())
+            })
+            .map_err(|e| error!("error: {:?}", e))
+    };
+
+    // Future sampling the devices of the machine each probe interval
+    // and publishing their status to the cluster. The alert rules are
+    // then evaluated against the new values.
+    let probe_future = {
+        let mut state = shared_state.clone();
+        let outbox = outbox.clone();
+        let prober = prober.clone();
+        let evaluator = evaluator.clone();
+        ticks(Instant::now(), probe_interval.clone())
+            .for_each(move |_| {
+                let actions = prober.lock().expect("unable to lock prober").tick();
+                dispatch(actions, &mut state, &outbox);
+                let actions = evaluator
+                    .lock()
+                    .expect("unable to lock evaluator")
+                    .tick(&state, Utc::now().timestamp_millis());
+                dispatch(actions, &mut state, &outbox);
+                Ok(())
+            })
+            .map_err(|e| error!("error: {:?}", e))
+    };
+
+    // Future sending notifications for the transitions of alerts.
+    let notify_future = {
+        let state = shared_state.clone();
+        let notifier = notifier.clone();
+        transitions.for_each(move |event| {
+            notifier.lock().expect("unable to lock notifier").handle(
+                &event,
+                &state.view.lock().expect("unable to lock view for notify"),
+                Utc::now().timestamp_millis(),
+            );
+            Ok(())
+        })
+    };
+
+    // Future telling the cluster about notifications delivered by this
+    // agent, so that the next agents in the ranking do not send them.
+    let delivered_future = {
+        let state = shared_state.clone();
+        let outbox = outbox.clone();
+        delivered.for_each(move |transition| {
+            outbox.broadcast(
+                Gossip::NotificationDelivered(transition),
+                &state.view.lock().expect("unable to lock view for delivery"),
+            );
+            Ok(())
+        })
+    };
+
+    // Future sending the notifications that other agents failed to
+    // deliver in time.
+    let failover_future = {
+        let notifier = notifier.clone();
+        Interval::new(Instant::now(), FAILOVER_CHECK_INTERVAL)
+            .for_each(move |_| {
+                notifier
+                    .lock()
+                    .expect("unable to lock notifier")
+                    .tick(Utc::now().timestamp_millis());
+                Ok(())
+            })
+            .map_err(|e| error!("error: {:?}", e))
+    };
+
+    // Future synchronizing state with a random peer each sync
+    // interval. Old state is also garbage collected.
+    let sync_future = {
+        let mut state = shared_state.clone();
+        let outbox = outbox.clone();
+        let mut selector = RandomFanout::new(1);
+        ticks(Instant::now() + config.sync_interval, sync_interval.clone())
+            .for_each(move |_| {
+                let peers = selector.select(
+                    &state.view.lock().expect("unable to lock view for sync"),
+                    &[uuid],
+                    None,
+                );
+                for peer in peers {
+                    debug!("Synchronizing state with {}", peer);
+                    outbox.send(Gossip::SyncRequest(state.digest()), peer);
+                }
+                state.collect_garbage();
+                Ok(())
+            })
+            .map_err(|e| error!("error: {:?}", e))
+    };
+
+    // Filter for dropping messages that have already been seen. Each
+    // message is then applied and forwarded only once. Messages
+    // originating from this agent are already applied, so they are
+    // dropped as well.
+    let duplicate_filter = {
+        let mut cache = MessageCache::new(MESSAGE_CACHE_SIZE, MESSAGE_CACHE_TTL);
+        let stats = shared_state.stats.clone();
+        move |(msg, addr): &(Message, SocketAddr)| {
+            let first_seen = msg.sender != uuid && cache.insert(&msg.id);
+            if !first_seen {
+                debug!("Dropping duplicate message {} from {}", msg.id, addr);
+                stats.record_duplicate();
+            }
+            first_seen
+        }
+    };
+
+    // Future for passing received gossip to the failure detector.
+    let detect_future = {
+        let mut state = shared_state.clone();
+        let outbox = outbox.clone();
+        move |(msg, addr): (Message, SocketAddr)| {
+            if let Some(ref gossip) = msg.payload {
+                let actions = detector
+                    .lock()
+                    .expect("unable to lock failure detector")
+                    .handle(
+                        gossip,
+                        &msg.sender,
+                        &addr,
+                        &mut state.view.lock().expect("unable to lock view for probing"),
+                        Instant::now(),
+                    );
+                dispatch(actions, &mut state, &outbox);
+            }
+            Ok((msg, addr))
+        }
+    };
 
-    let (mut writer, reader) = UdpFramed::new(socket, GossipCodec::new()).split();
+    // Future for handling requests and replies for joining the
+    // cluster.
+    let join_handler = {
+        let mut state = shared_state.clone();
+        let outbox = outbox.clone();
+        move |(msg, addr): (Message, SocketAddr)| {
+            if let Some(ref gossip) = msg.payload {
+                let actions = joiner.lock().expect("unable to lock joiner").handle(
+                    gossip,
+                    &msg.sender,
+                    &addr,
+                    &mut state.view.lock().expect("unable to lock view for join"),
+                );
+                dispatch(actions, &mut state, &outbox);
+            }
+            Ok((msg, addr))
+        }
+    };
+
+    // Future for handling anti-entropy synchronization.
+    let sync_handler = {
+        let mut state = shared_state.clone();
+        let outbox = outbox.clone();
+        move |(msg, addr): (Message, SocketAddr)| {
+            if let Some(ref gossip) = msg.payload {
+                for reply in sync::handle(gossip, &msg.sender, &mut state) {
+                    outbox.send(reply, addr);
+                }
+            }
+            Ok((msg, addr))
+        }
+    };
+
+    // Future for cancelling notifications that another agent
+    // delivered.
+    let delivery_handler = {
+        let notifier = notifier.clone();
+        move |(msg, addr): (Message, SocketAddr)| {
+            if let Some(Gossip::NotificationDelivered(ref transition)) = msg.payload {
+                notifier
+                    .lock()
+                    .expect("unable to lock notifier")
+                    .delivered(transition);
+            }
+            Ok((msg, addr))
+        }
+    };
 
     // Future for updating state based on received gossip.
     let update_future = {
@@ -69,41 +987,305 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    // Future for forwarding gossip to other servers in the
-    // view. Right now, it forwards the message to all servers in the
-    // cluster, not just a subset.
+    // Future for forwarding gossip to other servers in the view. The
+    // message is forwarded to a random subset of the servers in the
+    // cluster.
     let gossip_future = {
         let state = shared_state.clone();
+        let outbox = outbox.clone();
         move |(mut msg, addr): (Message, SocketAddr)| {
             if msg.hops > 0 {
                 msg.hops -= 1;
-                let locked_view = state
-                    .view
-                    .lock()
-                    .expect("unable to lock view for forwarding");
-                for (_uuid, info) in &locked_view.servers {
-                    writer.start_send((msg.clone(), info.address.clone()))?;
-                }
-                writer.poll_complete()?;
+                let copies = outbox.forward(
+                    &msg,
+                    &addr,
+                    &state
+                        .view
+                        .lock()
+                        .expect("unable to lock view for forwarding"),
+                );
+                state.stats.record_forwarded(copies as u64);
             }
             Ok((msg, addr))
         }
     };
 
-    tokio::run({
-        reader
-            .and_then(move |(msg, addr): (Message, SocketAddr)| {
-                debug!("Received gossip message from address {}: {:?}", addr, msg);
-                Ok((msg, addr))
+    // Datagrams that cannot be decoded are logged and dropped, so
+    // that a bad datagram does not stop the agent from receiving
+    // other messages.
+    let receive_filter = {
+        let stats = shared_state.stats.clone();
+        move |result: Result<(Message, SocketAddr), Error>| -> Result<_, Error> {
+            match result {
+                Ok((msg, addr)) => {
+                    debug!("Received gossip message from address {}: {:?}", addr, msg);
+                    stats.record_received();
+                    Ok(Some((msg, addr)))
+                }
+                Err(Error::IoError(err)) => {
+                    warn!("Unable to receive datagram: {}", err);
+                    Ok(None)
+                }
+                Err(err) => {
+                    stats.record_rejected();
+                    warn!(
+                        "Rejected datagram ({} rejected so far): {}",
+                        stats.rejected(),
+                        err
+                    );
+                    Ok(None)
+                }
+            }
+        }
+    };
+
+    let reader_future = reader
+        .then(receive_filter)
+        .filter_map(|received| received)
+        .filter(duplicate_filter)
+        .and_then(detect_future)
+        .and_then(join_handler)
+        .and_then(sync_handler)
+        .and_then(delivery_handler)
+        .and_then(update_future)
+        .and_then(gossip_future)
+        .for_each(|(_msg, addr): (Message, SocketAddr)| {
+            debug!("Finished processing gossip message from {}", addr);
+            Ok(())
+        })
+        .map(|_| ())
+        .map_err(|e| error!("error: {:?}", e));
+
+    // Future completing when the agent is asked to shut down, either
+    // by a signal or by a leave request on the control socket.
+    let (leave_sender, leave_receiver) = mpsc::unbounded();
+    let shutdown_future = Signal::new(SIGTERM)
+        .flatten_stream()
+        .select(Signal::new(SIGINT).flatten_stream())
+        .into_future()
+        .map(|(signal, _)| info!("Received signal {:?}, shutting down", signal))
+        .map_err(|(e, _)| error!("error: {:?}", e))
+        .select(
+            leave_receiver
+                .into_future()
+                .map(|_| info!("Asked to leave the cluster, shutting down"))
+                .map_err(|_| ()),
+        )
+        .map(|_| ())
+        .map_err(|_| ());
+
+    let mut runtime = Runtime::new()?;
+
+    // Future serving the HTTP API.
+    if let Some(ref addr) = config.http_listen {
+        let state = shared_state.clone();
+        let server = Server::try_bind(addr)?
+            .serve(move || {
+                let state = state.clone();
+                service_fn_ok(move |request| api::handle(&request, &state))
+            })
+            .map_err(|e| error!("HTTP server error: {}", e));
+        info!("Serving HTTP API on {}", addr);
+        runtime.spawn(server);
+    }
+
+    // Future serving requests on the control socket. Each request and
+    // reply is a line of JSON.
+    let control_socket = config.control_socket.clone();
+    if let Some(ref path) = control_socket {
+        let state = shared_state.clone();
+        let outbox = outbox.clone();
+        let server = bind_control_socket(path)?
+            .incoming()
+            .for_each(move |connection| {
+                let (replies, requests) = Framed::new(
+                    connection,
+                    LinesCodec::new_with_max_length(MAX_CONTROL_REQUEST),
+                )
+                .split();
+                let mut state = state.clone();
+                let outbox = outbox.clone();
+                let controller = controller.clone();
+                let leave_sender = leave_sender.clone();
+                let handler = requests
+                    .map(move |line| {
+                        let response = match serde_json::from_str::<control::Request>(&line) {
+                            Ok(request) => {
+                                debug!("Handling control request {:?}", request);
+                                let result = controller
+                                    .lock()
+                                    .expect("unable to lock controller")
+                                    .handle(&request, &state);
+                                let result = result.map(|(value, actions)| {
+                                    dispatch(actions, &mut state, &outbox);
+                                    if request == control::Request::Leave {
+                                        let _ = leave_sender.unbounded_send(());
+                                    }
+                                    value
+                                });
+                                control::Response::from(result)
+                            }
+                            Err(err) => {
+                                control::Response::Error(format!("Invalid request: {}", err))
+                            }
+                        };
+                        serde_json::to_string(&response).expect("replies can always be serialized")
+                    })
+                    .forward(replies)
+                    .map(|_| ())
+                    .map_err(|e| warn!("Control connection failed: {}", e));
+                tokio::spawn(handler);
+                Ok(())
             })
-            .and_then(update_future)
-            .and_then(gossip_future)
-            .for_each(|(_msg, addr): (Message, SocketAddr)| {
-                debug!("Finished processing gossip message from {}", addr);
+            .map_err(|e| error!("Control socket error: {}", e));
+        info!("Accepting control requests on {}", path.display());
+        runtime.spawn(server);
+    }
+
+    // Future reloading the configuration when the agent receives
+    // SIGHUP. The old configuration is kept if the reload fails.
+    let reload_future = {
+        let mut reloader = Reloader {
+            options,
+            config,
+            signer: signer.clone(),
+            version,
+            state: shared_state.clone(),
+            outbox: outbox.clone(),
+            security,
+            prober,
+            evaluator,
+            notifier,
+            sync_interval,
+            probe_interval,
+        };
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .for_each(move |_| {
+                info!("Reloading configuration");
+                if let Err(err) = reloader.reload() {
+                    error!("Unable to reload configuration, keeping the old: {}", err);
+                }
                 Ok(())
             })
-            .map(|_| ())
             .map_err(|e| error!("error: {:?}", e))
-    });
+    };
+    runtime.spawn(reload_future);
+    runtime.spawn(writer_future);
+    runtime.spawn(detector_future);
+    runtime.spawn(join_future);
+    runtime.spawn(sync_future);
+    runtime.spawn(probe_future);
+    runtime.spawn(notify_future);
+    runtime.spawn(delivered_future);
+    runtime.spawn(failover_future);
+    let _ = runtime.block_on(reader_future.select(shutdown_future));
+
+    // Announce that the agent leaves the cluster and give the writer
+    // some time to send the messages before stopping.
+    info!("Leaving the cluster");
+    for gossip in leave::leave_gossip(&signer, &clock, &shared_state) {
+        outbox.announce(
+            gossip,
+            &shared_state
+                .view
+                .lock()
+                .expect("unable to lock view for leave"),
+        );
+    }
+    runtime.block_on(Delay::new(Instant::now() + LEAVE_FLUSH_DELAY))?;
+    runtime
+        .shutdown_now()
+        .wait()
+        .map_err(|_| io::Error::other("unable to shut down runtime"))?;
+    if let Some(ref path) = control_socket {
+        let _ = fs::remove_file(path);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> ArgMatches<'static> {
+        app().get_matches_from(std::iter::once("chatterd").chain(args.iter().cloned()))
+    }
+
+    #[test]
+    fn control_socket_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("chatter-test-{}.sock", Uuid::new_v4()));
+        let listener = bind_control_socket(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            bind_control_socket(&path).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn options_override_configuration_file() {
+        let path = std::env::temp_dir().join(format!("chatter-test-{}.toml", Uuid::new_v4()));
+        fs::write(
+            &path,
+            "fanout = 5\nhops = 7\nseeds = [\"192.0.2.1:2428\"]\n\
+             [probes]\ninterval = 30\ndisks = [\"/var\"]\n\
+             [[probes.checks]]\ndevice = \"web\"\ncommand = \"true\"\n",
+        )
+        .unwrap();
+        let file = path.to_str().unwrap();
+
+        let config = configure(&options(&["--config", file])).unwrap();
+        assert_eq!(config.fanout, 5);
+        assert_eq!(config.hops, 7);
+        assert_eq!(config.probes.interval, Duration::from_secs(30));
+
+        let config = configure(&options(&[
+            "--config",
+            file,
+            "--fanout",
+            "2",
+            "--seed",
+            "192.0.2.2:2428",
+            "--seed",
+            "192.0.2.3:2428",
+            "--disk",
+            "/",
+            "--no-system-probes",
+            "--json-check",
+            "db=check-db",
+        ]))
+        .unwrap();
+        assert_eq!(config.fanout, 2);
+        assert_eq!(config.hops, 7);
+        assert_eq!(config.seeds.len(), 2);
+        assert_eq!(config.probes.interval, Duration::from_secs(30));
+        assert_eq!(config.probes.disks, vec![PathBuf::from("/")]);
+        assert!(!config.probes.system);
+        let checks: Vec<_> = config
+            .probes
+            .checks
+            .iter()
+            .map(|check| (check.device.as_str(), check.format))
+            .collect();
+        assert_eq!(
+            checks,
+            vec![("web", OutputFormat::Nagios), ("db", OutputFormat::Json)]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overridden_configuration_is_validated() {
+        assert!(configure(&options(&["--fanout", "0"])).is_err());
+        assert!(configure(&options(&["--check", "no-command"])).is_err());
+        assert!(configure(&options(&["--check", "a=true", "--json-check", "a=true"])).is_err());
+        assert!(configure(&options(&["--encryption", "required"])).is_err());
+        assert!(configure(&options(&[])).is_ok());
+    }
+}
@@ -23,9 +23,8 @@ extern crate futures;
 #[macro_use]
 extern crate log;
 
-use chatter::gossip::{Gossip, GossipCodec, Message};
+use chatter::gossip::{Gossip, GossipCodec, Message, DEFAULT_HOPS};
 use chatter::view::ViewUpdate;
-use chrono::Utc;
 use std::env;
 use std::net::SocketAddr;
 use tokio::net::{UdpFramed, UdpSocket};
@@ -45,43 +44,40 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let uuid = Uuid::new_v4();
     let server_uuid = Uuid::new_v4();
     tokio::run(
-        UdpFramed::new(socket, GossipCodec {})
+        UdpFramed::new(socket, GossipCodec::new())
             .send((
-                Message {
-                    timestamp_millis: Utc::now().timestamp_millis(),
-                    sender: uuid,
-                    hops: 5,
-                    payload: Some(Gossip::DebugMessage {
+                Message::new(
+                    uuid,
+                    DEFAULT_HOPS,
+                    Some(Gossip::DebugMessage {
                         text: "hello world".to_string(),
                     }),
-                },
+                ),
                 remote_addr,
             ))
             .wait()
             .expect("debug message failed")
             .send((
-                Message {
-                    timestamp_millis: Utc::now().timestamp_millis(),
-                    sender: uuid,
-                    hops: 5,
-                    payload: Some(Gossip::ViewGossip(ViewUpdate::ServerAdded {
+                Message::new(
+                    uuid,
+                    DEFAULT_HOPS,
+                    Some(Gossip::ViewGossip(ViewUpdate::ServerAdded {
                         uuid: server_uuid,
                         addr: local_addr,
                     })),
-                },
+                ),
                 remote_addr,
             ))
             .wait()
             .expect("debug message failed")
             .send((
-                Message {
-                    timestamp_millis: Utc::now().timestamp_millis(),
-                    sender: uuid,
-                    hops: 5,
-                    payload: Some(Gossip::ViewGossip(ViewUpdate::ServerRemoved {
+                Message::new(
+                    uuid,
+                    DEFAULT_HOPS,
+                    Some(Gossip::ViewGossip(ViewUpdate::ServerRemoved {
                         uuid: server_uuid,
                     })),
-                },
+                ),
                 remote_addr,
             ))
             .map(|_| ())
@@ -0,0 +1,138 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for keeping track of recently seen messages.
+//!
+//! Since gossip is forwarded to several servers, the same message
+//! will usually arrive at a server multiple times. The cache is used
+//! to detect these duplicates so that each message is applied to the
+//! state and forwarded only once.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Bounded cache of message identifiers.
+///
+/// Identifiers are kept in the cache until they expire or until the
+/// cache is full, in which case the oldest identifiers are evicted
+/// first.
+pub struct MessageCache {
+    capacity: usize,
+    ttl: Duration,
+    seen: HashMap<Uuid, Instant>,
+    order: VecDeque<(Instant, Uuid)>,
+}
+
+impl MessageCache {
+    /// Construct a new message cache.
+    ///
+    /// # Parameters
+    ///
+    /// * `capacity` - Maximum number of identifiers kept in the cache.
+    ///
+    /// * `ttl` - Time that an identifier is remembered.
+    ///
+    pub fn new(capacity: usize, ttl: Duration) -> MessageCache {
+        MessageCache {
+            capacity,
+            ttl,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Record a message identifier as seen.
+    ///
+    /// Returns `true` if the identifier was not already in the cache,
+    /// that is, if this is the first time the message is seen.
+    pub fn insert(&mut self, id: &Uuid) -> bool {
+        self.insert_at(id, Instant::now())
+    }
+
+    fn insert_at(&mut self, id: &Uuid, now: Instant) -> bool {
+        self.expire(now);
+        if self.seen.contains_key(id) {
+            return false;
+        }
+        while self.order.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some((_, oldest)) => self.seen.remove(&oldest),
+                None => break,
+            };
+        }
+        self.seen.insert(*id, now);
+        self.order.push_back((now, *id));
+        true
+    }
+
+    /// Number of identifiers currently in the cache.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Check if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(inserted, id)) = self.order.front() {
+            if now.duration_since(inserted) < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_duplicates() {
+        let mut cache = MessageCache::new(10, Duration::from_secs(60));
+        let id = Uuid::new_v4();
+        assert!(cache.insert(&id));
+        assert!(!cache.insert(&id));
+        assert!(cache.insert(&Uuid::new_v4()));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn expires_old_identifiers() {
+        let mut cache = MessageCache::new(10, Duration::from_secs(60));
+        let start = Instant::now();
+        let id = Uuid::new_v4();
+        assert!(cache.insert_at(&id, start));
+        assert!(!cache.insert_at(&id, start + Duration::from_secs(59)));
+        assert!(cache.insert_at(&id, start + Duration::from_secs(60)));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let mut cache = MessageCache::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            assert!(cache.insert_at(id, start + Duration::from_millis(i as u64)));
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.insert_at(&ids[2], start));
+        assert!(cache.insert_at(&ids[0], start + Duration::from_millis(3)));
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for versioning updates using a hybrid logical clock.
+//!
+//! Each agent versions the updates it originates using a hybrid
+//! logical clock. The version consists of the physical time in
+//! milliseconds and a logical counter that is used to order updates
+//! made within the same millisecond, or while the physical clock is
+//! behind the last version issued.
+
+use chrono::Utc;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Version of an update.
+///
+/// Versions are ordered first by the physical time and then by the
+/// logical counter.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Version {
+    /// Physical time in milliseconds since the epoch.
+    pub millis: i64,
+
+    /// Logical counter.
+    pub counter: u32,
+}
+
+impl Version {
+    pub fn new(millis: i64, counter: u32) -> Version {
+        Version { millis, counter }
+    }
+
+    /// Check if this is the zero version, which is used for updates
+    /// that were not assigned a version.
+    pub fn is_zero(&self) -> bool {
+        *self == Version::default()
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.millis, self.counter)
+    }
+}
+
+/// Hybrid logical clock.
+///
+/// The clock never issues the same version twice and the versions
+/// issued are always increasing, even if the physical clock goes
+/// backwards. An agent has a single clock that is shared by all parts
+/// issuing updates, see `SharedClock`, since two clocks could issue
+/// the same version.
+#[derive(Debug, Default)]
+pub struct Clock {
+    last: Version,
+}
+
+/// Clock shared by all parts of an agent that issue updates.
+pub type SharedClock = Arc<Mutex<Clock>>;
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            last: Version::default(),
+        }
+    }
+
+    /// Issue a new version for an update.
+    pub fn tick(&mut self) -> Version {
+        let now = Utc::now().timestamp_millis();
+        self.last = if now > self.last.millis {
+            Version::new(now, 0)
+        } else {
+            Version::new(self.last.millis, self.last.counter + 1)
+        };
+        self.last
+    }
+
+    /// Observe a version issued by another clock, making sure that all
+    /// versions issued after this are newer.
+    pub fn observe(&mut self, version: &Version) {
+        if *version > self.last {
+            self.last = *version;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn versions_are_increasing() {
+        let mut clock = Clock::new();
+        let mut last = clock.tick();
+        for _ in 0..1000 {
+            let version = clock.tick();
+            assert!(version > last);
+            last = version;
+        }
+    }
+
+    #[test]
+    fn observed_version_is_exceeded() {
+        let mut clock = Clock::new();
+        let future = Version::new(Utc::now().timestamp_millis() + 60_000, 7);
+        clock.observe(&future);
+        assert_eq!(clock.tick(), Version::new(future.millis, 8));
+    }
+
+    #[test]
+    fn shared_clock_never_issues_same_version() {
+        let clock: SharedClock = Arc::new(Mutex::new(Clock::new()));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let clock = clock.clone();
+                thread::spawn(move || {
+                    (0..1000)
+                        .map(|_| clock.lock().unwrap().tick())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut versions: Vec<Version> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        let count = versions.len();
+        versions.sort();
+        versions.dedup();
+        assert_eq!(versions.len(), count);
+    }
+}
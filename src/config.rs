@@ -0,0 +1,635 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for the configuration of the agent.
+//!
+//! The configuration is read from a TOML file. All settings are
+//! optional and have defaults, so an empty file is a valid
+//! configuration. Durations are given in seconds.
+//!
+//! ```toml
+//! listen = "0.0.0.0:2428"
+//! http_listen = "127.0.0.1:2429"
+//! control_socket = "/run/chatter/control.sock"
+//! data_dir = "/var/lib/chatter"
+//! seeds = ["192.0.2.1:2428", "192.0.2.2:2428"]
+//! fanout = 3
+//! hops = 5
+//! log_level = "info"
+//!
+//! [probes]
+//! interval = 10
+//! disks = ["/", "/var"]
+//!
+//! [[probes.checks]]
+//! device = "root-disk"
+//! command = "/usr/lib/nagios/plugins/check_disk -w 20% -c 10% -p /"
+//! format = "nagios"
+//! interval = 60
+//!
+//! [security]
+//! auth_key = "/etc/chatter/auth.key"
+//! encryption = "required"
+//! encryption_key = "/etc/chatter/cluster.key"
+//! trusted_keys = "/etc/chatter/trusted"
+//!
+//! [[alerts]]
+//! name = "disk-full"
+//! device = "disk:*"
+//! metric = "used_percent"
+//! above = 90
+//! clear = 85
+//! for = 300
+//!
+//! [notifications]
+//! retries = 3
+//! retry_interval = 5
+//! failover_delay = 30
+//!
+//! [[notifications.sinks]]
+//! type = "webhook"
+//! url = "http://alerts.example.com:8080/chatter"
+//!
+//! [[notifications.sinks]]
+//! type = "syslog"
+//! facility = "daemon"
+//! ```
+
+use crate::alert::{self, Rule};
+use crate::crypto::EncryptionMode;
+use crate::devices::DEFAULT_TOMBSTONE_HORIZON;
+use crate::error::Error;
+use crate::gossip::{DEFAULT_HOPS, MAX_HOPS};
+use crate::metrics::{HistoryLimits, DEFAULT_HISTORY_AGE, DEFAULT_HISTORY_SAMPLES};
+use crate::notify::{
+    self, CommandSink, Facility, RetryPolicy, Sink, SyslogSink, WebhookSink, DEFAULT_SYSLOG_PATH,
+};
+use crate::probe::command::{self, OutputFormat};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default port that agents listen for gossip on.
+pub const DEFAULT_PORT: u16 = 2428;
+
+/// Default number of servers to forward each message to.
+pub const DEFAULT_FANOUT: usize = 3;
+
+/// Default time between anti-entropy synchronizations.
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default time between samples of the devices of the machine.
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Deserialize a duration given in seconds.
+pub(crate) fn seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+/// Deserialize an optional duration given in seconds.
+fn optional_seconds<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u64>::deserialize(deserializer).map(|secs| secs.map(Duration::from_secs))
+}
+
+/// Configuration of the agent.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen for gossip on.
+    pub listen: SocketAddr,
+
+    /// Address to serve the HTTP API on, if any.
+    pub http_listen: Option<SocketAddr>,
+
+    /// Path of the Unix-domain socket to accept control requests on,
+    /// if any.
+    pub control_socket: Option<PathBuf>,
+
+    /// Directory to store the UUID and keys of the agent in.
+    pub data_dir: Option<PathBuf>,
+
+    /// Addresses of servers to join the cluster through.
+    pub seeds: Vec<SocketAddr>,
+
+    /// Number of servers to forward each message to.
+    pub fanout: usize,
+
+    /// Number of hops that gossip originating from the agent travel.
+    pub hops: u32,
+
+    /// Time between anti-entropy synchronizations.
+    #[serde(deserialize_with = "seconds")]
+    pub sync_interval: Duration,
+
+    /// Time that removed devices and resolved alerts are remembered.
+    #[serde(deserialize_with = "seconds")]
+    pub tombstone_horizon: Duration,
+
+    /// Protocol version to send gossip with, if not the current.
+    pub protocol_version: Option<u8>,
+
+    /// Log filter, for example `info` or `chatter=debug`. The
+    /// `RUST_LOG` environment variable overrides it.
+    pub log_level: Option<String>,
+
+    pub history: HistoryConfig,
+    pub probes: ProbeConfig,
+    pub security: SecurityConfig,
+
+    /// Alert rules, evaluated against the devices of the agent each
+    /// probe interval.
+    pub alerts: Vec<Rule>,
+
+    pub notifications: NotifyConfig,
+}
+
+/// Configuration of the history kept for each metric.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Maximum number of samples.
+    pub samples: usize,
+
+    /// Maximum age of samples.
+    #[serde(deserialize_with = "seconds")]
+    pub age: Duration,
+}
+
+/// Configuration of the probes of the devices of the machine.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    /// Time between samples of the devices.
+    #[serde(deserialize_with = "seconds")]
+    pub interval: Duration,
+
+    /// Whether to probe the CPU, memory, disks, and network of the
+    /// machine.
+    pub system: bool,
+
+    /// Mount points of the file systems to probe.
+    pub disks: Vec<PathBuf>,
+
+    /// Time that a check may run before it is killed, unless given
+    /// for the check.
+    #[serde(deserialize_with = "seconds")]
+    pub timeout: Duration,
+
+    /// External check commands.
+    pub checks: Vec<CheckConfig>,
+}
+
+/// Configuration of an external check command.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckConfig {
+    /// Name of the device that the command checks.
+    pub device: String,
+
+    /// Shell command to run.
+    pub command: String,
+
+    /// Format of the output of the command.
+    #[serde(default = "default_format")]
+    pub format: OutputFormat,
+
+    /// Time between runs of the command, if not the probe interval.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub interval: Option<Duration>,
+
+    /// Time that the command may run, if not the probe timeout.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub timeout: Option<Duration>,
+}
+
+fn default_format() -> OutputFormat {
+    OutputFormat::Nagios
+}
+
+/// Configuration of the keys used to secure gossip.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// File with the key used to authenticate gossip.
+    pub auth_key: Option<PathBuf>,
+
+    /// File with an additional key accepted while rotating keys.
+    pub auth_key_secondary: Option<PathBuf>,
+
+    /// How gossip is encrypted.
+    pub encryption: EncryptionMode,
+
+    /// File with the cluster key used to encrypt gossip.
+    pub encryption_key: Option<PathBuf>,
+
+    /// File with an additional cluster key used to decrypt gossip
+    /// while rotating keys.
+    pub encryption_key_secondary: Option<PathBuf>,
+
+    /// Directory with the public keys of agents whose device updates
+    /// are accepted.
+    pub trusted_keys: Option<PathBuf>,
+}
+
+/// Configuration of the notifications sent when alerts fire or are
+/// resolved.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Number of times a failed delivery is retried.
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled for each retry.
+    #[serde(deserialize_with = "seconds")]
+    pub retry_interval: Duration,
+
+    /// Age of transitions that are too old to notify.
+    #[serde(deserialize_with = "seconds")]
+    pub max_age: Duration,
+
+    /// Time that each agent waits after the agent ranked before it,
+    /// before sending a notification that is not yet delivered.
+    #[serde(deserialize_with = "seconds")]
+    pub failover_delay: Duration,
+
+    /// Destinations of the notifications.
+    pub sinks: Vec<SinkConfig>,
+}
+
+/// Configuration of a destination of notifications.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SinkConfig {
+    /// Post the notification as JSON to an HTTP URL.
+    Webhook {
+        url: String,
+        #[serde(default, deserialize_with = "optional_seconds")]
+        timeout: Option<Duration>,
+    },
+
+    /// Run a shell command with the notification in the environment.
+    Command {
+        command: String,
+        #[serde(default, deserialize_with = "optional_seconds")]
+        timeout: Option<Duration>,
+    },
+
+    /// Log the notification to the local syslog.
+    Syslog {
+        #[serde(default = "default_facility")]
+        facility: Facility,
+        path: Option<PathBuf>,
+    },
+}
+
+fn default_facility() -> Facility {
+    Facility::Daemon
+}
+
+impl SinkConfig {
+    /// Construct the sink.
+    pub fn build(&self) -> Result<Box<dyn Sink>, Error> {
+        Ok(match self {
+            SinkConfig::Webhook { url, timeout } => Box::new(WebhookSink::new(
+                url,
+                timeout.unwrap_or(notify::DEFAULT_TIMEOUT),
+            )?),
+            SinkConfig::Command { command, timeout } => Box::new(CommandSink::new(
+                command,
+                timeout.unwrap_or(notify::DEFAULT_TIMEOUT),
+            )),
+            SinkConfig::Syslog { facility, path } => Box::new(SyslogSink::new(
+                path.clone()
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_SYSLOG_PATH)),
+                *facility,
+            )),
+        })
+    }
+}
+
+impl NotifyConfig {
+    /// Construct the configured sinks.
+    pub fn build_sinks(&self) -> Result<Vec<Box<dyn Sink>>, Error> {
+        self.sinks.iter().map(SinkConfig::build).collect()
+    }
+
+    /// How failed deliveries are retried.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            delay: self.retry_interval,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from a file.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let text = fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|err| Error::ConfigError(format!("{}: {}", path.display(), err)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the settings are consistent.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.fanout == 0 {
+            return Err(Error::ConfigError(
+                "fanout has to be at least 1".to_string(),
+            ));
+        }
+        if self.hops == 0 || self.hops > MAX_HOPS {
+            return Err(Error::ConfigError(format!(
+                "hops has to be between 1 and {}",
+                MAX_HOPS
+            )));
+        }
+        if self.security.auth_key_secondary.is_some() && self.security.auth_key.is_none() {
+            return Err(Error::ConfigError(
+                "auth_key_secondary requires auth_key".to_string(),
+            ));
+        }
+        if self.security.encryption_key_secondary.is_some()
+            && self.security.encryption_key.is_none()
+        {
+            return Err(Error::ConfigError(
+                "encryption_key_secondary requires encryption_key".to_string(),
+            ));
+        }
+        if self.security.encryption != EncryptionMode::None
+            && self.security.encryption_key.is_none()
+        {
+            return Err(Error::ConfigError(format!(
+                "encryption mode {} requires encryption_key",
+                self.security.encryption
+            )));
+        }
+        let mut devices = HashSet::new();
+        for check in &self.probes.checks {
+            if !devices.insert(&check.device) {
+                return Err(Error::ConfigError(format!(
+                    "device {} is checked more than once",
+                    check.device
+                )));
+            }
+        }
+        alert::validate_rules(&self.alerts)?;
+        self.notifications
+            .build_sinks()
+            .map(|_| ())
+            .map_err(|err| Error::ConfigError(err.to_string()))
+    }
+
+    /// Limits on the history kept for each metric.
+    pub fn history_limits(&self) -> HistoryLimits {
+        HistoryLimits {
+            max_samples: self.history.samples,
+            max_age: self.history.age,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DEFAULT_PORT),
+            http_listen: None,
+            control_socket: None,
+            data_dir: None,
+            seeds: Vec::new(),
+            fanout: DEFAULT_FANOUT,
+            hops: DEFAULT_HOPS,
+            sync_interval: DEFAULT_SYNC_INTERVAL,
+            tombstone_horizon: DEFAULT_TOMBSTONE_HORIZON,
+            protocol_version: None,
+            log_level: None,
+            history: HistoryConfig::default(),
+            probes: ProbeConfig::default(),
+            security: SecurityConfig::default(),
+            alerts: Vec::new(),
+            notifications: NotifyConfig::default(),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            samples: DEFAULT_HISTORY_SAMPLES,
+            age: DEFAULT_HISTORY_AGE,
+        }
+    }
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            interval: DEFAULT_PROBE_INTERVAL,
+            system: true,
+            disks: vec![PathBuf::from("/")],
+            timeout: command::DEFAULT_TIMEOUT,
+            checks: Vec::new(),
+        }
+    }
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            retries: notify::DEFAULT_RETRIES,
+            retry_interval: notify::DEFAULT_RETRY_DELAY,
+            max_age: notify::DEFAULT_MAX_AGE,
+            failover_delay: notify::DEFAULT_FAILOVER_DELAY,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        SecurityConfig {
+            auth_key: None,
+            auth_key_secondary: None,
+            encryption: EncryptionMode::None,
+            encryption_key: None,
+            encryption_key_secondary: None,
+            trusted_keys: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// The example in the module documentation.
+    const EXAMPLE: &str = r#"
+listen = "0.0.0.0:2428"
+http_listen = "127.0.0.1:2429"
+control_socket = "/run/chatter/control.sock"
+data_dir = "/var/lib/chatter"
+seeds = ["192.0.2.1:2428", "192.0.2.2:2428"]
+fanout = 3
+hops = 5
+log_level = "info"
+
+[probes]
+interval = 10
+disks = ["/", "/var"]
+
+[[probes.checks]]
+device = "root-disk"
+command = "/usr/lib/nagios/plugins/check_disk -w 20% -c 10% -p /"
+format = "nagios"
+interval = 60
+
+[security]
+auth_key = "/etc/chatter/auth.key"
+encryption = "required"
+encryption_key = "/etc/chatter/cluster.key"
+trusted_keys = "/etc/chatter/trusted"
+
+[[alerts]]
+name = "disk-full"
+device = "disk:*"
+metric = "used_percent"
+above = 90
+clear = 85
+for = 300
+
+[notifications]
+retries = 3
+retry_interval = 5
+failover_delay = 30
+
+[[notifications.sinks]]
+type = "webhook"
+url = "http://alerts.example.com:8080/chatter"
+
+[[notifications.sinks]]
+type = "syslog"
+facility = "daemon"
+"#;
+
+    fn parse(text: &str) -> Result<Config, Error> {
+        let config: Config =
+            toml::from_str(text).map_err(|err| Error::ConfigError(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn empty_file_gives_defaults() {
+        assert_eq!(parse("").unwrap(), Config::default());
+        let config = Config::default();
+        assert_eq!(config.listen.port(), DEFAULT_PORT);
+        assert_eq!(config.fanout, DEFAULT_FANOUT);
+        assert_eq!(config.hops, DEFAULT_HOPS);
+        assert_eq!(config.probes.disks, vec![PathBuf::from("/")]);
+        assert!(config.probes.system);
+        assert_eq!(config.security.encryption, EncryptionMode::None);
+        assert_eq!(
+            config.notifications.failover_delay,
+            notify::DEFAULT_FAILOVER_DELAY
+        );
+    }
+
+    #[test]
+    fn example_is_parsed() {
+        let config = parse(EXAMPLE).unwrap();
+        assert_eq!(config.http_listen, Some("127.0.0.1:2429".parse().unwrap()));
+        assert_eq!(config.seeds.len(), 2);
+        assert_eq!(config.log_level.as_deref(), Some("info"));
+        assert_eq!(config.probes.interval, Duration::from_secs(10));
+        assert_eq!(config.probes.checks[0].format, OutputFormat::Nagios);
+        assert_eq!(
+            config.probes.checks[0].interval,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(config.probes.checks[0].timeout, None);
+        assert_eq!(config.security.encryption, EncryptionMode::Required);
+        assert_eq!(config.alerts[0].duration, Duration::from_secs(300));
+        assert_eq!(
+            config.notifications.retry_policy().delay,
+            Duration::from_secs(5)
+        );
+        assert_eq!(config.notifications.sinks.len(), 2);
+        assert_eq!(
+            config.notifications.sinks[1],
+            SinkConfig::Syslog {
+                facility: Facility::Daemon,
+                path: None,
+            }
+        );
+
+        // Settings that are not given keep their defaults.
+        assert_eq!(config.sync_interval, DEFAULT_SYNC_INTERVAL);
+        assert_eq!(config.history, HistoryConfig::default());
+        assert_eq!(config.notifications.max_age, notify::DEFAULT_MAX_AGE);
+    }
+
+    #[test]
+    fn unknown_and_malformed_settings_are_rejected() {
+        assert!(parse("fanuot = 3").is_err());
+        assert!(parse("[probes]\nintervall = 10").is_err());
+        assert!(parse("[[notifications.sinks]]\ntype = \"email\"").is_err());
+        assert!(parse("listen = \"not an address\"").is_err());
+        assert!(parse("sync_interval = -1").is_err());
+    }
+
+    #[test]
+    fn inconsistent_settings_are_rejected() {
+        let invalid = [
+            "fanout = 0",
+            "hops = 0",
+            &format!("hops = {}", MAX_HOPS + 1),
+            "[security]\nauth_key_secondary = \"/old.key\"",
+            "[security]\nencryption_key_secondary = \"/old.key\"",
+            "[security]\nencryption = \"optional\"",
+            "[[probes.checks]]\ndevice = \"a\"\ncommand = \"true\"\n\
+             [[probes.checks]]\ndevice = \"a\"\ncommand = \"false\"",
+            "[[alerts]]\nname = \"a\"\nmetric = \"m\"",
+            "[[alerts]]\nname = \"a\"\nmetric = \"m\"\nabove = 1\n\
+             [[alerts]]\nname = \"a\"\nmetric = \"m\"\nabove = 2",
+            "[[notifications.sinks]]\ntype = \"webhook\"\nurl = \"https://example.com/\"",
+        ];
+        for text in invalid.iter() {
+            assert!(parse(text).is_err(), "accepted {:?}", text);
+        }
+        assert!(parse(&format!("hops = {}", MAX_HOPS)).is_ok());
+    }
+
+    #[test]
+    fn load_reports_the_path_of_invalid_files() {
+        let path = std::env::temp_dir().join(format!("chatter-test-{}.toml", Uuid::new_v4()));
+        fs::write(&path, EXAMPLE).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), parse(EXAMPLE).unwrap());
+        fs::write(&path, "fanout = \"many\"").unwrap();
+        let err = Config::load(&path).unwrap_err().to_string();
+        assert!(err.contains(&path.display().to_string()), "{}", err);
+        fs::remove_file(&path).unwrap();
+        assert!(Config::load(&path).is_err());
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for administering the agent through a control socket.
+//!
+//! The agent listens for control requests on a Unix-domain socket.
+//! Each request is a JSON object on a single line with the name of
+//! the command in the `command` field, for example:
+//!
+//! ```json
+//! {"command": "set-metric", "device": "gateway", "metric": "up", "value": {"Boolean": true}}
+//! ```
+//!
+//! The agent replies with a single line holding either `{"ok":
+//! <result>}` or `{"error": <message>}`.
+//!
+//! Devices added through the control socket are owned and signed by
+//! the agent, so only devices owned by the agent can be changed or
+//! removed.
+
+use crate::api;
+use crate::clock::SharedClock;
+use crate::devices::{DeviceUpdate, Metric};
+use crate::error::Error;
+use crate::gossip::Action;
+use crate::join::Joiner;
+use crate::signing::Signer;
+use crate::state::State;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Request sent to the control socket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Get the servers in the view of the agent.
+    Members,
+
+    /// Get the devices, optionally only those of one owner.
+    Devices { owner: Option<Uuid> },
+
+    /// Get a single device.
+    Device { owner: Uuid, name: String },
+
+    /// Add a device owned by the agent.
+    AddDevice { name: String, description: String },
+
+    /// Remove a device owned by the agent.
+    RemoveDevice { name: String },
+
+    /// Set a metric of a device owned by the agent.
+    SetMetric {
+        device: String,
+        metric: String,
+        value: Metric,
+    },
+
+    /// Join a cluster through a server.
+    Join { address: SocketAddr },
+
+    /// Leave the cluster and shut down the agent.
+    Leave,
+
+    /// Get the counters of the agent.
+    Stats,
+
+    /// Get the firing and recently resolved alerts.
+    Alerts,
+}
+
+/// Reply to a control request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+impl From<Result<Value, Error>> for Response {
+    fn from(result: Result<Value, Error>) -> Response {
+        match result {
+            Ok(value) => Response::Ok(value),
+            Err(err) => Response::Error(err.to_string()),
+        }
+    }
+}
+
+/// Counters of the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub uuid: Uuid,
+    pub received: u64,
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub decode_errors: u64,
+    pub view_size: usize,
+    pub devices: usize,
+}
+
+/// Handler of control requests.
+pub struct Controller {
+    signer: Arc<Signer>,
+    joiner: Arc<Mutex<Joiner>>,
+    clock: SharedClock,
+}
+
+impl Controller {
+    pub fn new(signer: Arc<Signer>, joiner: Arc<Mutex<Joiner>>, clock: SharedClock) -> Controller {
+        Controller {
+            signer,
+            joiner,
+            clock,
+        }
+    }
+
+    /// Handle a control request.
+    ///
+    /// Returns the result to reply with together with the actions to
+    /// perform. A leave request has no actions, since leaving shuts
+    /// down the agent, which is up to the caller.
+    pub fn handle(
+        &mut self,
+        request: &Request,
+        state: &State,
+    ) -> Result<(Value, Vec<Action>), Error> {
+        let uuid = *self.signer.uuid();
+        match request {
+            Request::Members => Ok((to_value(&api::members(state))?, Vec::new())),
+
+            Request::Devices { owner } => {
+                Ok((to_value(&api::devices(state, owner.as_ref()))?, Vec::new()))
+            }
+
+            Request::Device { owner, name } => match api::device(state, owner, name) {
+                Some(device) => Ok((to_value(&device)?, Vec::new())),
+                None => Err(Error::ControlError(format!(
+                    "no device {} owned by {}",
+                    name, owner
+                ))),
+            },
+
+            Request::AddDevice { name, description } => {
+                self.observe(state, name);
+                let update = DeviceUpdate::DeviceAdded {
+                    origin: uuid,
+                    name: name.clone(),
+                    description: description.clone(),
+                    version: self.clock.lock().expect("unable to lock clock").tick(),
+                    signature: None,
+                };
+                info!("Adding device {}", name);
+                Ok((Value::Null, vec![self.publish(update)]))
+            }
+
+            Request::RemoveDevice { name } => {
+                if !self.observe(state, name) {
+                    return Err(Error::ControlError(format!(
+                        "no device {} owned by the agent",
+                        name
+                    )));
+                }
+                let update = DeviceUpdate::DeviceRemoved {
+                    origin: uuid,
+                    name: name.clone(),
+                    version: self.clock.lock().expect("unable to lock clock").tick(),
+                    signature: None,
+                };
+                info!("Removing device {}", name);
+                Ok((Value::Null, vec![self.publish(update)]))
+            }
+
+            Request::SetMetric {
+                device,
+                metric,
+                value,
+            } => {
+                value.validate().map_err(|msg| {
+                    Error::ControlError(format!("invalid value for {}: {}", metric, msg))
+                })?;
+                if !self.observe(state, device) {
+                    return Err(Error::ControlError(format!(
+                        "no device {} owned by the agent",
+                        device
+                    )));
+                }
+                let mut metrics = HashMap::new();
+                metrics.insert(metric.clone(), value.clone());
+                let update = DeviceUpdate::DeviceStatus {
+                    origin: uuid,
+                    name: device.clone(),
+                    metrics,
+                    version: self.clock.lock().expect("unable to lock clock").tick(),
+                    signatures: HashMap::new(),
+                };
+                debug!(
+                    "Setting metric {} of device {} to {:?}",
+                    metric, device, value
+                );
+                Ok((Value::Null, vec![self.publish(update)]))
+            }
+
+            Request::Join { address } => {
+                let actions = self
+                    .joiner
+                    .lock()
+                    .expect("unable to lock joiner")
+                    .join(*address);
+                Ok((Value::Null, actions))
+            }
+
+            Request::Leave => Ok((Value::Null, Vec::new())),
+
+            Request::Alerts => Ok((to_value(&api::alerts(state))?, Vec::new())),
+
+            Request::Stats => {
+                let stats = Stats {
+                    uuid,
+                    received: state.stats.received(),
+                    forwarded: state.stats.forwarded(),
+                    dropped: state.stats.duplicates(),
+                    decode_errors: state.stats.rejected(),
+                    view_size: state
+                        .view
+                        .lock()
+                        .expect("unable to lock view for stats")
+                        .servers
+                        .len(),
+                    devices: state
+                        .devices
+                        .lock()
+                        .expect("unable to lock device collection for stats")
+                        .iter()
+                        .count(),
+                };
+                Ok((to_value(&stats)?, Vec::new()))
+            }
+        }
+    }
+
+    /// Observe the versions of a device owned by the agent, so that the
+    /// next update of the device is newer than anything issued for it
+    /// before. Returns `false` if the agent does not own the device.
+    fn observe(&mut self, state: &State, name: &str) -> bool {
+        let devices = state
+            .devices
+            .lock()
+            .expect("unable to lock device collection for control");
+        match devices.get(self.signer.uuid(), name) {
+            Some(info) => {
+                let mut clock = self.clock.lock().expect("unable to lock clock");
+                clock.observe(&info.version);
+                for entry in info.metrics.values() {
+                    clock.observe(&entry.version);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn publish(&self, mut update: DeviceUpdate) -> Action {
+        self.signer.sign(&mut update);
+        Action::Publish(update)
+    }
+}
+
+fn to_value<T: serde::Serialize>(value: &T) -> Result<Value, Error> {
+    serde_json::to_value(value).map_err(|err| Error::ControlError(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, Version};
+
+    fn controller() -> Controller {
+        let uuid = Uuid::new_v4();
+        let addr = "127.0.0.1:2428".parse().unwrap();
+        Controller::new(
+            Arc::new(Signer::generate(uuid)),
+            Arc::new(Mutex::new(Joiner::new(uuid, addr, Vec::new()))),
+            Arc::new(Mutex::new(Clock::new())),
+        )
+    }
+
+    fn added(origin: Uuid, name: &str, version: Version) -> DeviceUpdate {
+        DeviceUpdate::DeviceAdded {
+            origin,
+            name: name.to_string(),
+            description: String::new(),
+            version,
+            signature: None,
+        }
+    }
+
+    fn set_metric(device: &str) -> Request {
+        Request::SetMetric {
+            device: device.to_string(),
+            metric: "up".to_string(),
+            value: Metric::Boolean(true),
+        }
+    }
+
+    #[test]
+    fn only_devices_of_agent_can_be_changed() {
+        let mut controller = controller();
+        let other = Uuid::new_v4();
+        let mut state = State::new();
+        state.update_devices(&added(other, "gateway", Version::new(1, 0)), &other, 0);
+
+        for request in &[
+            set_metric("gateway"),
+            Request::RemoveDevice {
+                name: "gateway".to_string(),
+            },
+        ] {
+            match controller.handle(request, &state) {
+                Err(Error::ControlError(msg)) => assert!(msg.contains("owned by the agent")),
+                result => panic!("expected error for {:?}, got {:?}", request, result),
+            }
+        }
+    }
+
+    #[test]
+    fn updates_are_newer_than_existing_versions() {
+        let mut controller = controller();
+        let uuid = *controller.signer.uuid();
+        let mut state = State::new();
+
+        // Versions from the future, as left behind by an earlier run of
+        // the agent with a clock running ahead.
+        let future = chrono::Utc::now().timestamp_millis() + 3_600_000;
+        state.update_devices(&added(uuid, "gateway", Version::new(future, 2)), &uuid, 0);
+        let mut metrics = HashMap::new();
+        metrics.insert("up".to_string(), Metric::Boolean(false));
+        let mut status = DeviceUpdate::DeviceStatus {
+            origin: uuid,
+            name: "gateway".to_string(),
+            metrics,
+            version: Version::new(future, 5),
+            signatures: HashMap::new(),
+        };
+        controller.signer.sign(&mut status);
+        state.update_devices(&status, &uuid, 0);
+
+        let requests = [
+            set_metric("gateway"),
+            Request::RemoveDevice {
+                name: "gateway".to_string(),
+            },
+        ];
+        for request in &requests {
+            let (_, actions) = controller.handle(request, &state).unwrap();
+            match &actions[..] {
+                [Action::Publish(DeviceUpdate::DeviceStatus { version, .. })]
+                | [Action::Publish(DeviceUpdate::DeviceRemoved { version, .. })] => {
+                    assert!(*version > Version::new(future, 5), "{:?}", version)
+                }
+                actions => panic!("unexpected actions {:?}", actions),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_metric_values_are_rejected() {
+        let mut controller = controller();
+        let uuid = *controller.signer.uuid();
+        let mut state = State::new();
+        state.update_devices(&added(uuid, "gateway", Version::new(1, 0)), &uuid, 0);
+
+        let request = Request::SetMetric {
+            device: "gateway".to_string(),
+            metric: "latency".to_string(),
+            value: Metric::Histogram(crate::metrics::Histogram {
+                bounds: vec![1.0],
+                counts: vec![1],
+                sum: 1.0,
+                count: 1,
+                start: chrono::Utc::now(),
+            }),
+        };
+        assert!(controller.handle(&request, &state).is_err());
+    }
+}
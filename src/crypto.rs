@@ -0,0 +1,266 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for encrypting datagrams using a cluster key.
+//!
+//! Datagrams are encrypted using XChaCha20-Poly1305 with a random
+//! nonce for each datagram. The envelope header is used as associated
+//! data, so it cannot be changed without the datagram being rejected.
+//!
+//! Encryption can be enabled for a running cluster by changing the
+//! encryption mode of all agents in steps: first from `none` to
+//! `accept`, then to `optional`, and finally to `required`.
+//!
+//! Datagrams are encrypted using the primary cluster key. A received
+//! datagram is decrypted using either the primary or the secondary
+//! key, which allows the key to be rotated without stopping the
+//! cluster:
+//!
+//! 1. Add the new key as secondary key on all agents.
+//!
+//! 2. Make the new key the primary key and the old key the secondary
+//!    key on all agents.
+//!
+//! 3. Remove the old key from all agents.
+
+use crate::error::Error;
+use crate::hex;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Size of the cluster key.
+pub const KEY_SIZE: usize = 32;
+
+/// Size of the nonce at the start of each encrypted payload.
+pub const NONCE_SIZE: usize = 24;
+
+/// How datagrams are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionMode {
+    /// Datagrams are neither encrypted nor decrypted.
+    None,
+
+    /// Encrypted datagrams are decrypted, but datagrams are sent
+    /// unencrypted.
+    Accept,
+
+    /// Datagrams are sent encrypted, but unencrypted datagrams are
+    /// still accepted.
+    Optional,
+
+    /// Datagrams are sent encrypted and unencrypted datagrams are
+    /// rejected.
+    Required,
+}
+
+impl EncryptionMode {
+    /// Check if outgoing datagrams are encrypted.
+    pub fn encrypts(&self) -> bool {
+        matches!(self, EncryptionMode::Optional | EncryptionMode::Required)
+    }
+
+    /// Check if incoming datagrams that are not encrypted are
+    /// accepted.
+    pub fn accepts_plaintext(&self) -> bool {
+        *self != EncryptionMode::Required
+    }
+}
+
+impl FromStr for EncryptionMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(EncryptionMode::None),
+            "accept" => Ok(EncryptionMode::Accept),
+            "optional" => Ok(EncryptionMode::Optional),
+            "required" => Ok(EncryptionMode::Required),
+            _ => Err(Error::KeyError(format!(
+                "unknown encryption mode '{}', expected none, accept, optional, or required",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EncryptionMode::None => write!(f, "none"),
+            EncryptionMode::Accept => write!(f, "accept"),
+            EncryptionMode::Optional => write!(f, "optional"),
+            EncryptionMode::Required => write!(f, "required"),
+        }
+    }
+}
+
+/// Keys shared by all agents in the cluster.
+pub struct ClusterKey {
+    primary: XChaCha20Poly1305,
+    secondary: Option<XChaCha20Poly1305>,
+}
+
+impl ClusterKey {
+    /// Construct cluster keys.
+    ///
+    /// # Parameters
+    ///
+    /// * `primary` - The key used to encrypt outgoing datagrams.
+    ///
+    /// * `secondary` - Additional key used to decrypt incoming
+    ///   datagrams while rotating keys.
+    ///
+    pub fn new(primary: &[u8], secondary: Option<&[u8]>) -> Result<ClusterKey, Error> {
+        Ok(ClusterKey {
+            primary: cipher(primary)?,
+            secondary: secondary.map(cipher).transpose()?,
+        })
+    }
+
+    /// Load cluster keys from files.
+    ///
+    /// Each file should contain the key as hexadecimal digits, for
+    /// example as generated by `openssl rand -hex 32`.
+    pub fn load(primary: &Path, secondary: Option<&Path>) -> Result<ClusterKey, Error> {
+        let secondary = secondary.map(read_key).transpose()?;
+        ClusterKey::new(&read_key(primary)?, secondary.as_deref())
+    }
+
+    /// Encrypt data, returning the nonce followed by the ciphertext.
+    pub fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .primary
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| Error::KeyError("unable to encrypt datagram".to_string()))?;
+        let mut result = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        result.extend_from_slice(&nonce);
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    /// Decrypt data consisting of the nonce followed by the
+    /// ciphertext.
+    pub fn decrypt(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_SIZE {
+            return Err(Error::ProtocolError("truncated nonce".to_string()));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        std::iter::once(&self.primary)
+            .chain(self.secondary.iter())
+            .find_map(|cipher| {
+                cipher
+                    .decrypt(
+                        XNonce::from_slice(nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad,
+                        },
+                    )
+                    .ok()
+            })
+            .ok_or_else(|| Error::AuthenticationError("unable to decrypt datagram".to_string()))
+    }
+}
+
+fn read_key(path: &Path) -> Result<Vec<u8>, Error> {
+    hex::decode(fs::read_to_string(path)?.trim())
+        .ok_or_else(|| Error::KeyError("cluster key is not a hexadecimal string".to_string()))
+}
+
+fn cipher(key: &[u8]) -> Result<XChaCha20Poly1305, Error> {
+    if key.len() != KEY_SIZE {
+        return Err(Error::KeyError(format!(
+            "cluster key is {} bytes, but should be {} bytes",
+            key.len(),
+            KEY_SIZE
+        )));
+    }
+    XChaCha20Poly1305::new_from_slice(key)
+        .map_err(|_| Error::KeyError("invalid cluster key".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIMARY: [u8; KEY_SIZE] = [1; KEY_SIZE];
+    const SECONDARY: [u8; KEY_SIZE] = [2; KEY_SIZE];
+    const FOREIGN: [u8; KEY_SIZE] = [3; KEY_SIZE];
+
+    fn key(primary: &[u8], secondary: Option<&[u8]>) -> ClusterKey {
+        ClusterKey::new(primary, secondary).unwrap()
+    }
+
+    #[test]
+    fn decrypts_own_datagrams() {
+        let key = key(&PRIMARY, None);
+        let data = key.encrypt(b"header", b"message").unwrap();
+        assert_eq!(key.decrypt(b"header", &data).unwrap(), b"message");
+    }
+
+    #[test]
+    fn rejects_tampered_datagrams() {
+        let key = key(&PRIMARY, None);
+        let data = key.encrypt(b"header", b"message").unwrap();
+        assert!(key.decrypt(b"other header", &data).is_err());
+        for pos in &[0, NONCE_SIZE, data.len() - 1] {
+            let mut tampered = data.clone();
+            tampered[*pos] ^= 1;
+            assert!(key.decrypt(b"header", &tampered).is_err());
+        }
+        assert!(key.decrypt(b"header", &data[..NONCE_SIZE - 1]).is_err());
+        assert!(key.decrypt(b"header", &data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_foreign_keys() {
+        let data = key(&FOREIGN, None).encrypt(b"header", b"message").unwrap();
+        assert!(key(&PRIMARY, Some(&SECONDARY))
+            .decrypt(b"header", &data)
+            .is_err());
+    }
+
+    #[test]
+    fn decrypts_with_secondary_key_during_rotation() {
+        let old = key(&PRIMARY, None);
+        let rotating = key(&SECONDARY, Some(&PRIMARY));
+        let new = key(&SECONDARY, None);
+
+        let data = old.encrypt(b"header", b"message").unwrap();
+        assert_eq!(rotating.decrypt(b"header", &data).unwrap(), b"message");
+        assert!(new.decrypt(b"header", &data).is_err());
+
+        let data = rotating.encrypt(b"header", b"message").unwrap();
+        assert_eq!(new.decrypt(b"header", &data).unwrap(), b"message");
+        assert!(old.decrypt(b"header", &data).is_err());
+    }
+
+    #[test]
+    fn rejects_keys_of_wrong_size() {
+        assert!(ClusterKey::new(&PRIMARY[1..], None).is_err());
+        assert!(ClusterKey::new(&PRIMARY, Some(&SECONDARY[1..])).is_err());
+    }
+}
@@ -14,17 +14,37 @@
 
 //! Module for managing the device collection.
 
+use crate::clock::Version;
+pub use crate::metrics::Metric;
+use crate::metrics::{Aggregate, History, HistoryLimits, Sample};
+use crate::signing::{Record, Signature, TrustedKeys};
+use crate::sync;
+use crate::watch::{Event, Watchers};
 use std::collections::HashMap;
 use std::fmt;
 use std::string::String;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
+/// Value of a metric together with the version of the update that
+/// set it.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum Metric {
-    Text(String),
+pub struct MetricEntry {
+    pub value: Metric,
+    pub version: Version,
+
+    /// Signature of the metric by the owner of the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+
+    /// Recent values of the metric. The history is local to the agent
+    /// and is not part of snapshots.
+    #[serde(skip)]
+    pub history: History,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DeviceInfo {
     /// The UUID of the agent that is responsible for the device.
     pub owner: Uuid,
@@ -35,9 +55,16 @@ pub struct DeviceInfo {
     /// Description of the device.
     pub description: String,
 
+    /// Version of the update that added the device.
+    pub version: Version,
+
+    /// Signature of the device by the owner.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+
     /// Collection of metrics containing the current status of the
     /// device.
-    pub metrics: HashMap<String, Metric>,
+    pub metrics: HashMap<String, MetricEntry>,
 }
 
 impl fmt::Display for DeviceInfo {
@@ -66,94 +93,850 @@ impl DeviceInfo {
     ///
     pub fn new(uuid: &Uuid, name: &str, descr: &str) -> DeviceInfo {
         DeviceInfo {
-            owner: uuid.clone(),
+            owner: *uuid,
             name: String::from(name),
             description: String::from(descr),
+            version: Version::default(),
+            signature: None,
             metrics: HashMap::new(),
         }
     }
+
+    /// Copy the device without the history of the metrics.
+    fn snapshot(&self) -> DeviceInfo {
+        DeviceInfo {
+            owner: self.owner,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            version: self.version,
+            signature: self.signature,
+            metrics: self
+                .metrics
+                .iter()
+                .map(|(name, entry)| {
+                    (
+                        name.clone(),
+                        MetricEntry {
+                            value: entry.value.clone(),
+                            version: entry.version,
+                            signature: entry.signature,
+                            history: History::new(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Set the value of metrics, unless the current value supersedes
+    /// it according to the merge semantics of the metric. Values that
+    /// are not well-formed are ignored.
+    ///
+    /// All values are recorded in the history of the metric, even if
+    /// they do not replace the current value, since they can be older
+    /// values arriving late.
+    ///
+    /// Returns the names of the metrics that were set.
+    fn set_metrics<'a, I>(&mut self, metrics: I, limits: &HistoryLimits) -> Vec<String>
+    where
+        I: IntoIterator<Item = (&'a String, &'a Metric, Version, Option<Signature>)>,
+    {
+        let mut changed = Vec::new();
+        for (name, value, version, signature) in metrics {
+            if let Err(err) = value.validate() {
+                warn!(
+                    "Ignoring metric {} of device {} on {}: {}",
+                    name, self.name, self.owner, err
+                );
+                continue;
+            }
+            match self.metrics.get_mut(name) {
+                Some(entry) if !value.supersedes(&entry.value, version > entry.version) => {
+                    entry.history.record(version.millis, value, limits);
+                }
+                Some(entry) => {
+                    entry.history.record(version.millis, value, limits);
+                    entry.value = value.clone();
+                    entry.version = version;
+                    entry.signature = signature;
+                    changed.push(name.clone());
+                }
+                None => {
+                    let mut history = History::new();
+                    history.record(version.millis, value, limits);
+                    self.metrics.insert(
+                        name.clone(),
+                        MetricEntry {
+                            value: value.clone(),
+                            version,
+                            signature,
+                            history,
+                        },
+                    );
+                    changed.push(name.clone());
+                }
+            }
+        }
+        changed
+    }
 }
 
+/// Updates of devices.
+///
+/// Each update carries the version assigned by the agent owning the
+/// device. Updates without a version get the timestamp of the message
+/// carrying them as version.
+///
+/// Updates are signed by the agent owning the device, see the
+/// `signing` module. Status updates carry one signature for each
+/// metric.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum DeviceUpdate {
     DeviceAdded {
         origin: Uuid,
         name: String,
         description: String,
+        #[serde(default)]
+        version: Version,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<Signature>,
     },
 
     DeviceRemoved {
         origin: Uuid,
         name: String,
+        #[serde(default)]
+        version: Version,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<Signature>,
     },
 
     DeviceStatus {
         origin: Uuid,
         name: String,
         metrics: HashMap<String, Metric>,
+        #[serde(default)]
+        version: Version,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        signatures: HashMap<String, Signature>,
     },
 }
 
+impl DeviceUpdate {
+    /// The agent owning the updated device.
+    pub fn origin(&self) -> &Uuid {
+        match self {
+            DeviceUpdate::DeviceAdded { origin, .. }
+            | DeviceUpdate::DeviceRemoved { origin, .. }
+            | DeviceUpdate::DeviceStatus { origin, .. } => origin,
+        }
+    }
+
+    /// The version of the update.
+    pub fn version(&self) -> &Version {
+        match self {
+            DeviceUpdate::DeviceAdded { version, .. }
+            | DeviceUpdate::DeviceRemoved { version, .. }
+            | DeviceUpdate::DeviceStatus { version, .. } => version,
+        }
+    }
+
+    /// Get the update in a form that agents speaking an older
+    /// protocol version can decode.
+    ///
+    /// Metrics that the agents cannot decode are removed from status
+    /// updates, together with their signatures. Returns `None` if no
+    /// metrics remain.
+    pub fn downgrade(self, protocol_version: u8) -> Option<DeviceUpdate> {
+        match self {
+            DeviceUpdate::DeviceStatus {
+                origin,
+                name,
+                metrics,
+                version,
+                signatures,
+            } => {
+                let metrics: HashMap<String, Metric> = metrics
+                    .into_iter()
+                    .filter(|(_, value)| value.is_supported(protocol_version))
+                    .collect();
+                if metrics.is_empty() {
+                    return None;
+                }
+                let signatures = signatures
+                    .into_iter()
+                    .filter(|(key, _)| metrics.contains_key(key))
+                    .collect();
+                Some(DeviceUpdate::DeviceStatus {
+                    origin,
+                    name,
+                    metrics,
+                    version,
+                    signatures,
+                })
+            }
+            update => Some(update),
+        }
+    }
+}
+
+/// Removed device.
+///
+/// Removed devices are remembered until the tombstone horizon has
+/// passed, so that older updates of the device are not applied.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tombstone {
+    pub name: String,
+    pub version: Version,
+
+    /// Signature of the removal by the owner of the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+}
+
+/// Removal of a device, kept until the tombstone horizon has passed.
+#[derive(Debug)]
+struct Removal {
+    version: Version,
+    signature: Option<Signature>,
+}
+
+/// Snapshot of all devices owned by an agent.
+///
+/// Snapshots are exchanged during anti-entropy to bring agents that
+/// missed updates up to date.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceSnapshot {
+    /// The agent owning the devices.
+    pub origin: Uuid,
+
+    /// Version of the devices for the agent.
+    pub version: Version,
+
+    /// All devices of the agent.
+    pub devices: Vec<DeviceInfo>,
+
+    /// All removed devices of the agent.
+    pub tombstones: Vec<Tombstone>,
+}
+
+impl DeviceSnapshot {
+    /// Version of the newest device, metric, or removal in the
+    /// snapshot.
+    pub fn newest(&self) -> Version {
+        let devices = self.devices.iter().flat_map(|info| {
+            std::iter::once(info.version).chain(info.metrics.values().map(|entry| entry.version))
+        });
+        let tombstones = self.tombstones.iter().map(|tombstone| tombstone.version);
+        devices.chain(tombstones).max().unwrap_or_default()
+    }
+
+    /// Get the snapshot in a form that agents speaking an older
+    /// protocol version can decode, by removing the metrics that they
+    /// cannot decode.
+    pub fn downgrade(mut self, protocol_version: u8) -> DeviceSnapshot {
+        for info in &mut self.devices {
+            info.metrics
+                .retain(|_, entry| entry.value.is_supported(protocol_version));
+        }
+        self
+    }
+}
+
+/// Default time that removed devices are remembered.
+pub const DEFAULT_TOMBSTONE_HORIZON: Duration = Duration::from_secs(3600);
+
 #[derive(Debug)]
 pub struct DeviceCollection {
     devices: HashMap<Uuid, HashMap<String, DeviceInfo>>,
+
+    /// Removed devices for each agent.
+    tombstones: HashMap<Uuid, HashMap<String, Removal>>,
+
+    /// Version of the devices for each agent, which is the version of
+    /// the latest update for the agent.
+    versions: HashMap<Uuid, Version>,
+
+    /// Time that removed devices are remembered.
+    tombstone_horizon: Duration,
+
+    /// Keys used to verify that updates are signed by the owner of
+    /// the device. Updates are not verified if there are no keys.
+    trusted_keys: Option<TrustedKeys>,
+
+    /// Limits on the history kept for each metric.
+    history_limits: HistoryLimits,
+
+    /// Subscribers to changes of the devices.
+    watchers: Arc<Watchers>,
 }
 
 impl DeviceCollection {
     pub fn new() -> DeviceCollection {
         DeviceCollection {
             devices: HashMap::new(),
+            tombstones: HashMap::new(),
+            versions: HashMap::new(),
+            tombstone_horizon: DEFAULT_TOMBSTONE_HORIZON,
+            trusted_keys: None,
+            history_limits: HistoryLimits::default(),
+            watchers: Arc::new(Watchers::new()),
+        }
+    }
+
+    /// Set the time that removed devices are remembered.
+    ///
+    /// Updates of a removed device that arrive after the tombstone
+    /// was collected can bring the device back, so the horizon should
+    /// be well above the time it takes for updates to propagate.
+    pub fn set_tombstone_horizon(&mut self, horizon: Duration) {
+        self.tombstone_horizon = horizon;
+    }
+
+    /// Set the keys used to verify updates.
+    ///
+    /// With keys, updates that are not signed by the owner of the
+    /// device are rejected. Without keys, all updates are accepted.
+    pub fn set_trusted_keys(&mut self, trusted_keys: Option<TrustedKeys>) {
+        self.trusted_keys = trusted_keys;
+    }
+
+    /// Set the limits on the history kept for each metric.
+    pub fn set_history_limits(&mut self, limits: HistoryLimits) {
+        self.history_limits = limits;
+    }
+
+    /// Set the subscribers that changes of the devices are published
+    /// to.
+    pub fn set_watchers(&mut self, watchers: Arc<Watchers>) {
+        self.watchers = watchers;
+    }
+
+    /// Publish the change of metrics of a device.
+    fn publish_metrics(&self, origin: &Uuid, name: &str, metrics: Vec<String>) {
+        let info = match self.get(origin, name) {
+            Some(info) => info,
+            None => return,
+        };
+        for metric in metrics {
+            if let Some(entry) = info.metrics.get(&metric) {
+                self.watchers.publish(Event::MetricChanged {
+                    owner: *origin,
+                    device: name.to_string(),
+                    metric,
+                    value: entry.value.clone(),
+                    version: entry.version,
+                });
+            }
+        }
+    }
+
+    /// Remove tombstones older than the tombstone horizon and metric
+    /// samples older than the history limit.
+    pub fn collect_garbage(&mut self, now_millis: i64) {
+        let oldest_sample = now_millis - self.history_limits.max_age.as_millis() as i64;
+        for info in self.devices.values_mut().flat_map(|map| map.values_mut()) {
+            for entry in info.metrics.values_mut() {
+                entry.history.expire(oldest_sample);
+            }
+        }
+
+        let oldest = now_millis - self.tombstone_horizon.as_millis() as i64;
+        for tombstones in self.tombstones.values_mut() {
+            tombstones.retain(|_, removal| removal.version.millis >= oldest);
+        }
+        self.tombstones
+            .retain(|_, tombstones| !tombstones.is_empty());
+    }
+
+    /// Get all devices owned by an agent.
+    pub fn owned_by(&self, owner: &Uuid) -> Vec<&DeviceInfo> {
+        self.devices
+            .get(owner)
+            .map(|map| map.values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Get a device.
+    pub fn get(&self, owner: &Uuid, name: &str) -> Option<&DeviceInfo> {
+        self.devices.get(owner).and_then(|map| map.get(name))
+    }
+
+    /// Iterate over all devices.
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceInfo> {
+        self.devices.values().flat_map(|map| map.values())
+    }
+
+    /// Get the history of a metric of a device.
+    pub fn history(&self, origin: &Uuid, device: &str, metric: &str) -> Option<&History> {
+        self.devices
+            .get(origin)
+            .and_then(|map| map.get(device))
+            .and_then(|info| info.metrics.get(metric))
+            .map(|entry| &entry.history)
+    }
+
+    /// Get the samples of a metric in a time range, given as
+    /// milliseconds since the epoch.
+    pub fn samples(
+        &self,
+        origin: &Uuid,
+        device: &str,
+        metric: &str,
+        from_millis: i64,
+        to_millis: i64,
+    ) -> Vec<Sample> {
+        self.history(origin, device, metric)
+            .map(|history| history.range(from_millis, to_millis).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Get the value of a metric at a point in time, which is the
+    /// newest sample at or before the time.
+    pub fn sample_at(
+        &self,
+        origin: &Uuid,
+        device: &str,
+        metric: &str,
+        timestamp_millis: i64,
+    ) -> Option<Sample> {
+        self.history(origin, device, metric)
+            .and_then(|history| history.at(timestamp_millis))
+            .cloned()
+    }
+
+    /// Get the minimum, maximum, and average of a metric in a time
+    /// range.
+    pub fn aggregate(
+        &self,
+        origin: &Uuid,
+        device: &str,
+        metric: &str,
+        from_millis: i64,
+        to_millis: i64,
+    ) -> Option<Aggregate> {
+        self.history(origin, device, metric)
+            .and_then(|history| history.aggregate(from_millis, to_millis))
+    }
+
+    /// Get the rate of change per second of a metric in a time range.
+    pub fn rate(
+        &self,
+        origin: &Uuid,
+        device: &str,
+        metric: &str,
+        from_millis: i64,
+        to_millis: i64,
+    ) -> Option<f64> {
+        self.history(origin, device, metric)
+            .and_then(|history| history.rate(from_millis, to_millis))
+    }
+
+    /// Get the version of the devices for each agent.
+    pub fn digest(&self) -> HashMap<Uuid, Version> {
+        self.versions.clone()
+    }
+
+    /// Get a fingerprint of the devices of each agent, covering the
+    /// version of each device, metric, and removal.
+    pub fn fingerprints(&self) -> HashMap<Uuid, u64> {
+        self.versions
+            .keys()
+            .map(|origin| (*origin, self.fingerprint(origin)))
+            .collect()
+    }
+
+    fn fingerprint(&self, origin: &Uuid) -> u64 {
+        let devices = self.devices.get(origin).into_iter().flat_map(|map| {
+            map.values().flat_map(|info| {
+                let metrics = info.metrics.iter().map(move |(metric, entry)| {
+                    (format!("m\0{}\0{}", info.name, metric), entry.version)
+                });
+                std::iter::once((format!("d\0{}", info.name), info.version)).chain(metrics)
+            })
+        });
+        let tombstones = self.tombstones.get(origin).into_iter().flat_map(|map| {
+            map.iter()
+                .map(|(name, removal)| (format!("t\0{}", name), removal.version))
+        });
+        sync::fingerprint(devices.chain(tombstones))
+    }
+
+    /// Get snapshots of all agents with a version newer than in the
+    /// digest, or with a fingerprint that differs from the digest.
+    ///
+    /// Agents that are missing from the fingerprints are only
+    /// compared by version.
+    pub fn delta(
+        &self,
+        digest: &HashMap<Uuid, Version>,
+        fingerprints: &HashMap<Uuid, u64>,
+    ) -> Vec<DeviceSnapshot> {
+        self.versions
+            .iter()
+            .filter(|(origin, version)| {
+                digest.get(origin).is_none_or(|v| v < *version)
+                    || fingerprints
+                        .get(origin)
+                        .is_some_and(|fingerprint| *fingerprint != self.fingerprint(origin))
+            })
+            .map(|(origin, version)| DeviceSnapshot {
+                origin: *origin,
+                version: *version,
+                devices: self
+                    .devices
+                    .get(origin)
+                    .map(|map| map.values().map(DeviceInfo::snapshot).collect())
+                    .unwrap_or_default(),
+                tombstones: self
+                    .tombstones
+                    .get(origin)
+                    .map(|map| {
+                        map.iter()
+                            .map(|(name, removal)| Tombstone {
+                                name: name.clone(),
+                                version: removal.version,
+                                signature: removal.signature,
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Merge snapshots into the collection.
+    ///
+    /// Each device, metric, and removal in the snapshot is merged
+    /// separately and only replaces the local entry if it is newer.
+    ///
+    /// If updates are verified, entries that are not signed by the
+    /// owner are ignored. The version of the snapshot is then not
+    /// trusted either, so the version of the agent is only increased
+    /// to the newest entry merged. Otherwise, only the snapshot of
+    /// `sender` itself is merged, like in `update`.
+    pub fn merge(&mut self, snapshots: &[DeviceSnapshot], sender: &Uuid) {
+        for snapshot in snapshots {
+            let origin = &snapshot.origin;
+            if self.trusted_keys.is_none() && origin != sender {
+                debug!(
+                    "Ignoring snapshot of devices of {} sent by {}",
+                    origin, sender
+                );
+                continue;
+            }
+            let mut changed = false;
+            let mut rejected = 0;
+            let mut newest = Version::default();
+            for tombstone in &snapshot.tombstones {
+                let record = Record::Removed {
+                    origin,
+                    name: &tombstone.name,
+                    version: tombstone.version,
+                };
+                if !self.is_signed(&record, tombstone.signature.as_ref()) {
+                    rejected += 1;
+                    continue;
+                }
+                if self.remove(
+                    origin,
+                    &tombstone.name,
+                    tombstone.version,
+                    tombstone.signature,
+                ) {
+                    changed = true;
+                    newest = std::cmp::max(newest, tombstone.version);
+                }
+            }
+            for info in &snapshot.devices {
+                let record = Record::Device {
+                    origin,
+                    name: &info.name,
+                    description: &info.description,
+                    version: info.version,
+                };
+                if !self.is_signed(&record, info.signature.as_ref()) {
+                    rejected += 1 + info.metrics.len();
+                    continue;
+                }
+                if self.add(
+                    origin,
+                    &info.name,
+                    &info.description,
+                    info.version,
+                    info.signature,
+                ) {
+                    changed = true;
+                    newest = std::cmp::max(newest, info.version);
+                }
+                let metrics: Vec<_> = info
+                    .metrics
+                    .iter()
+                    .filter(|(name, entry)| {
+                        let record = Record::Metric {
+                            origin,
+                            device: &info.name,
+                            metric: name,
+                            value: &entry.value,
+                            version: entry.version,
+                        };
+                        let signed = self.is_signed(&record, entry.signature.as_ref());
+                        if !signed {
+                            rejected += 1;
+                        }
+                        signed
+                    })
+                    .map(|(name, entry)| (name, &entry.value, entry.version, entry.signature))
+                    .collect();
+                let metrics_version = metrics.iter().map(|(_, _, version, _)| *version).max();
+                let limits = self.history_limits;
+                if let Some(device) = self.device_mut(origin, &info.name, info.version) {
+                    let updated = device.set_metrics(metrics, &limits);
+                    if !updated.is_empty() {
+                        changed = true;
+                        newest = std::cmp::max(newest, metrics_version.unwrap_or_default());
+                        self.publish_metrics(origin, &info.name, updated);
+                    }
+                }
+            }
+            if rejected > 0 {
+                warn!(
+                    "Ignored {} entries of {} in snapshot that are not signed by the owner",
+                    rejected, origin
+                );
+            }
+            if changed {
+                let version = if rejected > 0 {
+                    newest
+                } else {
+                    snapshot.version
+                };
+                debug!("Merged devices of {} with version {}", origin, version);
+                self.bump(origin, version);
+            }
+        }
+    }
+
+    /// Check that an entry is signed by its owner, if updates are
+    /// verified.
+    fn is_signed(&self, record: &Record, signature: Option<&Signature>) -> bool {
+        match self.trusted_keys {
+            Some(ref trusted_keys) => match trusted_keys.verify(record, signature) {
+                Ok(()) => true,
+                Err(err) => {
+                    debug!("Ignoring entry in snapshot: {}", err);
+                    false
+                }
+            },
+            None => true,
+        }
+    }
+
+    fn bump(&mut self, origin: &Uuid, version: Version) {
+        let current = self.versions.entry(*origin).or_default();
+        *current = std::cmp::max(*current, version);
+    }
+
+    fn removed(&self, origin: &Uuid, name: &str) -> Option<&Version> {
+        self.tombstones
+            .get(origin)
+            .and_then(|map| map.get(name))
+            .map(|removal| &removal.version)
+    }
+
+    /// Get a device, unless it was removed by a newer update than
+    /// `version`.
+    fn device_mut(
+        &mut self,
+        origin: &Uuid,
+        name: &str,
+        version: Version,
+    ) -> Option<&mut DeviceInfo> {
+        if self.removed(origin, name).is_some_and(|v| *v >= version) {
+            return None;
         }
+        self.devices
+            .get_mut(origin)
+            .and_then(|map| map.get_mut(name))
     }
 
-    pub fn update(&mut self, gossip: &DeviceUpdate, _origin: &Uuid, _timestamp_millis: i64) {
-        match gossip {
+    fn add(
+        &mut self,
+        origin: &Uuid,
+        name: &str,
+        description: &str,
+        version: Version,
+        signature: Option<Signature>,
+    ) -> bool {
+        if self.removed(origin, name).is_some_and(|v| *v >= version) {
+            return false;
+        }
+        if let Some(map) = self.tombstones.get_mut(origin) {
+            map.remove(name);
+        }
+        let devices = self.devices.entry(*origin).or_default();
+        match devices.get_mut(name) {
+            Some(info) if info.version >= version => false,
+            Some(info) => {
+                info.description = description.to_string();
+                info.version = version;
+                info.signature = signature;
+                true
+            }
+            None => {
+                let mut info = DeviceInfo::new(origin, name, description);
+                info.version = version;
+                info.signature = signature;
+                devices.insert(name.to_string(), info);
+                self.watchers.publish(Event::DeviceAdded {
+                    owner: *origin,
+                    name: name.to_string(),
+                    description: description.to_string(),
+                });
+                true
+            }
+        }
+    }
+
+    fn remove(
+        &mut self,
+        origin: &Uuid,
+        name: &str,
+        version: Version,
+        signature: Option<Signature>,
+    ) -> bool {
+        if self.removed(origin, name).is_some_and(|v| *v >= version) {
+            return false;
+        }
+        if let Some(devices) = self.devices.get_mut(origin) {
+            if devices
+                .get(name)
+                .is_some_and(|info| info.version >= version)
+            {
+                return false;
+            }
+            if devices.remove(name).is_some() {
+                self.watchers.publish(Event::DeviceRemoved {
+                    owner: *origin,
+                    name: name.to_string(),
+                });
+            }
+        }
+        self.tombstones
+            .entry(*origin)
+            .or_default()
+            .insert(name.to_string(), Removal { version, signature });
+        true
+    }
+
+    /// Apply an update sent by `sender`.
+    ///
+    /// Without trusted keys, signatures cannot be verified, so only
+    /// updates sent by the owner of the device are accepted. The
+    /// sender is not authenticated, so this does not stop forged
+    /// updates.
+    pub fn update(&mut self, gossip: &DeviceUpdate, sender: &Uuid, timestamp_millis: i64) {
+        match self.trusted_keys {
+            Some(ref trusted_keys) => {
+                if let Err(err) = trusted_keys.verify_update(gossip) {
+                    warn!("Rejected update of devices of {}: {}", gossip.origin(), err);
+                    return;
+                }
+            }
+            None if gossip.origin() != sender => {
+                warn!(
+                    "Rejected update of devices of {} sent by {}",
+                    gossip.origin(),
+                    sender
+                );
+                return;
+            }
+            None => (),
+        }
+        let version = if gossip.version().is_zero() {
+            Version::new(timestamp_millis, 0)
+        } else {
+            *gossip.version()
+        };
+        let applied = match gossip {
             DeviceUpdate::DeviceAdded {
                 origin,
                 name,
                 description,
+                signature,
+                ..
             } => {
-                self.devices
-                    .entry(origin.clone())
-                    .or_insert(HashMap::new())
-                    .insert(
-                        name.to_string(),
-                        DeviceInfo::new(origin, &name, &description),
-                    );
-                debug!("Added device {} to {}", name, origin);
+                let added = self.add(origin, name, description, version, *signature);
+                if added {
+                    debug!("Added device {} to {}", name, origin);
+                }
+                added
             }
 
-            DeviceUpdate::DeviceRemoved { origin, name } => {
-                if let Some(entry) = self.devices.get_mut(origin) {
-                    entry.remove(name);
+            DeviceUpdate::DeviceRemoved {
+                origin,
+                name,
+                signature,
+                ..
+            } => {
+                let removed = self.remove(origin, name, version, *signature);
+                if removed {
                     debug!("Removed device {} from {}", name, origin);
                 }
+                removed
             }
 
             DeviceUpdate::DeviceStatus {
                 origin,
                 name,
                 metrics,
+                signatures,
+                ..
             } => {
-                if let Some(agent) = self.devices.get_mut(origin) {
-                    if let Some(info) = agent.get_mut(name) {
-                        for (metric, value) in metrics {
-                            *info
-                                .metrics
-                                .entry(metric.to_string())
-                                .or_insert(value.clone()) = value.clone();
+                let limits = self.history_limits;
+                match self.device_mut(origin, name, version) {
+                    Some(info) => {
+                        let updated = info.set_metrics(
+                            metrics.iter().map(|(key, value)| {
+                                (key, value, version, signatures.get(key).copied())
+                            }),
+                            &limits,
+                        );
+                        if updated.is_empty() {
+                            false
+                        } else {
+                            debug!("Updated device {} on {}: {:?}", name, origin, metrics);
+                            self.publish_metrics(origin, name, updated);
+                            true
                         }
                     }
-                    debug!("Updated device {} on {}: {:?}", name, origin, metrics);
-                } else {
-                    warn!(
-                        "Update of device {} on agent {} failed - agent not added",
-                        name, origin
-                    );
+                    None => {
+                        warn!(
+                            "Update of device {} on agent {} failed - device not added",
+                            name, origin
+                        );
+                        false
+                    }
                 }
             }
+        };
+
+        if applied {
+            self.bump(gossip.origin(), version);
+            info!("Devices updated: {}", *self);
+        } else {
+            debug!(
+                "Discarded update of {} with version {}: superseded by current state",
+                gossip.origin(),
+                version
+            );
         }
-        info!("Devices updated: {}", *self);
+    }
+}
+
+impl Default for DeviceCollection {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -167,3 +950,290 @@ impl fmt::Display for DeviceCollection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::Signer;
+
+    fn added(origin: Uuid, description: &str, millis: i64) -> DeviceUpdate {
+        DeviceUpdate::DeviceAdded {
+            origin,
+            name: "disk".to_string(),
+            description: description.to_string(),
+            version: Version::new(millis, 0),
+            signature: None,
+        }
+    }
+
+    fn removed(origin: Uuid, millis: i64) -> DeviceUpdate {
+        DeviceUpdate::DeviceRemoved {
+            origin,
+            name: "disk".to_string(),
+            version: Version::new(millis, 0),
+            signature: None,
+        }
+    }
+
+    fn status(origin: Uuid, value: i64, millis: i64) -> DeviceUpdate {
+        let mut metrics = HashMap::new();
+        metrics.insert("used".to_string(), Metric::Integer(value));
+        DeviceUpdate::DeviceStatus {
+            origin,
+            name: "disk".to_string(),
+            metrics,
+            version: Version::new(millis, 0),
+            signatures: HashMap::new(),
+        }
+    }
+
+    fn used(devices: &DeviceCollection, origin: &Uuid) -> Option<Metric> {
+        devices
+            .get(origin, "disk")
+            .and_then(|info| info.metrics.get("used"))
+            .map(|entry| entry.value.clone())
+    }
+
+    #[test]
+    fn last_writer_wins_regardless_of_arrival_order() {
+        let origin = Uuid::new_v4();
+        let mut devices = DeviceCollection::new();
+        devices.update(&added(origin, "disk", 1), &origin, 0);
+        devices.update(&status(origin, 20, 3), &origin, 0);
+        devices.update(&status(origin, 10, 2), &origin, 0);
+        assert_eq!(used(&devices, &origin), Some(Metric::Integer(20)));
+        assert_eq!(devices.digest()[&origin], Version::new(3, 0));
+    }
+
+    #[test]
+    fn same_millisecond_is_ordered_by_counter() {
+        let origin = Uuid::new_v4();
+        let mut devices = DeviceCollection::new();
+        devices.update(&added(origin, "disk", 1), &origin, 0);
+        let mut first = status(origin, 10, 5);
+        let mut second = status(origin, 20, 5);
+        if let DeviceUpdate::DeviceStatus { version, .. } = &mut second {
+            version.counter = 1;
+        }
+        devices.update(&second, &origin, 0);
+        devices.update(&first, &origin, 0);
+        assert_eq!(used(&devices, &origin), Some(Metric::Integer(20)));
+        if let DeviceUpdate::DeviceStatus { version, .. } = &mut first {
+            version.counter = 2;
+        }
+        devices.update(&first, &origin, 0);
+        assert_eq!(used(&devices, &origin), Some(Metric::Integer(10)));
+    }
+
+    #[test]
+    fn only_owner_may_update_without_trusted_keys() {
+        let origin = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let mut devices = DeviceCollection::new();
+        devices.update(&added(origin, "disk", 1), &other, 0);
+        assert!(devices.get(&origin, "disk").is_none());
+
+        devices.update(&added(origin, "disk", 1), &origin, 0);
+        devices.update(&removed(origin, 2), &other, 0);
+        assert!(devices.get(&origin, "disk").is_some());
+    }
+
+    #[test]
+    fn trusted_keys_accept_signed_updates_from_any_sender() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let origin = *signer.uuid();
+        let other = Uuid::new_v4();
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.insert(origin, signer.verifying_key());
+        let mut devices = DeviceCollection::new();
+        devices.set_trusted_keys(Some(trusted_keys));
+
+        devices.update(&added(origin, "disk", 1), &origin, 0);
+        assert!(devices.get(&origin, "disk").is_none());
+
+        let mut update = added(origin, "disk", 1);
+        signer.sign(&mut update);
+        devices.update(&update, &other, 0);
+        assert!(devices.get(&origin, "disk").is_some());
+    }
+
+    #[test]
+    fn tombstone_rejects_older_updates() {
+        let origin = Uuid::new_v4();
+        let mut devices = DeviceCollection::new();
+        devices.update(&added(origin, "disk", 1), &origin, 0);
+        devices.update(&removed(origin, 3), &origin, 0);
+        assert!(devices.get(&origin, "disk").is_none());
+
+        devices.update(&added(origin, "disk", 2), &origin, 0);
+        devices.update(&status(origin, 10, 2), &origin, 0);
+        assert!(devices.get(&origin, "disk").is_none());
+
+        devices.update(&added(origin, "new disk", 4), &origin, 0);
+        assert_eq!(
+            devices
+                .get(&origin, "disk")
+                .map(|info| info.description.as_str()),
+            Some("new disk")
+        );
+    }
+
+    #[test]
+    fn removal_older_than_device_is_ignored() {
+        let origin = Uuid::new_v4();
+        let mut devices = DeviceCollection::new();
+        devices.update(&added(origin, "disk", 5), &origin, 0);
+        devices.update(&removed(origin, 4), &origin, 0);
+        assert!(devices.get(&origin, "disk").is_some());
+    }
+
+    #[test]
+    fn tombstones_are_collected_after_horizon() {
+        let origin = Uuid::new_v4();
+        let mut devices = DeviceCollection::new();
+        devices.set_tombstone_horizon(Duration::from_millis(100));
+        devices.update(&added(origin, "disk", 1), &origin, 0);
+        devices.update(&removed(origin, 2), &origin, 0);
+        devices.collect_garbage(50);
+        assert!(devices.removed(&origin, "disk").is_some());
+        devices.collect_garbage(200);
+        assert!(devices.removed(&origin, "disk").is_none());
+    }
+
+    #[test]
+    fn merge_keeps_newer_local_entries() {
+        let origin = Uuid::new_v4();
+        let mut source = DeviceCollection::new();
+        source.update(&added(origin, "disk", 1), &origin, 0);
+        source.update(&status(origin, 10, 2), &origin, 0);
+        let snapshots = source.delta(&HashMap::new(), &HashMap::new());
+
+        let mut target = DeviceCollection::new();
+        target.update(&added(origin, "disk", 1), &origin, 0);
+        target.update(&status(origin, 30, 3), &origin, 0);
+        target.merge(&snapshots, &origin);
+        assert_eq!(used(&target, &origin), Some(Metric::Integer(30)));
+    }
+
+    #[test]
+    fn merge_ignores_snapshots_of_others_without_trusted_keys() {
+        let origin = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let mut source = DeviceCollection::new();
+        source.update(&added(origin, "disk", 1), &origin, 0);
+        let snapshots = source.delta(&HashMap::new(), &HashMap::new());
+
+        let mut target = DeviceCollection::new();
+        target.merge(&snapshots, &other);
+        assert!(target.get(&origin, "disk").is_none());
+        target.merge(&snapshots, &origin);
+        assert!(target.get(&origin, "disk").is_some());
+    }
+
+    #[test]
+    fn merge_ignores_tampered_and_foreign_signatures() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let impostor = Signer::generate(Uuid::new_v4());
+        let origin = *signer.uuid();
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.insert(origin, signer.verifying_key());
+        trusted_keys.insert(*impostor.uuid(), impostor.verifying_key());
+
+        let mut source = DeviceCollection::new();
+        let mut add = added(origin, "disk", 1);
+        signer.sign(&mut add);
+        let mut update = status(origin, 10, 2);
+        signer.sign(&mut update);
+        source.update(&add, &origin, 0);
+        source.update(&update, &origin, 0);
+        let snapshots = source.delta(&HashMap::new(), &HashMap::new());
+
+        // A signed snapshot whose metric was edited.
+        let mut edited = snapshots.clone();
+        if let Some(entry) = edited[0].devices[0].metrics.get_mut("used") {
+            entry.value = Metric::Integer(99);
+        }
+        let mut target = DeviceCollection::new();
+        target.set_trusted_keys(Some(trusted_keys.clone()));
+        target.merge(&edited, impostor.uuid());
+        assert!(target.get(&origin, "disk").is_some());
+        assert_eq!(used(&target, &origin), None);
+
+        // Entries signed with the key of another trusted agent.
+        let mut forged = DeviceCollection::new();
+        let mut add = added(origin, "disk", 1);
+        impostor.sign(&mut add);
+        let mut update = status(origin, 99, 3);
+        impostor.sign(&mut update);
+        forged.update(&add, &origin, 0);
+        forged.update(&update, &origin, 0);
+        let mut target = DeviceCollection::new();
+        target.set_trusted_keys(Some(trusted_keys.clone()));
+        target.merge(
+            &forged.delta(&HashMap::new(), &HashMap::new()),
+            impostor.uuid(),
+        );
+        assert!(target.get(&origin, "disk").is_none());
+        assert!(target.digest().is_empty());
+
+        // The untouched snapshot is accepted from any sender.
+        let mut target = DeviceCollection::new();
+        target.set_trusted_keys(Some(trusted_keys));
+        target.merge(&snapshots, impostor.uuid());
+        assert_eq!(used(&target, &origin), Some(Metric::Integer(10)));
+    }
+
+    #[test]
+    fn updates_with_changed_metric_are_rejected() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let origin = *signer.uuid();
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.insert(origin, signer.verifying_key());
+        let mut devices = DeviceCollection::new();
+        devices.set_trusted_keys(Some(trusted_keys));
+        let mut add = added(origin, "disk", 1);
+        signer.sign(&mut add);
+        devices.update(&add, &origin, 0);
+
+        let mut update = status(origin, 10, 2);
+        signer.sign(&mut update);
+        if let DeviceUpdate::DeviceStatus { metrics, .. } = &mut update {
+            metrics.insert("used".to_string(), Metric::Integer(99));
+        }
+        devices.update(&update, &origin, 0);
+        assert_eq!(used(&devices, &origin), None);
+    }
+
+    #[test]
+    fn queries_use_history_of_metric() {
+        let origin = Uuid::new_v4();
+        let mut devices = DeviceCollection::new();
+        devices.update(&added(origin, "disk", 1), &origin, 0);
+        for (value, millis) in &[(10, 1000), (30, 3000), (20, 2000)] {
+            devices.update(&status(origin, *value, *millis), &origin, 0);
+        }
+
+        let samples = devices.samples(&origin, "disk", "used", 1500, 3000);
+        let values: Vec<_> = samples.iter().map(|s| s.value.clone()).collect();
+        assert_eq!(values, vec![Metric::Integer(20), Metric::Integer(30)]);
+        assert_eq!(
+            devices
+                .sample_at(&origin, "disk", "used", 2500)
+                .map(|s| s.value),
+            Some(Metric::Integer(20))
+        );
+        let aggregate = devices.aggregate(&origin, "disk", "used", 0, 3000).unwrap();
+        assert_eq!((aggregate.count, aggregate.avg), (3, 20.0));
+        assert_eq!(
+            devices.rate(&origin, "disk", "used", 1000, 3000),
+            Some(10.0)
+        );
+
+        assert!(devices.samples(&origin, "disk", "free", 0, 3000).is_empty());
+        assert!(devices.sample_at(&origin, "cpu", "used", 3000).is_none());
+        assert!(devices
+            .aggregate(&Uuid::new_v4(), "disk", "used", 0, 3000)
+            .is_none());
+        assert!(devices.rate(&origin, "disk", "used", 0, 1000).is_none());
+    }
+}
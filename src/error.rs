@@ -17,15 +17,51 @@ pub enum Error {
     /// Error when parsing network address
     AddrError(std::net::AddrParseError),
 
+    /// Configuration that is not valid.
+    ConfigError(String),
+
+    /// Control request that could not be performed.
+    ControlError(String),
+
+    /// Message that could not be authenticated.
+    AuthenticationError(String),
+
     /// I/O error.
     IoError(std::io::Error),
+
+    /// Error when serializing or deserializing a message.
+    SerializationError(serde_cbor::error::Error),
+
+    /// Key that cannot be used.
+    KeyError(String),
+
+    /// Notification that could not be delivered.
+    NotifyError(String),
+
+    /// Device that could not be probed.
+    ProbeError(String),
+
+    /// Message that does not follow the protocol.
+    ProtocolError(String),
+
+    /// Error when parsing a UUID.
+    UuidError(uuid::ParseError),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
             Error::AddrError(ref err) => write!(f, "Address error: {}", err),
+            Error::AuthenticationError(ref msg) => write!(f, "Authentication error: {}", msg),
+            Error::ConfigError(ref msg) => write!(f, "Configuration error: {}", msg),
+            Error::ControlError(ref msg) => write!(f, "Control error: {}", msg),
             Error::IoError(ref err) => write!(f, "I/O error: {}", err),
+            Error::KeyError(ref msg) => write!(f, "Key error: {}", msg),
+            Error::SerializationError(ref err) => write!(f, "Serialization error: {}", err),
+            Error::NotifyError(ref msg) => write!(f, "Notification error: {}", msg),
+            Error::ProbeError(ref msg) => write!(f, "Probe error: {}", msg),
+            Error::ProtocolError(ref msg) => write!(f, "Protocol error: {}", msg),
+            Error::UuidError(ref err) => write!(f, "UUID error: {}", err),
         }
     }
 }
@@ -35,6 +71,15 @@ impl std::error::Error for Error {
         match *self {
             Error::AddrError(ref err) => Some(err),
             Error::IoError(ref err) => Some(err),
+            Error::SerializationError(ref err) => Some(err),
+            Error::AuthenticationError(_)
+            | Error::ConfigError(_)
+            | Error::ControlError(_)
+            | Error::KeyError(_)
+            | Error::NotifyError(_)
+            | Error::ProbeError(_)
+            | Error::ProtocolError(_) => None,
+            Error::UuidError(ref err) => Some(err),
         }
     }
 }
@@ -45,8 +90,20 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<serde_cbor::error::Error> for Error {
+    fn from(error: serde_cbor::error::Error) -> Self {
+        Error::SerializationError(error)
+    }
+}
+
 impl From<std::net::AddrParseError> for Error {
     fn from(error: std::net::AddrParseError) -> Self {
         Error::AddrError(error)
     }
 }
+
+impl From<uuid::ParseError> for Error {
+    fn from(error: uuid::ParseError) -> Self {
+        Error::UuidError(error)
+    }
+}
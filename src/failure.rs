@@ -0,0 +1,567 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for failure detection.
+//!
+//! The failure detector is based on the SWIM protocol. Each protocol
+//! period, a random member of the view is sent a ping. If no
+//! acknowledgement arrives within the ping timeout, a number of other
+//! members are asked to ping the member on our behalf. If none of them
+//! get an acknowledgement either, the member is suspected and the
+//! suspicion is disseminated to the cluster. A member that is
+//! suspected for longer than the suspect timeout is declared dead.
+//!
+//! A member that learns that it is suspected refutes the suspicion by
+//! increasing its incarnation number and disseminating that it is
+//! alive.
+//!
+//! The failure detector does not do any I/O itself. Instead, it
+//! returns a list of actions that the caller should perform.
+
+use crate::gossip::{Action, Gossip};
+use crate::view::{ServerStatus, ServerView, ViewUpdate};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Configuration of the failure detector.
+#[derive(Debug, Clone)]
+pub struct DetectorConfig {
+    /// Time between probes of members.
+    pub protocol_period: Duration,
+
+    /// Time to wait for an acknowledgement before asking other
+    /// members to probe.
+    pub ping_timeout: Duration,
+
+    /// Number of members asked to probe on our behalf.
+    pub indirect_probes: usize,
+
+    /// Time a member is suspected before it is declared dead.
+    pub suspect_timeout: Duration,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        DetectorConfig {
+            protocol_period: Duration::from_secs(1),
+            ping_timeout: Duration::from_millis(300),
+            indirect_probes: 3,
+            suspect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Outstanding probe of a member.
+struct Probe {
+    target: Uuid,
+    addr: SocketAddr,
+    started: Instant,
+    indirect: bool,
+
+    /// Members asked to probe the target indirectly, which may relay
+    /// its acknowledgement.
+    helpers: Vec<Uuid>,
+}
+
+/// Outstanding ping made on behalf of another member.
+struct Relay {
+    requester: SocketAddr,
+    target: Uuid,
+    seq: u64,
+    started: Instant,
+}
+
+pub struct FailureDetector<R: Rng> {
+    uuid: Uuid,
+    config: DetectorConfig,
+    rng: R,
+    next_seq: u64,
+    incarnation: u64,
+    last_probe: Option<Instant>,
+    probes: HashMap<u64, Probe>,
+    relays: HashMap<u64, Relay>,
+    suspects: HashMap<Uuid, Instant>,
+}
+
+impl FailureDetector<StdRng> {
+    /// Construct a failure detector for the agent with the given
+    /// UUID.
+    pub fn new(uuid: Uuid, config: DetectorConfig) -> FailureDetector<StdRng> {
+        FailureDetector::with_rng(uuid, config, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> FailureDetector<R> {
+    /// Construct a failure detector using the provided random
+    /// generator to select members to probe.
+    pub fn with_rng(uuid: Uuid, config: DetectorConfig, rng: R) -> FailureDetector<R> {
+        FailureDetector {
+            uuid,
+            config,
+            rng,
+            next_seq: 0,
+            incarnation: 0,
+            last_probe: None,
+            probes: HashMap::new(),
+            relays: HashMap::new(),
+            suspects: HashMap::new(),
+        }
+    }
+
+    /// Configuration of the failure detector.
+    pub fn config(&self) -> &DetectorConfig {
+        &self.config
+    }
+
+    /// Current incarnation number of this agent.
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    /// Check outstanding probes and suspicions, and start a probe of
+    /// a new member if a protocol period has passed since the last
+    /// probe.
+    ///
+    /// Should be called at least as often as the ping timeout.
+    pub fn tick(&mut self, view: &ServerView, now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
+        self.check_probes(view, now, &mut actions);
+        self.check_suspects(view, now, &mut actions);
+
+        let period = self.config.protocol_period;
+        self.relays
+            .retain(|_, relay| now.duration_since(relay.started) < period);
+
+        if let Some(last_probe) = self.last_probe {
+            if now.duration_since(last_probe) < period {
+                return actions;
+            }
+        }
+        self.last_probe = Some(now);
+
+        let probed: Vec<Uuid> = self.probes.values().map(|probe| probe.target).collect();
+        let mut candidates: Vec<(&Uuid, SocketAddr)> = view
+            .servers
+            .iter()
+            .filter(|(uuid, info)| {
+                **uuid != self.uuid && info.status.is_active() && !probed.contains(uuid)
+            })
+            .map(|(uuid, info)| (uuid, info.address))
+            .collect();
+        candidates.sort_by_key(|(uuid, _)| **uuid);
+        if let Some(&(target, addr)) = candidates.choose(&mut self.rng) {
+            let seq = self.next_seq();
+            debug!("Probing server {} at {} (seq {})", target, addr, seq);
+            self.probes.insert(
+                seq,
+                Probe {
+                    target: *target,
+                    addr,
+                    started: now,
+                    indirect: false,
+                    helpers: Vec::new(),
+                },
+            );
+            actions.push(Action::Send(Gossip::Ping { seq }, addr));
+        }
+        actions
+    }
+
+    fn check_probes(&mut self, view: &ServerView, now: Instant, actions: &mut Vec<Action>) {
+        let mut expired = Vec::new();
+        let uuid = self.uuid;
+        let config = &self.config;
+        let rng = &mut self.rng;
+        for (seq, probe) in self.probes.iter_mut() {
+            let elapsed = now.duration_since(probe.started);
+            if !probe.indirect && elapsed >= config.ping_timeout {
+                let mut helpers: Vec<(&Uuid, SocketAddr)> = view
+                    .servers
+                    .iter()
+                    .filter(|(member, info)| {
+                        **member != uuid
+                            && **member != probe.target
+                            && info.status == ServerStatus::Alive
+                    })
+                    .map(|(uuid, info)| (uuid, info.address))
+                    .collect();
+                helpers.sort_by_key(|(uuid, _)| **uuid);
+                debug!(
+                    "No acknowledgement from {} (seq {}), probing indirectly",
+                    probe.target, seq
+                );
+                for (helper, addr) in helpers.choose_multiple(rng, config.indirect_probes) {
+                    actions.push(Action::Send(
+                        Gossip::PingReq {
+                            seq: *seq,
+                            target: probe.target,
+                            addr: probe.addr,
+                        },
+                        *addr,
+                    ));
+                    probe.helpers.push(**helper);
+                }
+                probe.indirect = true;
+            } else if probe.indirect && elapsed >= config.protocol_period {
+                expired.push(*seq);
+            }
+        }
+
+        for seq in expired {
+            if let Some(probe) = self.probes.remove(&seq) {
+                if let Some((ServerStatus::Alive, incarnation)) = view.status(&probe.target) {
+                    self.suspects.insert(probe.target, now);
+                    actions.push(Action::Disseminate(ViewUpdate::ServerSuspect {
+                        uuid: probe.target,
+                        incarnation,
+                    }));
+                }
+            }
+        }
+    }
+
+    fn check_suspects(&mut self, view: &ServerView, now: Instant, actions: &mut Vec<Action>) {
+        let timeout = self.config.suspect_timeout;
+        self.suspects.retain(|uuid, since| match view.status(uuid) {
+            Some((ServerStatus::Suspect, incarnation)) => {
+                if now.duration_since(*since) >= timeout {
+                    actions.push(Action::Disseminate(ViewUpdate::ServerDead {
+                        uuid: *uuid,
+                        incarnation,
+                    }));
+                    false
+                } else {
+                    true
+                }
+            }
+            // Keep the suspicion until it has been applied to the view.
+            Some((ServerStatus::Alive, _)) => now.duration_since(*since) < timeout,
+            _ => false,
+        });
+    }
+
+    /// Handle gossip received from another agent.
+    ///
+    /// # Parameters
+    ///
+    /// * `gossip` - The received gossip.
+    ///
+    /// * `sender` - The agent that sent the gossip. Acknowledgements
+    ///   are only accepted from the probed member, or from the members
+    ///   asked to probe it indirectly.
+    ///
+    /// * `peer` - The address that the gossip arrived from.
+    ///
+    /// * `view` - The current view, which is updated when a member is
+    ///   heard from.
+    ///
+    pub fn handle(
+        &mut self,
+        gossip: &Gossip,
+        sender: &Uuid,
+        peer: &SocketAddr,
+        view: &mut ServerView,
+        now: Instant,
+    ) -> Vec<Action> {
+        let mut actions = Vec::new();
+        match gossip {
+            Gossip::Ping { seq } => actions.push(Action::Send(Gossip::Ack { seq: *seq }, *peer)),
+
+            Gossip::PingReq { seq, target, addr } => {
+                let relay_seq = self.next_seq();
+                debug!(
+                    "Probing server {} at {} on behalf of {} (seq {})",
+                    target, addr, peer, relay_seq
+                );
+                self.relays.insert(
+                    relay_seq,
+                    Relay {
+                        requester: *peer,
+                        target: *target,
+                        seq: *seq,
+                        started: now,
+                    },
+                );
+                actions.push(Action::Send(Gossip::Ping { seq: relay_seq }, *addr));
+            }
+
+            Gossip::Ack { seq } => {
+                let relayed = self
+                    .relays
+                    .get(seq)
+                    .is_some_and(|relay| relay.target == *sender);
+                let acknowledged = self
+                    .probes
+                    .get(seq)
+                    .is_some_and(|probe| probe.target == *sender || probe.helpers.contains(sender));
+                if relayed {
+                    if let Some(relay) = self.relays.remove(seq) {
+                        actions.push(Action::Send(
+                            Gossip::Ack { seq: relay.seq },
+                            relay.requester,
+                        ));
+                    }
+                } else if acknowledged {
+                    if let Some(probe) = self.probes.remove(seq) {
+                        debug!("Server {} acknowledged probe (seq {})", probe.target, seq);
+                        self.suspects.remove(&probe.target);
+                        view.touch(&probe.target);
+                    }
+                } else {
+                    debug!("Ignoring acknowledgement from {} (seq {})", sender, seq);
+                }
+            }
+
+            Gossip::ViewGossip(ViewUpdate::ServerSuspect { uuid, incarnation })
+            | Gossip::ViewGossip(ViewUpdate::ServerDead { uuid, incarnation })
+                if *uuid == self.uuid && *incarnation >= self.incarnation =>
+            {
+                self.incarnation = incarnation + 1;
+                info!(
+                    "Refuting suspicion about this agent (incarnation {})",
+                    self.incarnation
+                );
+                actions.push(Action::Disseminate(ViewUpdate::ServerAlive {
+                    uuid: self.uuid,
+                    incarnation: self.incarnation,
+                }));
+            }
+
+            _ => (),
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u64 = 42;
+
+    fn setup() -> (FailureDetector<StdRng>, ServerView, Uuid, SocketAddr) {
+        let uuid = Uuid::new_v4();
+        let detector =
+            FailureDetector::with_rng(uuid, DetectorConfig::default(), StdRng::seed_from_u64(SEED));
+        let mut view = ServerView::new();
+        let member = Uuid::new_v4();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9001));
+        view.update(&ViewUpdate::ServerAdded { uuid: member, addr }, &member, 0);
+        (detector, view, member, addr)
+    }
+
+    fn apply(view: &mut ServerView, actions: &[Action]) {
+        for action in actions {
+            if let Action::Disseminate(update) = action {
+                view.update(update, &Uuid::nil(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn acknowledged_probe_is_not_suspected() {
+        let (mut detector, mut view, member, addr) = setup();
+        let start = Instant::now();
+        let actions = detector.tick(&view, start);
+        let seq = match actions.as_slice() {
+            [Action::Send(Gossip::Ping { seq }, to)] if *to == addr => *seq,
+            other => panic!("expected a ping, got {:?}", other),
+        };
+        detector.handle(&Gossip::Ack { seq }, &member, &addr, &mut view, start);
+        let config = detector.config().clone();
+        let actions = detector.tick(&view, start + config.ping_timeout + config.protocol_period);
+        assert!(actions
+            .iter()
+            .all(|action| !matches!(action, Action::Disseminate(_))));
+    }
+
+    #[test]
+    fn acknowledgement_from_other_member_is_ignored() {
+        let (mut detector, mut view, member, addr) = setup();
+        let config = detector.config().clone();
+        let start = Instant::now();
+        let seq = match detector.tick(&view, start).as_slice() {
+            [Action::Send(Gossip::Ping { seq }, _)] => *seq,
+            other => panic!("expected a ping, got {:?}", other),
+        };
+        let impostor = Uuid::new_v4();
+        detector.handle(&Gossip::Ack { seq }, &impostor, &addr, &mut view, start);
+        detector.tick(&view, start + config.ping_timeout);
+        let actions = detector.tick(&view, start + config.ping_timeout + config.protocol_period);
+        assert!(actions.iter().any(|action| matches!(
+            action,
+            Action::Disseminate(ViewUpdate::ServerSuspect { uuid, .. }) if *uuid == member
+        )));
+    }
+
+    #[test]
+    fn indirect_acknowledgement_from_helper_is_accepted() {
+        let (mut detector, mut view, member, _) = setup();
+        let helper = Uuid::new_v4();
+        let helper_addr = SocketAddr::from(([127, 0, 0, 1], 9002));
+        view.update(
+            &ViewUpdate::ServerAdded {
+                uuid: helper,
+                addr: helper_addr,
+            },
+            &helper,
+            0,
+        );
+        let config = detector.config().clone();
+        let start = Instant::now();
+
+        // Probe until the member is picked rather than the helper.
+        let mut now = start;
+        let seq = loop {
+            let actions = detector.tick(&view, now);
+            match actions.as_slice() {
+                [Action::Send(Gossip::Ping { seq }, to)] if *to != helper_addr => break *seq,
+                [Action::Send(Gossip::Ping { seq }, _)] => {
+                    let ack = Gossip::Ack { seq: *seq };
+                    detector.handle(&ack, &helper, &helper_addr, &mut view, now);
+                }
+                _ => (),
+            }
+            now += config.protocol_period;
+        };
+        let actions = detector.tick(&view, now + config.ping_timeout);
+        assert!(actions.iter().any(|action| matches!(
+            action,
+            Action::Send(Gossip::PingReq { target, .. }, to) if *target == member && *to == helper_addr
+        )));
+        detector.handle(&Gossip::Ack { seq }, &helper, &helper_addr, &mut view, now);
+        let actions = detector.tick(&view, now + config.ping_timeout + config.protocol_period);
+        assert!(actions
+            .iter()
+            .all(|action| !matches!(action, Action::Disseminate(_))));
+    }
+
+    #[test]
+    fn relayed_acknowledgement_must_come_from_target() {
+        let (mut detector, mut view, member, addr) = setup();
+        let requester = SocketAddr::from(([127, 0, 0, 1], 9003));
+        let now = Instant::now();
+        let actions = detector.handle(
+            &Gossip::PingReq {
+                seq: 7,
+                target: member,
+                addr,
+            },
+            &Uuid::new_v4(),
+            &requester,
+            &mut view,
+            now,
+        );
+        let seq = match actions.as_slice() {
+            [Action::Send(Gossip::Ping { seq }, to)] if *to == addr => *seq,
+            other => panic!("expected a ping, got {:?}", other),
+        };
+        let forged = detector.handle(&Gossip::Ack { seq }, &Uuid::new_v4(), &addr, &mut view, now);
+        assert!(forged.is_empty());
+        let relayed = detector.handle(&Gossip::Ack { seq }, &member, &addr, &mut view, now);
+        assert!(matches!(
+            relayed.as_slice(),
+            [Action::Send(Gossip::Ack { seq: 7 }, to)] if *to == requester
+        ));
+    }
+
+    #[test]
+    fn unacknowledged_member_is_suspected_then_dead() {
+        let (mut detector, mut view, member, _) = setup();
+        let config = detector.config().clone();
+        let start = Instant::now();
+        detector.tick(&view, start);
+
+        // No helpers are available, so the indirect probe sends nothing.
+        let indirect = start + config.ping_timeout;
+        assert!(detector.tick(&view, indirect).is_empty());
+
+        let suspected = indirect + config.protocol_period;
+        let actions = detector.tick(&view, suspected);
+        assert!(actions.iter().any(|action| matches!(
+            action,
+            Action::Disseminate(ViewUpdate::ServerSuspect { uuid, incarnation: 0 }) if *uuid == member
+        )));
+        apply(&mut view, &actions);
+        assert_eq!(view.status(&member), Some((ServerStatus::Suspect, 0)));
+
+        let actions = detector.tick(&view, suspected + config.suspect_timeout);
+        assert!(actions.iter().any(|action| matches!(
+            action,
+            Action::Disseminate(ViewUpdate::ServerDead { uuid, incarnation: 0 }) if *uuid == member
+        )));
+        apply(&mut view, &actions);
+        assert_eq!(view.status(&member), Some((ServerStatus::Dead, 0)));
+    }
+
+    #[test]
+    fn refuted_suspicion_is_not_declared_dead() {
+        let (mut detector, mut view, member, _) = setup();
+        let config = detector.config().clone();
+        let start = Instant::now();
+        detector.tick(&view, start);
+        detector.tick(&view, start + config.ping_timeout);
+        let suspected = start + config.ping_timeout + config.protocol_period;
+        let actions = detector.tick(&view, suspected);
+        apply(&mut view, &actions);
+
+        view.update(
+            &ViewUpdate::ServerAlive {
+                uuid: member,
+                incarnation: 1,
+            },
+            &member,
+            0,
+        );
+        let actions = detector.tick(&view, suspected + config.suspect_timeout);
+        assert!(actions
+            .iter()
+            .all(|action| !matches!(action, Action::Disseminate(ViewUpdate::ServerDead { .. }))));
+        assert_eq!(view.status(&member), Some((ServerStatus::Alive, 1)));
+    }
+
+    #[test]
+    fn suspicion_about_self_is_refuted() {
+        let (mut detector, mut view, _, addr) = setup();
+        let uuid = detector.uuid;
+        let actions = detector.handle(
+            &Gossip::ViewGossip(ViewUpdate::ServerSuspect {
+                uuid,
+                incarnation: 0,
+            }),
+            &Uuid::new_v4(),
+            &addr,
+            &mut view,
+            Instant::now(),
+        );
+        assert_eq!(detector.incarnation(), 1);
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Disseminate(ViewUpdate::ServerAlive {
+                incarnation: 1,
+                ..
+            })]
+        ));
+    }
+}
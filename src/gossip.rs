@@ -12,20 +12,87 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
+use crate::alert::AlertUpdate;
+use crate::auth::{AuthKeys, TAG_SIZE};
+use crate::crypto::{ClusterKey, EncryptionMode};
 use crate::devices::DeviceUpdate;
+use crate::error::Error;
+use crate::notify::Transition;
 use crate::state::State;
-use crate::view::ViewUpdate;
+use crate::sync::{Delta, Digest};
+use crate::view::{PeerSelector, RandomFanout, ServerSnapshot, ServerView, ViewUpdate};
+use crate::wire::{
+    check_version, header, Envelope, Kind, ALERTS_PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION,
+    NOTIFICATIONS_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use bytes::BytesMut;
+use chrono::Utc;
+use futures::sync::mpsc::UnboundedSender;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend};
+use rand::thread_rng;
 use serde_cbor::{from_slice, to_vec};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use tokio::codec::{Decoder, Encoder};
+use tokio::net::UdpSocket;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Gossip {
-    DebugMessage { text: String },
+    DebugMessage {
+        text: String,
+    },
     DeviceGossip(DeviceUpdate),
     ViewGossip(ViewUpdate),
+
+    /// Probe sent by the failure detector. The receiver replies with
+    /// an acknowledgement carrying the same sequence number.
+    Ping {
+        seq: u64,
+    },
+
+    /// Request to probe a server on behalf of the sender.
+    PingReq {
+        seq: u64,
+        target: Uuid,
+        addr: SocketAddr,
+    },
+
+    /// Acknowledgement of a probe.
+    Ack {
+        seq: u64,
+    },
+
+    /// Request to synchronize state with the digest of the sender.
+    SyncRequest(Digest),
+
+    /// Entries that are newer than in the digest of the receiver,
+    /// optionally with the digest of the sender to request entries
+    /// back.
+    SyncResponse {
+        delta: Delta,
+        digest: Option<Digest>,
+    },
+
+    /// Request to join the cluster, sent to seed servers.
+    JoinRequest {
+        uuid: Uuid,
+    },
+
+    /// Reply to a join request with the members of the cluster and the
+    /// address that the request arrived from.
+    JoinReply {
+        addr: SocketAddr,
+        members: Vec<ServerSnapshot>,
+    },
+
+    /// Alert that started firing or was resolved.
+    AlertGossip(AlertUpdate),
+
+    /// Notification of a transition of an alert that was delivered by
+    /// the sender, so other agents do not need to send it.
+    NotificationDelivered(Transition),
 }
 
 impl Gossip {
@@ -46,12 +113,58 @@ impl Gossip {
             Gossip::ViewGossip(view_gossip) => {
                 state.update_view(view_gossip, sender, timestamp_millis)
             }
+
+            Gossip::AlertGossip(alert) => state.update_alerts(alert, sender),
+
+            // Probes are handled by the failure detector,
+            // synchronization by the anti-entropy, and joins by the
+            // joiner, so they do not change the state here. Deliveries
+            // are handled by the notifier.
+            Gossip::Ping { .. }
+            | Gossip::PingReq { .. }
+            | Gossip::Ack { .. }
+            | Gossip::SyncRequest(_)
+            | Gossip::SyncResponse { .. }
+            | Gossip::JoinRequest { .. }
+            | Gossip::JoinReply { .. }
+            | Gossip::NotificationDelivered(_) => (),
+        }
+    }
+
+    /// Get the gossip in a form that agents speaking an older protocol
+    /// version can decode. Returns `None` if they cannot decode it at
+    /// all.
+    ///
+    /// Agents from before the envelope only know debug messages,
+    /// device updates, and servers being added or removed. Agents from
+    /// before alerts ignore the alerts in deltas, but cannot decode
+    /// alert gossip. Agents from before deliveries of notifications
+    /// cannot decode them, but fail over without them.
+    pub fn downgrade(self, version: u8) -> Option<Gossip> {
+        match self {
+            Gossip::DeviceGossip(update) => update.downgrade(version).map(Gossip::DeviceGossip),
+            Gossip::ViewGossip(ViewUpdate::ServerAdded { .. })
+            | Gossip::ViewGossip(ViewUpdate::ServerRemoved { .. })
+            | Gossip::DebugMessage { .. } => Some(self),
+            _ if version == LEGACY_PROTOCOL_VERSION => None,
+            Gossip::AlertGossip(_) if version < ALERTS_PROTOCOL_VERSION => None,
+            Gossip::NotificationDelivered(_) if version < NOTIFICATIONS_PROTOCOL_VERSION => None,
+            Gossip::SyncResponse { delta, digest } => Some(Gossip::SyncResponse {
+                delta: delta.downgrade(version),
+                digest,
+            }),
+            gossip => Some(gossip),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
+    /// Unique identifier of the message, used to detect duplicates.
+    /// Agents from before the envelope do not send it, so their
+    /// messages are given a fresh identifier.
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
     pub sender: Uuid,
     pub timestamp_millis: i64,
     pub hops: u32,
@@ -59,37 +172,1024 @@ pub struct Message {
 }
 
 impl Message {
+    /// Construct a new message with a fresh message identifier.
+    pub fn new(sender: Uuid, hops: u32, payload: Option<Gossip>) -> Message {
+        Message {
+            id: Uuid::new_v4(),
+            sender,
+            timestamp_millis: Utc::now().timestamp_millis(),
+            hops,
+            payload,
+        }
+    }
+
     pub fn update_state(&self, state: &mut State, peer: &SocketAddr) {
         if let Some(ref gossip) = self.payload {
             gossip.update_state(state, &self.sender, self.timestamp_millis, peer)
         }
     }
+
+    /// Get the message in a form that agents speaking an older
+    /// protocol version can decode, see `Gossip::downgrade`.
+    pub fn downgrade(mut self, version: u8) -> Option<Message> {
+        if let Some(gossip) = self.payload.take() {
+            self.payload = Some(gossip.downgrade(version)?);
+        }
+        Some(self)
+    }
+}
+
+/// Action requested by a protocol handler, such as the failure
+/// detector.
+///
+/// Protocol handlers do not do any I/O themselves. Instead, they
+/// return actions that the caller should perform.
+#[derive(Debug)]
+pub enum Action {
+    /// Send gossip directly to a server.
+    Send(Gossip, SocketAddr),
+
+    /// Apply a view update locally and disseminate it to the cluster.
+    Disseminate(ViewUpdate),
+
+    /// Apply an update of a device owned by this agent locally and
+    /// disseminate it to the cluster.
+    Publish(DeviceUpdate),
+
+    /// Apply a transition of an alert of this agent locally and
+    /// disseminate it to the cluster.
+    Alert(AlertUpdate),
+}
+
+/// Number of hops that gossip originating from this agent travel.
+pub const DEFAULT_HOPS: u32 = 5;
+
+/// Outgoing queue of messages.
+///
+/// All messages that the agent sends are put in the outbox, which is
+/// then drained by a single task writing to the socket. This allows
+/// several independent tasks to send gossip.
+#[derive(Clone)]
+pub struct Outbox {
+    uuid: Uuid,
+    fanout: Arc<AtomicUsize>,
+    hops: Arc<AtomicU32>,
+    queue: UnboundedSender<(Message, SocketAddr)>,
+}
+
+impl Outbox {
+    /// Construct a new outbox.
+    ///
+    /// # Parameters
+    ///
+    /// * `uuid` - The UUID of this agent, used as sender for all
+    ///   messages originating from the agent.
+    ///
+    /// * `fanout` - Number of servers to send each message to.
+    ///
+    /// * `queue` - Queue of messages to write to the socket.
+    ///
+    pub fn new(uuid: Uuid, fanout: usize, queue: UnboundedSender<(Message, SocketAddr)>) -> Outbox {
+        Outbox {
+            uuid,
+            fanout: Arc::new(AtomicUsize::new(fanout)),
+            hops: Arc::new(AtomicU32::new(DEFAULT_HOPS)),
+            queue,
+        }
+    }
+
+    /// Set the number of hops that gossip originating from this agent
+    /// travel.
+    pub fn with_hops(self, hops: u32) -> Outbox {
+        self.set_hops(hops);
+        self
+    }
+
+    /// Change the number of servers to send each message to. The
+    /// change applies to all clones of the outbox.
+    pub fn set_fanout(&self, fanout: usize) {
+        self.fanout.store(fanout, Ordering::Relaxed);
+    }
+
+    /// Change the number of hops that gossip originating from this
+    /// agent travel. The change applies to all clones of the outbox.
+    pub fn set_hops(&self, hops: u32) {
+        self.hops.store(hops, Ordering::Relaxed);
+    }
+
+    /// The UUID of this agent.
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
+    /// Send gossip directly to a single server without forwarding.
+    pub fn send(&self, gossip: Gossip, addr: SocketAddr) {
+        self.enqueue(Message::new(self.uuid, 0, Some(gossip)), addr);
+    }
+
+    /// Disseminate gossip originating from this agent to the cluster.
+    pub fn broadcast(&self, gossip: Gossip, view: &ServerView) {
+        let msg = Message::new(self.uuid, self.hops.load(Ordering::Relaxed), Some(gossip));
+        for peer in RandomFanout::with_rng(self.fanout.load(Ordering::Relaxed), thread_rng())
+            .select(view, &[self.uuid], None)
+        {
+            self.enqueue(msg.clone(), peer);
+        }
+    }
+
+    /// Send gossip originating from this agent to all active members
+    /// of the cluster.
+    ///
+    /// This is used for gossip that should reach the cluster even if
+    /// the agent is not around to resend it, such as when leaving.
+    pub fn announce(&self, gossip: Gossip, view: &ServerView) {
+        let msg = Message::new(self.uuid, self.hops.load(Ordering::Relaxed), Some(gossip));
+        for (_, info) in view
+            .servers
+            .iter()
+            .filter(|(uuid, info)| **uuid != self.uuid && info.status.is_active())
+        {
+            self.enqueue(msg.clone(), info.address);
+        }
+    }
+
+    /// Forward a message received from `peer` to other servers in
+    /// the cluster and return the number of servers it was sent to.
+    pub fn forward(&self, msg: &Message, peer: &SocketAddr, view: &ServerView) -> usize {
+        let addrs = RandomFanout::with_rng(self.fanout.load(Ordering::Relaxed), thread_rng())
+            .select(view, &[msg.sender, self.uuid], Some(peer));
+        let count = addrs.len();
+        for addr in addrs {
+            self.enqueue(msg.clone(), addr);
+        }
+        count
+    }
+
+    fn enqueue(&self, msg: Message, addr: SocketAddr) {
+        if let Err(err) = self.queue.unbounded_send((msg, addr)) {
+            warn!("Unable to send message to {}: {}", addr, err);
+        }
+    }
+}
+
+/// Maximum number of hops accepted in a received message. Messages
+/// with more hops are rejected, so that a misbehaving sender cannot
+/// make gossip circulate indefinitely.
+pub const MAX_HOPS: u32 = 16;
+
+/// Maximum size of a message, which is the largest payload that fits
+/// in a UDP datagram.
+pub const MAX_MESSAGE_SIZE: usize = 65_507;
+
+/// Keys and encryption mode used by a codec.
+///
+/// The settings are shared with the codec, so that keys can be
+/// rotated and the encryption mode changed while the codec is in use.
+pub struct Security {
+    /// Keys used to authenticate messages, if any.
+    pub keys: Option<AuthKeys>,
+
+    /// How datagrams are encrypted.
+    pub mode: EncryptionMode,
+
+    /// Key used to encrypt and decrypt datagrams, which is required
+    /// unless the encryption mode is `none`.
+    pub cluster_key: Option<ClusterKey>,
 }
 
-pub struct GossipCodec;
+/// Security settings shared between a codec and its owner.
+pub type SharedSecurity = Arc<RwLock<Security>>;
+
+impl Security {
+    /// Construct settings without authentication and encryption.
+    pub fn new() -> Security {
+        Security {
+            keys: None,
+            mode: EncryptionMode::None,
+            cluster_key: None,
+        }
+    }
+
+    /// Check that the settings can be used with a protocol version.
+    ///
+    /// Messages in the legacy protocol version can neither be
+    /// authenticated nor encrypted.
+    pub fn validate(&self, version: u8) -> Result<(), Error> {
+        if self.mode != EncryptionMode::None && self.cluster_key.is_none() {
+            return Err(Error::KeyError(format!(
+                "encryption mode {} requires a cluster key",
+                self.mode
+            )));
+        }
+        let feature = if self.keys.is_some() {
+            "authentication"
+        } else if self.mode != EncryptionMode::None {
+            "encryption"
+        } else {
+            return Ok(());
+        };
+        if version == LEGACY_PROTOCOL_VERSION {
+            Err(Error::ProtocolError(format!(
+                "protocol version {} does not support {}",
+                version, feature
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Decrypt the payload of an encrypted envelope.
+    fn decrypt(&self, envelope: &Envelope) -> Result<Vec<u8>, Error> {
+        match self.cluster_key {
+            Some(ref key) => {
+                key.decrypt(&header(envelope.version, envelope.kind), envelope.payload)
+            }
+            None => Err(Error::AuthenticationError(
+                "datagram is encrypted, but no cluster key is configured".to_string(),
+            )),
+        }
+    }
+
+    /// Get the serialized message from the payload of an envelope,
+    /// authenticating it if necessary.
+    fn open<'a>(&self, envelope: &Envelope<'a>) -> Result<&'a [u8], Error> {
+        match (envelope.kind, &self.keys) {
+            (Kind::Message, None) => Ok(envelope.payload),
+            (Kind::Message, Some(_)) => Err(Error::AuthenticationError(
+                "message is not authenticated".to_string(),
+            )),
+            (Kind::Authenticated, Some(keys)) => {
+                if envelope.payload.len() < TAG_SIZE {
+                    return Err(Error::ProtocolError(
+                        "truncated authentication tag".to_string(),
+                    ));
+                }
+                let (tag, bytes) = envelope.payload.split_at(TAG_SIZE);
+                keys.verify(&[&header(envelope.version, envelope.kind), bytes], tag)?;
+                Ok(bytes)
+            }
+            (Kind::Authenticated, None) => Err(Error::AuthenticationError(
+                "message is authenticated, but no key is configured".to_string(),
+            )),
+            (Kind::Encrypted, _) => Err(Error::ProtocolError(
+                "unexpected encrypted envelope".to_string(),
+            )),
+        }
+    }
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Codec for messages sent over UDP.
+///
+/// Messages are wrapped in an envelope, see the `wire` module, using
+/// the protocol version of the codec. Messages in any protocol
+/// version that the agent understands are decoded.
+///
+/// If the codec has authentication keys, all outgoing messages are
+/// authenticated and incoming messages that cannot be authenticated
+/// are rejected, see the `auth` module.
+///
+/// If the codec has a cluster key, datagrams are encrypted and
+/// decrypted according to the encryption mode, see the `crypto`
+/// module.
+///
+/// Neither encoding nor decoding panics on bad input. Instead, an
+/// error is returned so that the caller can drop the datagram and
+/// continue.
+///
+/// Clones of the codec share the security settings.
+#[derive(Clone)]
+pub struct GossipCodec {
+    version: u8,
+    security: SharedSecurity,
+}
 
 impl GossipCodec {
+    /// Construct a codec sending messages using the current protocol
+    /// version.
     pub fn new() -> GossipCodec {
-        GossipCodec {}
+        GossipCodec {
+            version: PROTOCOL_VERSION,
+            security: Arc::new(RwLock::new(Security::new())),
+        }
+    }
+
+    /// Construct a codec sending messages using an older protocol
+    /// version.
+    ///
+    /// This is used while upgrading a cluster, so that agents that
+    /// are not yet upgraded understand the messages sent.
+    pub fn with_version(version: u8) -> Result<GossipCodec, Error> {
+        check_version(version)?;
+        Ok(GossipCodec {
+            version,
+            ..GossipCodec::new()
+        })
+    }
+
+    /// Authenticate messages using the keys.
+    ///
+    /// Messages in the legacy protocol version cannot be
+    /// authenticated, so it is an error to use keys with it.
+    pub fn with_keys(self, keys: AuthKeys) -> Result<GossipCodec, Error> {
+        self.update(|security| security.keys = Some(keys))?;
+        Ok(self)
+    }
+
+    /// Encrypt and decrypt datagrams using the cluster key.
+    ///
+    /// Datagrams in the legacy protocol version cannot be encrypted,
+    /// so it is an error to use encryption with it.
+    pub fn with_encryption(
+        self,
+        mode: EncryptionMode,
+        cluster_key: ClusterKey,
+    ) -> Result<GossipCodec, Error> {
+        self.update(|security| {
+            security.mode = mode;
+            security.cluster_key = Some(cluster_key);
+        })?;
+        Ok(self)
+    }
+
+    /// Use the security settings, which have to be valid for the
+    /// protocol version of the codec.
+    pub fn with_security(self, security: Security) -> Result<GossipCodec, Error> {
+        self.update(|current| *current = security)?;
+        Ok(self)
+    }
+
+    /// Change the security settings and check that they can be used
+    /// with the protocol version of the codec.
+    fn update<F>(&self, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Security),
+    {
+        let mut security = self
+            .security
+            .write()
+            .expect("unable to lock security settings");
+        change(&mut security);
+        security.validate(self.version)
+    }
+
+    /// Protocol version used when sending messages.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Security settings of the codec, which can be replaced while
+    /// the codec is in use.
+    pub fn security(&self) -> SharedSecurity {
+        self.security.clone()
+    }
+
+    /// Check if messages are authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        self.read_security().keys.is_some()
+    }
+
+    /// Encryption mode of the codec.
+    pub fn encryption_mode(&self) -> EncryptionMode {
+        self.read_security().mode
+    }
+
+    fn read_security(&self) -> RwLockReadGuard<'_, Security> {
+        self.security
+            .read()
+            .expect("unable to lock security settings")
+    }
+}
+
+impl Default for GossipCodec {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Encoder for GossipCodec {
     type Item = Message;
-    type Error = std::io::Error;
+    type Error = Error;
 
-    fn encode(&mut self, item: Self::Item, buf: &mut BytesMut) -> std::io::Result<()> {
-        let bytes = to_vec(&item).unwrap();
-        buf.extend_from_slice(&bytes);
+    fn encode(&mut self, item: Self::Item, buf: &mut BytesMut) -> Result<(), Error> {
+        let id = item.id;
+        let item = item.downgrade(self.version).ok_or_else(|| {
+            Error::ProtocolError(format!(
+                "message {} cannot be sent using protocol version {}",
+                id, self.version
+            ))
+        })?;
+        let security = self.read_security();
+        let bytes = to_vec(&item)?;
+        let (kind, payload) = match security.keys {
+            Some(ref keys) => {
+                let kind = Kind::Authenticated;
+                let tag = keys.sign(&[&header(self.version, kind), &bytes]);
+                let mut payload = Vec::with_capacity(TAG_SIZE + bytes.len());
+                payload.extend_from_slice(&tag);
+                payload.extend_from_slice(&bytes);
+                (kind, payload)
+            }
+            None => (Kind::Message, bytes),
+        };
+        let (kind, payload) = match security.cluster_key {
+            Some(ref key) if security.mode.encrypts() => {
+                let inner = Envelope::new(self.version, kind, &payload).write_inner();
+                let kind = Kind::Encrypted;
+                let payload = key.encrypt(&header(self.version, kind), &inner)?;
+                (kind, payload)
+            }
+            _ => (kind, payload),
+        };
+        let envelope = Envelope::new(self.version, kind, &payload);
+        if envelope.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::ProtocolError(format!(
+                "message {} is {} bytes, which exceeds the maximum of {} bytes",
+                item.id,
+                envelope.len(),
+                MAX_MESSAGE_SIZE
+            )));
+        }
+        envelope.write(buf);
         Ok(())
     }
 }
 
 impl Decoder for GossipCodec {
     type Item = Message;
-    type Error = std::io::Error;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
+        if buf.is_empty() {
+            return Err(Error::ProtocolError("empty datagram".to_string()));
+        }
+        let security = self.read_security();
+        let envelope = Envelope::parse(buf)?;
+        let plaintext;
+        let envelope = if envelope.kind == Kind::Encrypted {
+            plaintext = security.decrypt(&envelope)?;
+            Envelope::parse_inner(envelope.version, &plaintext)?
+        } else if security.mode.accepts_plaintext() {
+            envelope
+        } else {
+            return Err(Error::AuthenticationError(
+                "datagram is not encrypted".to_string(),
+            ));
+        };
+        let msg: Message = from_slice(security.open(&envelope)?)?;
+        if msg.hops > MAX_HOPS {
+            return Err(Error::ProtocolError(format!(
+                "message {} has {} hops, which exceeds the maximum of {}",
+                msg.id, msg.hops, MAX_HOPS
+            )));
+        }
+        Ok(Some(msg))
+    }
+}
+
+/// Sink sending each message in a datagram of its own.
+///
+/// A message that cannot be encoded or sent, for example because the
+/// destination is unreachable, is logged and dropped. This does not
+/// affect the messages sent after it, so the sink never fails.
+pub struct DatagramSink {
+    socket: UdpSocket,
+    codec: GossipCodec,
+    buffer: BytesMut,
+
+    /// Destination of the datagram in the buffer, if it is not sent
+    /// yet.
+    pending: Option<SocketAddr>,
+}
+
+impl DatagramSink {
+    pub fn new(socket: UdpSocket, codec: GossipCodec) -> DatagramSink {
+        DatagramSink {
+            socket,
+            codec,
+            buffer: BytesMut::new(),
+            pending: None,
+        }
+    }
+}
+
+impl Sink for DatagramSink {
+    type SinkItem = (Message, SocketAddr);
+    type SinkError = std::io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.poll_complete()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let (msg, addr) = item;
+        // Gossip that agents speaking the protocol version of the codec
+        // cannot decode is expected and not worth a warning.
+        let id = msg.id;
+        let msg = match msg.downgrade(self.codec.version()) {
+            Some(msg) => msg,
+            None => {
+                debug!(
+                    "Not sending message {} using protocol version {}",
+                    id,
+                    self.codec.version()
+                );
+                return Ok(AsyncSink::Ready);
+            }
+        };
+        self.buffer.clear();
+        match self.codec.encode(msg, &mut self.buffer) {
+            Ok(()) => self.pending = Some(addr),
+            Err(err) => warn!("Dropping outgoing message to {}: {}", addr, err),
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if let Some(addr) = self.pending {
+            match self.socket.poll_send_to(&self.buffer, &addr) {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(_)) => (),
+                Err(err) => warn!("Unable to send message to {}: {}", addr, err),
+            }
+            self.pending = None;
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::AlertState;
+    use crate::clock::Version;
+    use crate::crypto::KEY_SIZE;
+    use crate::devices::Metric;
+    use crate::signing::Signer;
+    use crate::wire::{HEADER_SIZE, MAGIC, TYPED_METRICS_PROTOCOL_VERSION};
+    use futures::stream;
+    use std::collections::HashMap;
+    use std::io;
+    use std::time::Duration;
+    use tokio::reactor::Handle;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn message(text: &str) -> Message {
+        Message::new(
+            Uuid::new_v4(),
+            3,
+            Some(Gossip::DebugMessage {
+                text: text.to_string(),
+            }),
+        )
+    }
+
+    fn encode(codec: &mut GossipCodec, msg: Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec.encode(msg, &mut buf).unwrap();
+        buf
+    }
+
+    fn text(msg: &Message) -> &str {
+        match msg.payload {
+            Some(Gossip::DebugMessage { ref text }) => text,
+            ref other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_encoded_message() {
+        let mut codec = GossipCodec::new();
+        let msg = message("hello");
+        let mut buf = encode(&mut codec, msg.clone());
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.id, msg.id);
+        assert_eq!(text(&decoded), "hello");
+    }
+
+    #[test]
+    fn rejects_malformed_datagrams() {
+        let mut codec = GossipCodec::new();
+        let valid = encode(&mut codec, message("hello"));
+        let datagrams: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"garbage".to_vec(),
+            vec![0xff; 64],
+            MAGIC.to_vec(),
+            valid[..valid.len() / 2].to_vec(),
+            [&header(PROTOCOL_VERSION, Kind::Message)[..], b"garbage"].concat(),
+        ];
+        for datagram in datagrams {
+            let mut buf = BytesMut::from(datagram.clone());
+            assert!(
+                codec.decode(&mut buf).is_err(),
+                "accepted datagram {:?}",
+                datagram
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_hops() {
+        let mut codec = GossipCodec::new();
+        let mut msg = message("hello");
+        msg.hops = MAX_HOPS + 1;
+        let mut buf = encode(&mut codec, msg);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn rejects_too_large_messages() {
+        let mut codec = GossipCodec::new();
+        let mut buf = BytesMut::new();
+        let msg = message(&"x".repeat(MAX_MESSAGE_SIZE));
+        assert!(codec.encode(msg, &mut buf).is_err());
+    }
+
+    fn authenticated(key: &[u8]) -> GossipCodec {
+        let keys = AuthKeys::new(key.to_vec(), None).unwrap();
+        GossipCodec::new().with_keys(keys).unwrap()
+    }
+
+    #[test]
+    fn rejects_unauthenticated_datagrams() {
+        let mut codec = authenticated(b"0123456789abcdef");
+        let mut buf = encode(&mut authenticated(b"0123456789abcdef"), message("hello"));
+        assert!(codec.decode(&mut buf.clone()).unwrap().is_some());
+
+        // Any changed byte after the header invalidates the tag.
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = encode(&mut authenticated(b"0123456789abcdef"), message("hello"));
+        buf[HEADER_SIZE] ^= 1;
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut foreign = encode(&mut authenticated(b"fedcba9876543210"), message("hello"));
+        assert!(codec.decode(&mut foreign).is_err());
+
+        let mut plain = encode(&mut GossipCodec::new(), message("hello"));
+        assert!(codec.decode(&mut plain).is_err());
+
+        let mut truncated = BytesMut::from(&header(PROTOCOL_VERSION, Kind::Authenticated)[..]);
+        truncated.extend_from_slice(&[0; TAG_SIZE - 1]);
+        assert!(codec.decode(&mut truncated).is_err());
+    }
+
+    fn encrypted(mode: EncryptionMode, key: u8) -> GossipCodec {
+        let key = ClusterKey::new(&[key; KEY_SIZE], None).unwrap();
+        GossipCodec::new().with_encryption(mode, key).unwrap()
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
-        Ok(from_slice(&buf).unwrap())
+    #[test]
+    fn rejects_undecryptable_datagrams() {
+        let mut codec = encrypted(EncryptionMode::Required, 1);
+        let mut buf = encode(
+            &mut encrypted(EncryptionMode::Required, 1),
+            message("hello"),
+        );
+        assert!(codec.decode(&mut buf.clone()).unwrap().is_some());
+
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(codec.decode(&mut buf).is_err());
+
+        // The header is authenticated, so the kind cannot be changed.
+        let mut buf = encode(
+            &mut encrypted(EncryptionMode::Required, 1),
+            message("hello"),
+        );
+        buf[HEADER_SIZE - 1] = Kind::Message.as_u8();
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut foreign = encode(
+            &mut encrypted(EncryptionMode::Required, 2),
+            message("hello"),
+        );
+        assert!(codec.decode(&mut foreign).is_err());
+
+        let mut plain = encode(&mut GossipCodec::new(), message("hello"));
+        assert!(codec.decode(&mut plain.clone()).is_err());
+        let mut codec = encrypted(EncryptionMode::Optional, 1);
+        assert!(codec.decode(&mut plain).unwrap().is_some());
+    }
+
+    /// Types of the agents from before the envelope, which decode the
+    /// datagrams sent using the legacy protocol version and encode the
+    /// datagrams that they send.
+    ///
+    /// The types are copied as they were, and the tests only check that
+    /// decoding succeeds, so most fields are never read.
+    #[allow(dead_code, clippy::enum_variant_names)]
+    mod baseline {
+        use std::collections::HashMap;
+        use std::net::SocketAddr;
+        use uuid::Uuid;
+
+        #[derive(Serialize, Deserialize, Debug)]
+        pub enum Metric {
+            Text(String),
+        }
+
+        #[derive(Serialize, Deserialize, Debug)]
+        pub enum DeviceUpdate {
+            DeviceAdded {
+                origin: Uuid,
+                name: String,
+                description: String,
+            },
+            DeviceRemoved {
+                origin: Uuid,
+                name: String,
+            },
+            DeviceStatus {
+                origin: Uuid,
+                name: String,
+                metrics: HashMap<String, Metric>,
+            },
+        }
+
+        #[derive(Serialize, Deserialize, Debug)]
+        pub enum ViewUpdate {
+            ServerAdded { uuid: Uuid, addr: SocketAddr },
+            ServerRemoved { uuid: Uuid },
+        }
+
+        #[derive(Serialize, Deserialize, Debug)]
+        pub enum Gossip {
+            DebugMessage { text: String },
+            DeviceGossip(DeviceUpdate),
+            ViewGossip(ViewUpdate),
+        }
+
+        #[derive(Serialize, Deserialize, Debug)]
+        pub struct Message {
+            pub sender: Uuid,
+            pub timestamp_millis: i64,
+            pub hops: u32,
+            pub payload: Option<Gossip>,
+        }
+    }
+
+    fn gossip(payload: Gossip) -> Message {
+        Message::new(Uuid::new_v4(), 3, Some(payload))
+    }
+
+    fn device_status(metrics: Vec<(&str, Metric)>) -> DeviceUpdate {
+        let signer = Signer::generate(Uuid::new_v4());
+        let mut update = DeviceUpdate::DeviceStatus {
+            origin: *signer.uuid(),
+            name: "disk".to_string(),
+            metrics: metrics
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            version: Version::new(1000, 1),
+            signatures: HashMap::new(),
+        };
+        signer.sign(&mut update);
+        update
+    }
+
+    fn alert() -> AlertUpdate {
+        AlertUpdate {
+            origin: Uuid::new_v4(),
+            rule: "full".to_string(),
+            device: "disk".to_string(),
+            metric: "used".to_string(),
+            state: AlertState::Firing,
+            value: 0.95,
+            version: Version::new(1000, 1),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn baseline_agents_decode_legacy_messages() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let mut added = DeviceUpdate::DeviceAdded {
+            origin: *signer.uuid(),
+            name: "disk".to_string(),
+            description: "Root disk".to_string(),
+            version: Version::new(1000, 1),
+            signature: None,
+        };
+        signer.sign(&mut added);
+        let status = device_status(vec![
+            ("mount", Metric::Text("/".to_string())),
+            ("used", Metric::Float(0.5)),
+        ]);
+        let server = Uuid::new_v4();
+        let messages = vec![
+            message("hello"),
+            gossip(Gossip::DeviceGossip(added)),
+            gossip(Gossip::DeviceGossip(status)),
+            gossip(Gossip::ViewGossip(ViewUpdate::ServerAdded {
+                uuid: server,
+                addr: "127.0.0.1:2428".parse().unwrap(),
+            })),
+            gossip(Gossip::ViewGossip(ViewUpdate::ServerRemoved {
+                uuid: server,
+            })),
+        ];
+
+        let mut codec = GossipCodec::with_version(LEGACY_PROTOCOL_VERSION).unwrap();
+        let mut decoded = Vec::new();
+        for msg in messages {
+            let buf = encode(&mut codec, msg);
+            let msg: baseline::Message = from_slice(&buf).unwrap();
+            decoded.push(msg.payload.unwrap());
+        }
+        match decoded[2] {
+            baseline::Gossip::DeviceGossip(baseline::DeviceUpdate::DeviceStatus {
+                ref metrics,
+                ..
+            }) => {
+                assert_eq!(metrics.len(), 1);
+                assert!(metrics.contains_key("mount"));
+            }
+            ref other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_messages_from_baseline_agents() {
+        let sender = Uuid::new_v4();
+        let payloads = vec![
+            baseline::Gossip::DebugMessage {
+                text: "hello".to_string(),
+            },
+            baseline::Gossip::DeviceGossip(baseline::DeviceUpdate::DeviceAdded {
+                origin: sender,
+                name: "disk".to_string(),
+                description: "Root disk".to_string(),
+            }),
+            baseline::Gossip::DeviceGossip(baseline::DeviceUpdate::DeviceStatus {
+                origin: sender,
+                name: "disk".to_string(),
+                metrics: vec![("mount".to_string(), baseline::Metric::Text("/".to_string()))]
+                    .into_iter()
+                    .collect(),
+            }),
+            baseline::Gossip::ViewGossip(baseline::ViewUpdate::ServerAdded {
+                uuid: sender,
+                addr: "127.0.0.1:2428".parse().unwrap(),
+            }),
+        ];
+
+        let mut codec = GossipCodec::new();
+        let mut ids = Vec::new();
+        for payload in payloads {
+            let msg = baseline::Message {
+                sender,
+                timestamp_millis: 1000,
+                hops: 3,
+                payload: Some(payload),
+            };
+            let mut buf = BytesMut::from(&to_vec(&msg).unwrap()[..]);
+            let decoded = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(decoded.sender, sender);
+            assert_eq!(decoded.hops, 3);
+            ids.push(decoded.id);
+            match decoded.payload {
+                Some(Gossip::DeviceGossip(DeviceUpdate::DeviceStatus { ref metrics, .. })) => {
+                    assert_eq!(metrics["mount"], Metric::Text("/".to_string()))
+                }
+                Some(_) => (),
+                None => panic!("missing payload"),
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 4);
+    }
+
+    #[test]
+    fn legacy_messages_leave_out_newer_gossip() {
+        let server = Uuid::new_v4();
+        let payloads = vec![
+            Gossip::Ping { seq: 1 },
+            Gossip::Ack { seq: 1 },
+            Gossip::ViewGossip(ViewUpdate::ServerSuspect {
+                uuid: server,
+                incarnation: 1,
+            }),
+            Gossip::SyncRequest(Digest::default()),
+            Gossip::JoinRequest { uuid: server },
+            Gossip::AlertGossip(alert()),
+            Gossip::DeviceGossip(device_status(vec![("used", Metric::Float(0.5))])),
+        ];
+        let mut codec = GossipCodec::with_version(LEGACY_PROTOCOL_VERSION).unwrap();
+        for payload in payloads {
+            assert!(payload.clone().downgrade(LEGACY_PROTOCOL_VERSION).is_none());
+            let mut buf = BytesMut::new();
+            assert!(codec.encode(gossip(payload), &mut buf).is_err());
+            assert!(buf.is_empty());
+        }
+        let empty = Message::new(server, 3, None);
+        assert!(empty.downgrade(LEGACY_PROTOCOL_VERSION).is_some());
+    }
+
+    #[test]
+    fn downgrade_removes_typed_metrics_and_alerts() {
+        let status = device_status(vec![
+            ("mount", Metric::Text("/".to_string())),
+            ("used", Metric::Float(0.5)),
+        ]);
+        let version = TYPED_METRICS_PROTOCOL_VERSION - 1;
+        match Gossip::DeviceGossip(status.clone()).downgrade(version) {
+            Some(Gossip::DeviceGossip(DeviceUpdate::DeviceStatus {
+                metrics,
+                signatures,
+                ..
+            })) => {
+                assert_eq!(metrics.keys().collect::<Vec<_>>(), vec!["mount"]);
+                assert_eq!(signatures.keys().collect::<Vec<_>>(), vec!["mount"]);
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+        match Gossip::DeviceGossip(status).downgrade(TYPED_METRICS_PROTOCOL_VERSION) {
+            Some(Gossip::DeviceGossip(DeviceUpdate::DeviceStatus { metrics, .. })) => {
+                assert_eq!(metrics.len(), 2)
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+
+        assert!(Gossip::AlertGossip(alert()).downgrade(version).is_none());
+        assert!(Gossip::AlertGossip(alert())
+            .downgrade(ALERTS_PROTOCOL_VERSION)
+            .is_some());
+
+        let response = Gossip::SyncResponse {
+            delta: Delta {
+                devices: Vec::new(),
+                servers: Vec::new(),
+                alerts: vec![alert()],
+            },
+            digest: None,
+        };
+        match response.downgrade(ALERTS_PROTOCOL_VERSION - 1) {
+            Some(Gossip::SyncResponse { delta, .. }) => assert!(delta.is_empty()),
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn downgrade_removes_notification_deliveries() {
+        let delivered = Gossip::NotificationDelivered(Transition {
+            owner: Uuid::new_v4(),
+            rule: "disk-full".to_string(),
+            device: "disk:/var".to_string(),
+            version: Version::new(1, 0),
+        });
+        assert!(delivered
+            .clone()
+            .downgrade(NOTIFICATIONS_PROTOCOL_VERSION - 1)
+            .is_none());
+        assert!(delivered
+            .downgrade(NOTIFICATIONS_PROTOCOL_VERSION)
+            .is_some());
+    }
+
+    #[test]
+    fn sink_keeps_sending_after_failures() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let target = receiver.local_addr().unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::from_std(socket, &Handle::default()).unwrap();
+        let sink = DatagramSink::new(socket, GossipCodec::new());
+
+        // An IPv4 socket cannot send to an IPv6 address, and the large
+        // message cannot be encoded.
+        let unreachable: SocketAddr = "[::1]:9".parse().unwrap();
+        let messages = vec![
+            (message("lost"), unreachable),
+            (message(&"x".repeat(MAX_MESSAGE_SIZE)), target),
+            (message("first"), target),
+            (message("lost"), unreachable),
+            (message("second"), target),
+        ];
+        let mut runtime = Runtime::new().unwrap();
+        let (_sink, _messages) = runtime
+            .block_on(sink.send_all(stream::iter_ok::<_, io::Error>(messages)))
+            .unwrap();
+
+        let mut codec = GossipCodec::new();
+        for expected in &["first", "second"] {
+            let mut buf = [0; MAX_MESSAGE_SIZE];
+            let (len, _) = receiver.recv_from(&mut buf).unwrap();
+            let msg = codec
+                .decode(&mut BytesMut::from(&buf[..len]))
+                .unwrap()
+                .unwrap();
+            assert_eq!(text(&msg), *expected);
+        }
     }
 }
@@ -0,0 +1,107 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for the persistent identity of an agent.
+//!
+//! Each agent is identified by a UUID that is generated the first
+//! time the agent starts and stored in the data directory of the
+//! agent. Later starts load the same UUID, so devices owned by the
+//! agent and the entry of the agent in the view stay valid across
+//! restarts.
+
+use crate::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Name of the file in the data directory holding the UUID.
+pub const NODE_ID_FILE: &str = "node-id";
+
+/// Load the UUID of the agent from the data directory, or generate
+/// and store a new UUID if there is none.
+pub fn load_or_create(data_dir: &Path) -> Result<Uuid, Error> {
+    let path = data_dir.join(NODE_ID_FILE);
+    if path.exists() {
+        let uuid = Uuid::parse_str(fs::read_to_string(&path)?.trim())?;
+        debug!("Loaded agent UUID {} from {}", uuid, path.display());
+        return Ok(uuid);
+    }
+
+    let uuid = Uuid::new_v4();
+    write_atomically(&path, &format!("{}\n", uuid), 0o644)?;
+    info!("Generated agent UUID {} in {}", uuid, path.display());
+    Ok(uuid)
+}
+
+/// Write a file in the data directory with the given permissions,
+/// creating the directory if necessary.
+///
+/// The contents is written to a temporary file first so that a crash
+/// does not leave a partially written file behind. A temporary file
+/// left behind by an earlier crash is removed first, since the
+/// permissions are only set when the file is created.
+pub(crate) fn write_atomically(path: &Path, contents: &str, mode: u32) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    if let Err(err) = fs::remove_file(&tmp_path) {
+        if err.kind() != ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn leftover_temporary_file_does_not_keep_permissions() {
+        let dir = std::env::temp_dir().join(format!("chatter-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node-key");
+        let tmp_path = dir.join("node-key.tmp");
+        fs::write(&tmp_path, "old contents").unwrap();
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_atomically(&path, "secret", 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret");
+        assert!(!tmp_path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uuid_is_kept_across_loads() {
+        let dir = std::env::temp_dir().join(format!("chatter-test-{}", Uuid::new_v4()));
+        let uuid = load_or_create(&dir).unwrap();
+        assert_eq!(load_or_create(&dir).unwrap(), uuid);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for joining a cluster.
+//!
+//! An agent joins the cluster by sending a join request to a set of
+//! seed servers. A seed replies with the members of the cluster and
+//! the address that the request arrived from. When the agent receives
+//! the first reply, it adds the members to its view and announces
+//! itself to the cluster.
+//!
+//! Join requests are repeated until a reply arrives, so seeds do not
+//! have to be up when the agent starts. Replies are only accepted
+//! from servers that the agent sent a join request to, and only until
+//! the agent has joined, so other servers cannot add members to the
+//! view by sending replies.
+
+use crate::gossip::{Action, Gossip};
+use crate::view::{ServerView, ViewUpdate};
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+pub struct Joiner {
+    uuid: Uuid,
+    local_addr: SocketAddr,
+    seeds: Vec<SocketAddr>,
+
+    /// Addresses that join requests were sent to.
+    contacted: HashSet<SocketAddr>,
+    joined: bool,
+}
+
+impl Joiner {
+    /// Construct a joiner.
+    ///
+    /// # Parameters
+    ///
+    /// * `uuid` - The UUID of this agent.
+    ///
+    /// * `local_addr` - The address this agent listens on.
+    ///
+    /// * `seeds` - Addresses of the seed servers. If there are no
+    ///   seeds, the agent starts a new cluster and is considered
+    ///   joined immediately.
+    ///
+    pub fn new(uuid: Uuid, local_addr: SocketAddr, seeds: Vec<SocketAddr>) -> Joiner {
+        let seeds: Vec<SocketAddr> = seeds
+            .into_iter()
+            .filter(|addr| *addr != local_addr)
+            .collect();
+        Joiner {
+            uuid,
+            local_addr,
+            contacted: seeds.iter().copied().collect(),
+            joined: seeds.is_empty(),
+            seeds,
+        }
+    }
+
+    /// Check if the agent has joined the cluster.
+    pub fn is_joined(&self) -> bool {
+        self.joined
+    }
+
+    /// Get the join requests to send to the seeds.
+    ///
+    /// Should be called periodically until the agent has joined.
+    pub fn tick(&self) -> Vec<Action> {
+        if self.joined {
+            return Vec::new();
+        }
+        self.seeds
+            .iter()
+            .map(|seed| {
+                debug!("Sending join request to seed {}", seed);
+                Action::Send(Gossip::JoinRequest { uuid: self.uuid }, *seed)
+            })
+            .collect()
+    }
+
+    /// Get a join request to send to a server, for example to merge
+    /// two clusters.
+    ///
+    /// The agent announces itself again when the reply arrives, so
+    /// that the members of the other cluster learn about it.
+    pub fn join(&mut self, addr: SocketAddr) -> Vec<Action> {
+        info!("Sending join request to {}", addr);
+        self.joined = false;
+        self.contacted.insert(addr);
+        vec![Action::Send(Gossip::JoinRequest { uuid: self.uuid }, addr)]
+    }
+
+    /// The address that other agents should use to reach this agent.
+    ///
+    /// If the agent listens on all addresses, the address that the
+    /// seed saw the join request arrive from is used instead.
+    fn advertised_addr(&self, observed: &SocketAddr) -> SocketAddr {
+        if self.local_addr.ip().is_unspecified() {
+            SocketAddr::new(observed.ip(), self.local_addr.port())
+        } else {
+            self.local_addr
+        }
+    }
+
+    /// Handle join gossip received from another agent.
+    ///
+    /// # Parameters
+    ///
+    /// * `gossip` - The received gossip.
+    ///
+    /// * `sender` - The UUID of the agent that sent the gossip.
+    ///
+    /// * `peer` - The address that the gossip arrived from.
+    ///
+    /// * `view` - The current view, which is updated with the members
+    ///   of the cluster when a join reply arrives.
+    ///
+    pub fn handle(
+        &mut self,
+        gossip: &Gossip,
+        sender: &Uuid,
+        peer: &SocketAddr,
+        view: &mut ServerView,
+    ) -> Vec<Action> {
+        match gossip {
+            Gossip::JoinRequest { uuid } => {
+                info!("Server {} at {} requests to join", uuid, peer);
+                vec![Action::Send(
+                    Gossip::JoinReply {
+                        addr: *peer,
+                        members: view.delta(&HashMap::new()),
+                    },
+                    *peer,
+                )]
+            }
+
+            Gossip::JoinReply { addr, members } => {
+                if self.joined || !self.contacted.contains(peer) {
+                    warn!("Ignoring unexpected join reply from {}", peer);
+                    return Vec::new();
+                }
+                view.merge(members);
+                if !view.servers.contains_key(sender) {
+                    view.update(
+                        &ViewUpdate::ServerAdded {
+                            uuid: *sender,
+                            addr: *peer,
+                        },
+                        sender,
+                        Utc::now().timestamp_millis(),
+                    );
+                }
+                self.joined = true;
+                self.contacted = self.seeds.iter().copied().collect();
+                let addr = self.advertised_addr(addr);
+                info!("Joined cluster through {}, announcing {}", peer, addr);
+                vec![Action::Disseminate(ViewUpdate::ServerAdded {
+                    uuid: self.uuid,
+                    addr,
+                })]
+            }
+
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::view::{ServerSnapshot, ServerStatus};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn reply(member: Uuid) -> Gossip {
+        Gossip::JoinReply {
+            addr: addr(9000),
+            members: vec![ServerSnapshot {
+                uuid: member,
+                address: addr(9100),
+                status: ServerStatus::Alive,
+                incarnation: 0,
+                version: 1,
+            }],
+        }
+    }
+
+    #[test]
+    fn reply_from_seed_joins_cluster() {
+        let mut joiner = Joiner::new(Uuid::new_v4(), addr(9000), vec![addr(9001)]);
+        let mut view = ServerView::new();
+        let member = Uuid::new_v4();
+        let actions = joiner.handle(&reply(member), &Uuid::new_v4(), &addr(9001), &mut view);
+        assert!(joiner.is_joined());
+        assert!(view.servers.contains_key(&member));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Disseminate(ViewUpdate::ServerAdded { .. })]
+        ));
+    }
+
+    #[test]
+    fn unsolicited_reply_is_ignored() {
+        let mut joiner = Joiner::new(Uuid::new_v4(), addr(9000), vec![addr(9001)]);
+        let mut view = ServerView::new();
+        let member = Uuid::new_v4();
+        let actions = joiner.handle(&reply(member), &Uuid::new_v4(), &addr(9666), &mut view);
+        assert!(actions.is_empty());
+        assert!(!joiner.is_joined());
+        assert!(view.servers.is_empty());
+    }
+
+    #[test]
+    fn reply_after_joining_is_ignored() {
+        let mut joiner = Joiner::new(Uuid::new_v4(), addr(9000), vec![addr(9001)]);
+        let mut view = ServerView::new();
+        joiner.handle(
+            &reply(Uuid::new_v4()),
+            &Uuid::new_v4(),
+            &addr(9001),
+            &mut view,
+        );
+        let count = view.servers.len();
+        let late = Uuid::new_v4();
+        let actions = joiner.handle(&reply(late), &Uuid::new_v4(), &addr(9001), &mut view);
+        assert!(actions.is_empty());
+        assert!(!view.servers.contains_key(&late));
+        assert_eq!(view.servers.len(), count);
+    }
+
+    #[test]
+    fn reply_from_explicitly_joined_server_is_accepted() {
+        let mut joiner = Joiner::new(Uuid::new_v4(), addr(9000), Vec::new());
+        let mut view = ServerView::new();
+        assert!(joiner.is_joined());
+        joiner.join(addr(9002));
+        let member = Uuid::new_v4();
+        joiner.handle(&reply(member), &Uuid::new_v4(), &addr(9002), &mut view);
+        assert!(joiner.is_joined());
+        assert!(view.servers.contains_key(&member));
+    }
+}
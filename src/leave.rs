@@ -0,0 +1,219 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for leaving a cluster.
+//!
+//! An agent that shuts down gracefully removes all devices it owns,
+//! resolves its firing alerts, and announces that it leaves the
+//! cluster. The other agents then mark the agent as left rather than
+//! failed.
+
+use crate::alert::AlertState;
+use crate::clock::SharedClock;
+use crate::devices::DeviceUpdate;
+use crate::gossip::Gossip;
+use crate::signing::Signer;
+use crate::state::State;
+use crate::view::ViewUpdate;
+
+/// Build the gossip announcing that the agent leaves the cluster.
+///
+/// # Parameters
+///
+/// * `signer` - The signer of the agent leaving, which is used to
+///   sign the removal of the devices.
+///
+/// * `clock` - The clock of the agent, which is used to version the
+///   removal of the devices.
+///
+/// * `state` - The state of the agent, which is used to find the
+///   devices and alerts owned by the agent.
+///
+pub fn leave_gossip(signer: &Signer, clock: &SharedClock, state: &State) -> Vec<Gossip> {
+    let uuid = signer.uuid();
+    let devices = state
+        .devices
+        .lock()
+        .expect("unable to lock device collection for leave");
+    let owned = devices.owned_by(uuid);
+
+    // The removals need to be newer than anything the agent has
+    // issued for the devices.
+    let mut clock = clock.lock().expect("unable to lock clock");
+    for info in &owned {
+        clock.observe(&info.version);
+        for entry in info.metrics.values() {
+            clock.observe(&entry.version);
+        }
+    }
+
+    let mut gossip: Vec<Gossip> = owned
+        .iter()
+        .map(|info| {
+            let mut update = DeviceUpdate::DeviceRemoved {
+                origin: *uuid,
+                name: info.name.clone(),
+                version: clock.tick(),
+                signature: None,
+            };
+            signer.sign(&mut update);
+            Gossip::DeviceGossip(update)
+        })
+        .collect();
+
+    let alerts = state
+        .alerts
+        .lock()
+        .expect("unable to lock alerts for leave");
+    for alert in alerts.owned_by(uuid) {
+        if alert.state == AlertState::Firing {
+            clock.observe(&alert.version);
+            let mut update = alert.clone();
+            update.state = AlertState::Resolved;
+            update.version = clock.tick();
+            signer.sign_alert(&mut update);
+            gossip.push(Gossip::AlertGossip(update));
+        }
+    }
+    gossip.push(Gossip::ViewGossip(ViewUpdate::ServerRemoved {
+        uuid: *uuid,
+    }));
+    gossip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::AlertUpdate;
+    use crate::clock::{Clock, Version};
+    use crate::devices::Metric;
+    use chrono::Utc;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use uuid::Uuid;
+
+    fn added(origin: Uuid, name: &str, version: Version) -> DeviceUpdate {
+        DeviceUpdate::DeviceAdded {
+            origin,
+            name: name.to_string(),
+            description: name.to_string(),
+            version,
+            signature: None,
+        }
+    }
+
+    fn alert(origin: Uuid, device: &str, state: AlertState, version: Version) -> AlertUpdate {
+        AlertUpdate {
+            origin,
+            rule: "disk-full".to_string(),
+            device: device.to_string(),
+            metric: "used_percent".to_string(),
+            state,
+            value: 93.5,
+            version,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn leave_removes_owned_devices_and_resolves_firing_alerts() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let uuid = *signer.uuid();
+        let other = Uuid::new_v4();
+        let mut state = State::new();
+
+        // Versions from the future, which the removals have to be
+        // newer than.
+        let future = Utc::now().timestamp_millis() + 3_600_000;
+        let mut metrics = HashMap::new();
+        metrics.insert("used".to_string(), Metric::Integer(10));
+        state.update_devices(&added(uuid, "disk", Version::new(1, 0)), &uuid, 0);
+        state.update_devices(
+            &DeviceUpdate::DeviceStatus {
+                origin: uuid,
+                name: "disk".to_string(),
+                metrics,
+                version: Version::new(future, 4),
+                signatures: HashMap::new(),
+            },
+            &uuid,
+            0,
+        );
+        state.update_devices(&added(uuid, "cpu", Version::new(2, 0)), &uuid, 0);
+        state.update_devices(&added(other, "disk", Version::new(1, 0)), &other, 0);
+        let firing = alert(uuid, "disk", AlertState::Firing, Version::new(future, 7));
+        state.update_alerts(&firing, &uuid);
+        state.update_alerts(
+            &alert(uuid, "cpu", AlertState::Resolved, Version::new(3, 0)),
+            &uuid,
+        );
+        state.update_alerts(
+            &alert(other, "disk", AlertState::Firing, Version::new(3, 0)),
+            &other,
+        );
+
+        let clock = Arc::new(Mutex::new(Clock::new()));
+        let gossip = leave_gossip(&signer, &clock, &state);
+
+        let mut removed: Vec<&str> = gossip
+            .iter()
+            .filter_map(|gossip| match gossip {
+                Gossip::DeviceGossip(DeviceUpdate::DeviceRemoved {
+                    origin,
+                    name,
+                    version,
+                    signature,
+                }) => {
+                    assert_eq!(*origin, uuid);
+                    assert!(*version > Version::new(future, 4));
+                    assert!(signature.is_some());
+                    Some(name.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        removed.sort_unstable();
+        assert_eq!(removed, vec!["cpu", "disk"]);
+
+        let resolved: Vec<&AlertUpdate> = gossip
+            .iter()
+            .filter_map(|gossip| match gossip {
+                Gossip::AlertGossip(update) => Some(update),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].origin, uuid);
+        assert_eq!(resolved[0].device, "disk");
+        assert_eq!(resolved[0].state, AlertState::Resolved);
+        assert!(resolved[0].version > firing.version);
+        assert!(resolved[0].signature.is_some());
+
+        assert!(matches!(
+            gossip.last(),
+            Some(Gossip::ViewGossip(ViewUpdate::ServerRemoved { uuid: removed })) if *removed == uuid
+        ));
+        assert_eq!(gossip.len(), 4);
+
+        // The removals are accepted by an agent that has the state.
+        for update in &gossip {
+            if let Gossip::DeviceGossip(update) = update {
+                state.update_devices(update, &uuid, 0);
+            }
+        }
+        let devices = state.devices.lock().unwrap();
+        assert!(devices.owned_by(&uuid).is_empty());
+        assert_eq!(devices.owned_by(&other).len(), 1);
+    }
+}
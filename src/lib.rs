@@ -30,8 +30,30 @@ extern crate serde_derive;
 #[macro_use]
 extern crate log;
 
+pub mod alert;
+pub mod api;
+pub mod auth;
+pub mod cache;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod crypto;
 pub mod devices;
 pub mod error;
+pub mod failure;
 pub mod gossip;
+mod hex;
+pub mod identity;
+pub mod join;
+pub mod leave;
+pub mod metrics;
+pub mod notify;
+pub mod probe;
+pub mod prometheus;
+pub mod signing;
 pub mod state;
+pub mod stats;
+pub mod sync;
 pub mod view;
+pub mod watch;
+pub mod wire;
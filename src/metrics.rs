@@ -0,0 +1,713 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for metric values.
+//!
+//! Metrics are typed, so that numbers can be aggregated. Each type of
+//! metric has its own merge semantics, which decide if a value
+//! reported for a metric replaces the current value:
+//!
+//! * Gauges, that is, text, integer, float, boolean, duration, and
+//!   timestamp values, are replaced by values with a newer version.
+//!
+//! * Counters, histograms, and summaries are cumulative and
+//!   increase monotonically from their start time. A value with the
+//!   same start time replaces the current value if it is larger, or
+//!   if it is equal and has a newer version, so the result does not
+//!   depend on the order that updates arrive. A value with another
+//!   start time, which means that the metric was reset, replaces the
+//!   current value if it has a newer version, so the version of a
+//!   metric never decreases.
+//!
+//! * A value of a different type than the current value replaces it
+//!   if it has a newer version.
+//!
+//! Each metric also keeps a bounded history of the values reported,
+//! which is local to the agent and not exchanged with other agents.
+
+use crate::wire::TYPED_METRICS_PROTOCOL_VERSION;
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+/// Value of a quantile in a summary.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Quantile {
+    /// The quantile, between 0 and 1.
+    pub quantile: f64,
+
+    /// The value of the quantile.
+    pub value: f64,
+}
+
+/// Distribution of observations in buckets.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Histogram {
+    /// Upper bounds of the buckets, in increasing order.
+    pub bounds: Vec<f64>,
+
+    /// Number of observations in each bucket. There is one more count
+    /// than bounds, for observations above the last bound.
+    pub counts: Vec<u64>,
+
+    /// Sum of all observations.
+    pub sum: f64,
+
+    /// Total number of observations.
+    pub count: u64,
+
+    /// Time when the histogram started collecting observations.
+    pub start: DateTime<Utc>,
+}
+
+/// Summary of observations using quantiles.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Summary {
+    /// Quantiles of the observations.
+    pub quantiles: Vec<Quantile>,
+
+    /// Sum of all observations.
+    pub sum: f64,
+
+    /// Total number of observations.
+    pub count: u64,
+
+    /// Time when the summary started collecting observations.
+    pub start: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Metric {
+    Text(String),
+
+    /// Integer gauge.
+    Integer(i64),
+
+    /// Floating-point gauge.
+    Float(f64),
+
+    /// Monotonic counter, counting from the start time.
+    Counter {
+        value: u64,
+        start: DateTime<Utc>,
+    },
+
+    Boolean(bool),
+
+    Duration(Duration),
+
+    Timestamp(DateTime<Utc>),
+
+    Histogram(Histogram),
+
+    Summary(Summary),
+}
+
+impl Metric {
+    /// Check if agents speaking a protocol version can decode the
+    /// value. Agents from before typed metrics only know text.
+    pub fn is_supported(&self, version: u8) -> bool {
+        version >= TYPED_METRICS_PROTOCOL_VERSION || matches!(self, Metric::Text(_))
+    }
+
+    /// Check that the value is well-formed.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Metric::Histogram(histogram) => {
+                if histogram.counts.len() != histogram.bounds.len() + 1 {
+                    return Err(format!(
+                        "histogram has {} bounds, but {} counts",
+                        histogram.bounds.len(),
+                        histogram.counts.len()
+                    ));
+                }
+                if histogram.bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return Err("histogram bounds are not increasing".to_string());
+                }
+                if histogram.counts.iter().sum::<u64>() != histogram.count {
+                    return Err("histogram counts do not add up to the count".to_string());
+                }
+                Ok(())
+            }
+            Metric::Summary(summary) => {
+                if summary
+                    .quantiles
+                    .iter()
+                    .any(|q| !(0.0..=1.0).contains(&q.quantile))
+                {
+                    return Err("summary quantile is not between 0 and 1".to_string());
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check if the value should replace the current value of the
+    /// metric, according to the merge semantics of the metric.
+    ///
+    /// # Parameters
+    ///
+    /// * `version_is_newer` - Whether the value has a newer version
+    ///   than the current value.
+    ///
+    pub fn supersedes(&self, current: &Metric, version_is_newer: bool) -> bool {
+        match (self, current) {
+            (
+                Metric::Counter { value, start },
+                Metric::Counter {
+                    value: current_value,
+                    start: current_start,
+                },
+            ) => cumulative_supersedes(
+                (start, *value),
+                (current_start, *current_value),
+                version_is_newer,
+            ),
+            (Metric::Histogram(histogram), Metric::Histogram(current))
+                if histogram.bounds == current.bounds =>
+            {
+                cumulative_supersedes(
+                    (&histogram.start, histogram.count),
+                    (&current.start, current.count),
+                    version_is_newer,
+                )
+            }
+            (Metric::Summary(summary), Metric::Summary(current)) => cumulative_supersedes(
+                (&summary.start, summary.count),
+                (&current.start, current.count),
+                version_is_newer,
+            ),
+            _ => version_is_newer,
+        }
+    }
+
+    /// Convert a JSON value to a metric.
+    ///
+    /// Booleans, numbers, and strings are converted to the
+    /// corresponding gauge or text metric, while objects are read as
+    /// typed metrics, for example `{"Counter": {"value": 7, "start":
+    /// "2019-06-01T00:00:00Z"}}`. Returns `None` for other values.
+    pub fn from_json(value: serde_json::Value) -> Option<Metric> {
+        match value {
+            serde_json::Value::Bool(value) => Some(Metric::Boolean(value)),
+            serde_json::Value::Number(number) => match number.as_i64() {
+                Some(value) => Some(Metric::Integer(value)),
+                None => number.as_f64().map(Metric::Float),
+            },
+            serde_json::Value::String(text) => Some(Metric::Text(text)),
+            value @ serde_json::Value::Object(_) => serde_json::from_value(value).ok(),
+            serde_json::Value::Null | serde_json::Value::Array(_) => None,
+        }
+    }
+
+    /// Get the value as a number, if it has one.
+    ///
+    /// Booleans are 0 or 1, durations are in seconds, and timestamps
+    /// are seconds since the epoch. Histograms and summaries are the
+    /// average of the observations.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Metric::Text(_) => None,
+            Metric::Integer(value) => Some(*value as f64),
+            Metric::Float(value) => Some(*value),
+            Metric::Counter { value, .. } => Some(*value as f64),
+            Metric::Boolean(value) => Some(if *value { 1.0 } else { 0.0 }),
+            Metric::Duration(value) => Some(value.as_secs_f64()),
+            Metric::Timestamp(value) => Some(value.timestamp_millis() as f64 / 1000.0),
+            Metric::Histogram(Histogram { sum, count, .. })
+            | Metric::Summary(Summary { sum, count, .. }) => {
+                if *count > 0 {
+                    Some(sum / *count as f64)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Check if a cumulative value, given by its start time and count,
+/// should replace the current value.
+fn cumulative_supersedes(
+    (start, count): (&DateTime<Utc>, u64),
+    (current_start, current_count): (&DateTime<Utc>, u64),
+    version_is_newer: bool,
+) -> bool {
+    if start == current_start {
+        count > current_count || (count == current_count && version_is_newer)
+    } else {
+        version_is_newer
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Metric::Text(text) => write!(f, "{}", text),
+            Metric::Integer(value) => write!(f, "{}", value),
+            Metric::Float(value) => write!(f, "{}", value),
+            Metric::Counter { value, .. } => write!(f, "{}", value),
+            Metric::Boolean(value) => write!(f, "{}", value),
+            Metric::Duration(value) => write!(f, "{:?}", value),
+            Metric::Timestamp(value) => write!(f, "{}", value.to_rfc3339()),
+            Metric::Histogram(Histogram { sum, count, .. })
+            | Metric::Summary(Summary { sum, count, .. }) => {
+                write!(f, "count={} sum={}", count, sum)
+            }
+        }
+    }
+}
+
+/// Default maximum number of samples kept in the history of a
+/// metric.
+pub const DEFAULT_HISTORY_SAMPLES: usize = 360;
+
+/// Default maximum age of samples kept in the history of a metric.
+pub const DEFAULT_HISTORY_AGE: Duration = Duration::from_secs(3600);
+
+/// Value of a metric at a point in time.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Sample {
+    /// Time of the sample in milliseconds since the epoch, which is
+    /// the time of the version of the update reporting it.
+    pub timestamp_millis: i64,
+
+    pub value: Metric,
+}
+
+/// Limits on the history kept for each metric.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryLimits {
+    /// Maximum number of samples.
+    pub max_samples: usize,
+
+    /// Maximum age of samples.
+    pub max_age: Duration,
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        HistoryLimits {
+            max_samples: DEFAULT_HISTORY_SAMPLES,
+            max_age: DEFAULT_HISTORY_AGE,
+        }
+    }
+}
+
+/// Aggregate of the numeric values of samples.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct Aggregate {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+/// Bounded history of the values of a metric, ordered by time.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    samples: VecDeque<Sample>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// Number of samples in the history.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Check if the history has no samples.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Record a sample in the history.
+    ///
+    /// Samples can arrive out of order, so the sample is inserted at
+    /// its place in time. A sample with the same time as an existing
+    /// sample replaces it. The oldest samples are then dropped until
+    /// the history is within the limits, where the age is relative to
+    /// the newest sample.
+    pub fn record(&mut self, timestamp_millis: i64, value: &Metric, limits: &HistoryLimits) {
+        let sample = Sample {
+            timestamp_millis,
+            value: value.clone(),
+        };
+        match self
+            .samples
+            .binary_search_by_key(&timestamp_millis, |s| s.timestamp_millis)
+        {
+            Ok(pos) => self.samples[pos] = sample,
+            Err(pos) => self.samples.insert(pos, sample),
+        }
+        while self.samples.len() > limits.max_samples {
+            self.samples.pop_front();
+        }
+        if let Some(newest) = self.samples.back() {
+            self.expire(newest.timestamp_millis - limits.max_age.as_millis() as i64);
+        }
+    }
+
+    /// Drop all samples older than the given time.
+    pub fn expire(&mut self, oldest_millis: i64) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|s| s.timestamp_millis < oldest_millis)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Get the samples in a time range, including both ends.
+    pub fn range(&self, from_millis: i64, to_millis: i64) -> impl Iterator<Item = &Sample> {
+        self.samples
+            .iter()
+            .skip_while(move |s| s.timestamp_millis < from_millis)
+            .take_while(move |s| s.timestamp_millis <= to_millis)
+    }
+
+    /// Get the newest sample at or before a point in time.
+    pub fn at(&self, timestamp_millis: i64) -> Option<&Sample> {
+        self.samples
+            .iter()
+            .rev()
+            .find(|s| s.timestamp_millis <= timestamp_millis)
+    }
+
+    /// Compute the minimum, maximum, and average of the numeric values
+    /// in a time range.
+    ///
+    /// Samples without a numeric value are skipped, see
+    /// `Metric::as_f64`. Returns `None` if there are no numeric
+    /// samples in the range.
+    pub fn aggregate(&self, from_millis: i64, to_millis: i64) -> Option<Aggregate> {
+        let mut result: Option<Aggregate> = None;
+        let mut sum = 0.0;
+        for value in self
+            .range(from_millis, to_millis)
+            .filter_map(|s| s.value.as_f64())
+        {
+            sum += value;
+            result = Some(match result {
+                None => Aggregate {
+                    count: 1,
+                    min: value,
+                    max: value,
+                    avg: value,
+                },
+                Some(agg) => Aggregate {
+                    count: agg.count + 1,
+                    min: agg.min.min(value),
+                    max: agg.max.max(value),
+                    avg: 0.0,
+                },
+            });
+        }
+        result.map(|agg| Aggregate {
+            avg: sum / agg.count as f64,
+            ..agg
+        })
+    }
+
+    /// Compute the rate of change per second in a time range.
+    ///
+    /// For counters, the increase is summed over consecutive samples,
+    /// where a counter that was reset counts from zero. For other
+    /// numeric metrics, the rate is the difference between the last
+    /// and first value in the range. Returns `None` if there are not
+    /// at least two numeric samples at different times in the range.
+    pub fn rate(&self, from_millis: i64, to_millis: i64) -> Option<f64> {
+        let samples: Vec<&Sample> = self
+            .range(from_millis, to_millis)
+            .filter(|s| s.value.as_f64().is_some())
+            .collect();
+        let first = samples.first()?;
+        let last = samples.last()?;
+        let elapsed = (last.timestamp_millis - first.timestamp_millis) as f64 / 1000.0;
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let increase: f64 = samples
+            .windows(2)
+            .map(|pair| match (&pair[0].value, &pair[1].value) {
+                (
+                    Metric::Counter {
+                        value: prev,
+                        start: prev_start,
+                    },
+                    Metric::Counter { value, start },
+                ) => {
+                    if start == prev_start && value >= prev {
+                        (value - prev) as f64
+                    } else {
+                        *value as f64
+                    }
+                }
+                (prev, value) => value.as_f64().unwrap_or(0.0) - prev.as_f64().unwrap_or(0.0),
+            })
+            .sum();
+        Some(increase / elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(secs, 0).unwrap()
+    }
+
+    fn counter(value: u64, start: i64) -> Metric {
+        Metric::Counter {
+            value,
+            start: at(start),
+        }
+    }
+
+    fn histogram(bounds: Vec<f64>, count: u64, start: i64) -> Metric {
+        let mut counts = vec![0; bounds.len() + 1];
+        counts[0] = count;
+        Metric::Histogram(Histogram {
+            bounds,
+            counts,
+            sum: count as f64,
+            count,
+            start: at(start),
+        })
+    }
+
+    fn summary(count: u64, start: i64) -> Metric {
+        Metric::Summary(Summary {
+            quantiles: Vec::new(),
+            sum: count as f64,
+            count,
+            start: at(start),
+        })
+    }
+
+    #[test]
+    fn gauges_are_replaced_by_newer_versions() {
+        let gauges = vec![
+            (Metric::Text("a".to_string()), Metric::Text("b".to_string())),
+            (Metric::Integer(2), Metric::Integer(1)),
+            (Metric::Float(1.0), Metric::Float(2.0)),
+            (Metric::Boolean(true), Metric::Boolean(false)),
+            (
+                Metric::Duration(Duration::from_secs(1)),
+                Metric::Duration(Duration::from_secs(2)),
+            ),
+            (Metric::Timestamp(at(1)), Metric::Timestamp(at(2))),
+        ];
+        for (value, current) in gauges {
+            assert!(value.supersedes(&current, true));
+            assert!(!value.supersedes(&current, false));
+        }
+    }
+
+    #[test]
+    fn cumulative_values_only_increase_within_a_start_time() {
+        let cumulative = vec![
+            (counter(1, 10), counter(2, 10)),
+            (
+                histogram(vec![1.0, 2.0], 1, 10),
+                histogram(vec![1.0, 2.0], 2, 10),
+            ),
+            (summary(1, 10), summary(2, 10)),
+        ];
+        for (smaller, larger) in cumulative {
+            assert!(larger.supersedes(&smaller, false));
+            assert!(!smaller.supersedes(&larger, true));
+        }
+    }
+
+    #[test]
+    fn equal_cumulative_values_are_decided_by_version() {
+        for value in [
+            counter(2, 10),
+            histogram(vec![1.0, 2.0], 2, 10),
+            summary(2, 10),
+        ] {
+            assert!(value.supersedes(&value, true));
+            assert!(!value.supersedes(&value, false));
+        }
+    }
+
+    #[test]
+    fn reset_cumulative_values_are_decided_by_version() {
+        let resets = vec![
+            (counter(1, 20), counter(100, 10)),
+            (counter(1, 10), counter(100, 20)),
+            (summary(1, 20), summary(100, 10)),
+            (histogram(vec![1.0], 1, 20), histogram(vec![1.0], 100, 10)),
+        ];
+        for (value, current) in resets {
+            assert!(value.supersedes(&current, true));
+            assert!(!value.supersedes(&current, false));
+        }
+    }
+
+    #[test]
+    fn changed_type_or_buckets_is_decided_by_version() {
+        let changed = vec![
+            (counter(1, 10), Metric::Integer(100)),
+            (Metric::Integer(1), counter(100, 10)),
+            (summary(1, 10), counter(100, 10)),
+            (histogram(vec![1.0], 1, 10), histogram(vec![2.0], 100, 10)),
+        ];
+        for (value, current) in changed {
+            assert!(value.supersedes(&current, true));
+            assert!(!value.supersedes(&current, false));
+        }
+    }
+
+    fn limits(max_samples: usize, max_age_secs: u64) -> HistoryLimits {
+        HistoryLimits {
+            max_samples,
+            max_age: Duration::from_secs(max_age_secs),
+        }
+    }
+
+    fn history(samples: &[(i64, Metric)]) -> History {
+        let mut history = History::new();
+        for (millis, value) in samples {
+            history.record(*millis, value, &HistoryLimits::default());
+        }
+        history
+    }
+
+    fn times(history: &History) -> Vec<i64> {
+        history
+            .range(i64::MIN, i64::MAX)
+            .map(|s| s.timestamp_millis)
+            .collect()
+    }
+
+    #[test]
+    fn history_keeps_samples_ordered_by_time() {
+        let history = history(&[
+            (3000, Metric::Integer(3)),
+            (1000, Metric::Integer(1)),
+            (2000, Metric::Integer(2)),
+        ]);
+        assert_eq!(times(&history), vec![1000, 2000, 3000]);
+    }
+
+    #[test]
+    fn history_replaces_sample_with_same_time() {
+        let history = history(&[
+            (1000, Metric::Integer(1)),
+            (2000, Metric::Integer(2)),
+            (1000, Metric::Integer(10)),
+        ]);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.at(1000).unwrap().value, Metric::Integer(10));
+    }
+
+    #[test]
+    fn history_drops_oldest_samples_above_count_limit() {
+        let mut history = History::new();
+        for millis in 0..5 {
+            history.record(millis * 1000, &Metric::Integer(millis), &limits(3, 3600));
+        }
+        assert_eq!(times(&history), vec![2000, 3000, 4000]);
+
+        // A late sample older than all kept samples is dropped at once.
+        history.record(500, &Metric::Integer(0), &limits(3, 3600));
+        assert_eq!(times(&history), vec![2000, 3000, 4000]);
+    }
+
+    #[test]
+    fn history_drops_samples_older_than_age_limit() {
+        let mut history = History::new();
+        for secs in &[0, 5, 10, 15] {
+            history.record(secs * 1000, &Metric::Integer(*secs), &limits(100, 10));
+        }
+        assert_eq!(times(&history), vec![5000, 10000, 15000]);
+        history.expire(12_000);
+        assert_eq!(times(&history), vec![15000]);
+    }
+
+    #[test]
+    fn history_range_and_at_include_boundaries() {
+        let history = history(&[
+            (1000, Metric::Integer(1)),
+            (2000, Metric::Integer(2)),
+            (3000, Metric::Integer(3)),
+        ]);
+        let range: Vec<i64> = history
+            .range(2000, 3000)
+            .map(|s| s.timestamp_millis)
+            .collect();
+        assert_eq!(range, vec![2000, 3000]);
+        assert_eq!(history.range(1500, 1900).count(), 0);
+        assert_eq!(history.at(2000).unwrap().value, Metric::Integer(2));
+        assert_eq!(history.at(2999).unwrap().value, Metric::Integer(2));
+        assert_eq!(history.at(999), None);
+    }
+
+    #[test]
+    fn history_aggregates_numeric_samples() {
+        let history = history(&[
+            (1000, Metric::Integer(4)),
+            (2000, Metric::Text("down".to_string())),
+            (3000, Metric::Float(1.0)),
+            (4000, Metric::Boolean(true)),
+        ]);
+        assert_eq!(
+            history.aggregate(0, 5000),
+            Some(Aggregate {
+                count: 3,
+                min: 1.0,
+                max: 4.0,
+                avg: 2.0,
+            })
+        );
+        assert_eq!(history.aggregate(2000, 2000), None);
+    }
+
+    #[test]
+    fn history_rate_of_gauge_is_difference_over_time() {
+        let history = history(&[
+            (0, Metric::Integer(10)),
+            (5000, Metric::Integer(40)),
+            (10_000, Metric::Integer(30)),
+        ]);
+        assert_eq!(history.rate(0, 10_000), Some(2.0));
+        assert_eq!(history.rate(0, 5000), Some(6.0));
+        assert_eq!(history.rate(5000, 5000), None);
+    }
+
+    #[test]
+    fn history_rate_counts_from_zero_after_counter_reset() {
+        let history = history(&[
+            (0, counter(100, 10)),
+            (10_000, counter(150, 10)),
+            // Reset by a new start time, and by a smaller value.
+            (20_000, counter(20, 20)),
+            (30_000, counter(50, 20)),
+            (40_000, counter(5, 20)),
+        ]);
+        // 50 + 20 + 30 + 5 over 40 seconds.
+        assert_eq!(history.rate(0, 40_000), Some(2.625));
+        assert_eq!(history.rate(20_000, 30_000), Some(3.0));
+    }
+}
@@ -0,0 +1,962 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for sending notifications when alerts fire or are resolved.
+//!
+//! Notifications are delivered through sinks: HTTP webhooks receiving
+//! the notification as JSON, local commands receiving it in
+//! environment variables, and the local syslog. Each sink has a worker
+//! thread delivering its notifications, retrying failed deliveries
+//! with an increasing delay.
+//!
+//! All agents learn about the transitions of alerts, but only one of
+//! them sends each notification. The agents are ranked using
+//! rendezvous hashing over the members of the view that are alive, so
+//! all agents with the same view agree on the ranking without
+//! coordinating. The first agent sends the notification at once and
+//! gossips that it was delivered once all sinks accepted it. Each
+//! following agent waits one failover delay longer than the agent
+//! before it and only sends the notification if it has not learned
+//! that it was delivered, so a notification is still sent if the
+//! first agent fails. A transition is also only notified once by each
+//! agent, even if it arrives both through gossip and through
+//! anti-entropy.
+
+use crate::alert::AlertState;
+use crate::clock::Version;
+use crate::error::Error;
+use crate::view::{ServerStatus, ServerView};
+use crate::watch::Event;
+use chrono::{DateTime, Local, Utc};
+use futures::sync::mpsc::UnboundedSender;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request, Uri};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::prelude::FutureExt;
+use tokio::runtime::current_thread::Runtime;
+use uuid::Uuid;
+
+/// Default time that a webhook or command may take.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default number of times a failed delivery is retried.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Default delay before the first retry. The delay is doubled for
+/// each retry.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Default age of transitions that are too old to notify, for example
+/// when they arrive at an agent that just joined the cluster.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Default time that each agent waits after the agent ranked before
+/// it, before sending a notification that is not yet delivered.
+pub const DEFAULT_FAILOVER_DELAY: Duration = Duration::from_secs(30);
+
+/// Default path of the syslog socket.
+pub const DEFAULT_SYSLOG_PATH: &str = "/dev/log";
+
+/// Number of transitions remembered to avoid notifying them twice.
+const RECENT_SIZE: usize = 1024;
+
+/// Transition of an alert, identified by the alert and the version of
+/// the transition.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Transition {
+    /// The agent owning the device.
+    pub owner: Uuid,
+    pub rule: String,
+    pub device: String,
+    pub version: Version,
+}
+
+/// Notification of a transition of an alert.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    /// The agent sending the notification.
+    pub agent: Uuid,
+
+    /// The agent owning the device.
+    pub owner: Uuid,
+    pub rule: String,
+    pub device: String,
+    pub metric: String,
+    pub state: AlertState,
+    pub value: f64,
+
+    /// Time of the transition.
+    pub time: DateTime<Utc>,
+}
+
+impl Notification {
+    /// Human-readable summary of the notification.
+    pub fn summary(&self) -> String {
+        format!(
+            "Alert {} is {} for device {} on {}: {} is {}",
+            self.rule, self.state, self.device, self.owner, self.metric, self.value
+        )
+    }
+}
+
+/// Destination of notifications.
+pub trait Sink: Send {
+    /// Name of the sink, used in log messages.
+    fn name(&self) -> String;
+
+    /// Deliver a notification.
+    fn send(&mut self, notification: &Notification) -> Result<(), Error>;
+}
+
+/// Sink posting notifications as JSON to an HTTP URL.
+pub struct WebhookSink {
+    url: Uri,
+    timeout: Duration,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str, timeout: Duration) -> Result<WebhookSink, Error> {
+        let url: Uri = url
+            .parse()
+            .map_err(|err| Error::NotifyError(format!("invalid URL {}: {}", url, err)))?;
+        if url.scheme_str() != Some("http") {
+            return Err(Error::NotifyError(format!(
+                "unsupported URL {}, only http is supported",
+                url
+            )));
+        }
+        Ok(WebhookSink { url, timeout })
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    fn send(&mut self, notification: &Notification) -> Result<(), Error> {
+        let body =
+            serde_json::to_vec(notification).expect("notifications can always be serialized");
+        let mut request = Request::post(self.url.clone())
+            .body(Body::from(body))
+            .map_err(|err| Error::NotifyError(err.to_string()))?;
+        request
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let mut runtime = Runtime::new()?;
+        let response = runtime
+            .block_on(Client::new().request(request).timeout(self.timeout))
+            .map_err(|err| Error::NotifyError(format!("request failed: {}", err)))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::NotifyError(format!(
+                "request failed with status {}",
+                response.status()
+            )))
+        }
+    }
+}
+
+/// Sink running a shell command for each notification.
+///
+/// The notification is passed in the environment variables
+/// `CHATTER_ALERT_OWNER`, `CHATTER_ALERT_RULE`,
+/// `CHATTER_ALERT_DEVICE`, `CHATTER_ALERT_METRIC`,
+/// `CHATTER_ALERT_STATE`, `CHATTER_ALERT_VALUE`, and
+/// `CHATTER_ALERT_TIME`. The delivery fails if the command exits with
+/// a non-zero status or does not complete in time, in which case the
+/// command is killed together with the processes it started.
+pub struct CommandSink {
+    command: String,
+    timeout: Duration,
+}
+
+impl CommandSink {
+    pub fn new(command: &str, timeout: Duration) -> CommandSink {
+        CommandSink {
+            command: command.to_string(),
+            timeout,
+        }
+    }
+}
+
+impl Sink for CommandSink {
+    fn name(&self) -> String {
+        format!("command {}", self.command)
+    }
+
+    fn send(&mut self, notification: &Notification) -> Result<(), Error> {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("CHATTER_ALERT_OWNER", notification.owner.to_string())
+            .env("CHATTER_ALERT_RULE", &notification.rule)
+            .env("CHATTER_ALERT_DEVICE", &notification.device)
+            .env("CHATTER_ALERT_METRIC", &notification.metric)
+            .env("CHATTER_ALERT_STATE", notification.state.to_string())
+            .env("CHATTER_ALERT_VALUE", notification.value.to_string())
+            .env("CHATTER_ALERT_TIME", notification.time.to_rfc3339())
+            .process_group(0)
+            .spawn()?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return if status.success() {
+                    Ok(())
+                } else {
+                    Err(Error::NotifyError(format!(
+                        "command failed with {}",
+                        status
+                    )))
+                };
+            }
+            if Instant::now() >= deadline {
+                // The process group has the same id as the shell.
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                let _ = child.wait();
+                return Err(Error::NotifyError(format!(
+                    "command did not complete in {} seconds",
+                    self.timeout.as_secs()
+                )));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Syslog facility to log notifications with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+/// Sink logging notifications to the local syslog.
+///
+/// Firing alerts are logged with severity warning and resolved alerts
+/// with severity notice.
+pub struct SyslogSink {
+    path: PathBuf,
+    facility: Facility,
+}
+
+impl SyslogSink {
+    pub fn new(path: PathBuf, facility: Facility) -> SyslogSink {
+        SyslogSink { path, facility }
+    }
+}
+
+impl Sink for SyslogSink {
+    fn name(&self) -> String {
+        format!("syslog {}", self.path.display())
+    }
+
+    fn send(&mut self, notification: &Notification) -> Result<(), Error> {
+        let severity = match notification.state {
+            AlertState::Firing => 4,
+            AlertState::Resolved => 5,
+        };
+        let message = format!(
+            "<{}>{} chatterd[{}]: {}",
+            self.facility.code() * 8 + severity,
+            Local::now().format("%b %e %H:%M:%S"),
+            process::id(),
+            notification.summary()
+        );
+        UnixDatagram::unbound()?.send_to(message.as_bytes(), &self.path)?;
+        Ok(())
+    }
+}
+
+/// How failed deliveries are retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of times a failed delivery is retried.
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled for each retry.
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: DEFAULT_RETRIES,
+            delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+}
+
+/// Notification queued for delivery to a sink.
+struct Job {
+    notification: Notification,
+    transition: Transition,
+
+    /// Number of sinks that have not yet delivered the notification.
+    remaining: Arc<AtomicUsize>,
+}
+
+/// Deliver notifications to a sink until the sender is dropped.
+///
+/// When the last sink delivers a notification, the transition is sent
+/// to `delivered`.
+fn deliver(
+    mut sink: Box<dyn Sink>,
+    jobs: Receiver<Job>,
+    policy: RetryPolicy,
+    delivered: UnboundedSender<Transition>,
+) {
+    for job in jobs {
+        let mut delay = policy.delay;
+        for attempt in 0..=policy.retries {
+            match sink.send(&job.notification) {
+                Ok(()) => {
+                    debug!("Delivered notification to {}", sink.name());
+                    if job.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        let _ = delivered.unbounded_send(job.transition.clone());
+                    }
+                    break;
+                }
+                Err(err) if attempt < policy.retries => {
+                    warn!(
+                        "Unable to deliver notification to {}, retrying in {} seconds: {}",
+                        sink.name(),
+                        delay.as_secs(),
+                        err
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(err) => error!(
+                    "Unable to deliver notification to {}, giving up: {}",
+                    sink.name(),
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// Sender of notifications for the transitions of alerts.
+pub struct Notifier {
+    uuid: Uuid,
+    workers: Vec<Sender<Job>>,
+    max_age: Duration,
+    failover_delay: Duration,
+
+    /// Queue receiving the transitions whose notifications were
+    /// delivered by all sinks, which should be gossiped to the other
+    /// agents.
+    delivered: UnboundedSender<Transition>,
+
+    /// Notifications that this agent sends at the given time, unless
+    /// another agent delivers them first.
+    pending: HashMap<Transition, (Notification, i64)>,
+
+    /// Transitions already handled, oldest first.
+    recent: VecDeque<Transition>,
+    seen: HashSet<Transition>,
+}
+
+impl Notifier {
+    /// Construct a notifier for this agent without any sinks.
+    ///
+    /// # Parameters
+    ///
+    /// * `uuid` - The UUID of this agent.
+    ///
+    /// * `delivered` - Queue receiving the transitions whose
+    ///   notifications were delivered by this agent.
+    ///
+    pub fn new(uuid: Uuid, delivered: UnboundedSender<Transition>) -> Notifier {
+        Notifier {
+            uuid,
+            workers: Vec::new(),
+            max_age: DEFAULT_MAX_AGE,
+            failover_delay: DEFAULT_FAILOVER_DELAY,
+            delivered,
+            pending: HashMap::new(),
+            recent: VecDeque::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Set the age of transitions that are too old to notify.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
+
+    /// Set the time that each agent waits after the agent ranked
+    /// before it.
+    pub fn set_failover_delay(&mut self, failover_delay: Duration) {
+        self.failover_delay = failover_delay;
+    }
+
+    /// Replace the sinks. Notifications that are queued for the old
+    /// sinks are still delivered.
+    pub fn set_sinks(&mut self, sinks: Vec<Box<dyn Sink>>, policy: RetryPolicy) {
+        self.workers = sinks
+            .into_iter()
+            .map(|sink| {
+                let (sender, receiver) = channel();
+                let delivered = self.delivered.clone();
+                thread::spawn(move || deliver(sink, receiver, policy, delivered));
+                sender
+            })
+            .collect();
+    }
+
+    /// Handle an event, sending a notification if it is a transition of
+    /// an alert and this agent is the first in the ranking, or
+    /// scheduling it if this agent is a later one.
+    pub fn handle(&mut self, event: &Event, view: &ServerView, now_millis: i64) {
+        let (owner, rule, device, metric, value, version, state) = match event {
+            Event::AlertFiring {
+                owner,
+                rule,
+                device,
+                metric,
+                value,
+                version,
+            } => (
+                owner,
+                rule,
+                device,
+                metric,
+                value,
+                version,
+                AlertState::Firing,
+            ),
+            Event::AlertResolved {
+                owner,
+                rule,
+                device,
+                metric,
+                value,
+                version,
+            } => (
+                owner,
+                rule,
+                device,
+                metric,
+                value,
+                version,
+                AlertState::Resolved,
+            ),
+            _ => return,
+        };
+        let transition = Transition {
+            owner: *owner,
+            rule: rule.clone(),
+            device: device.clone(),
+            version: *version,
+        };
+        if self.workers.is_empty() || !self.remember(transition.clone()) {
+            return;
+        }
+        if now_millis - version.millis > self.max_age.as_millis() as i64 {
+            debug!(
+                "Not notifying alert {} of {} on {}: too old",
+                rule, device, owner
+            );
+            return;
+        }
+        let key = format!("{}/{}/{}/{}", owner, rule, device, version);
+        let rank = ranking(&self.uuid, view, &key)
+            .iter()
+            .position(|uuid| *uuid == self.uuid)
+            .unwrap_or(0);
+        let wait = rank as i64 * self.failover_delay.as_millis() as i64;
+        if wait > self.max_age.as_millis() as i64 {
+            debug!(
+                "Not notifying alert {} of {} on {}: ranked {}",
+                rule, device, owner, rank
+            );
+            return;
+        }
+
+        let notification = Notification {
+            agent: self.uuid,
+            owner: *owner,
+            rule: rule.clone(),
+            device: device.clone(),
+            metric: metric.clone(),
+            state,
+            value: *value,
+            time: DateTime::from_timestamp_millis(version.millis).unwrap_or_default(),
+        };
+        if rank == 0 {
+            self.send(transition, notification);
+        } else {
+            debug!(
+                "Alert {} of {} on {} is notified by this agent in {} seconds unless delivered",
+                rule,
+                device,
+                owner,
+                wait / 1000
+            );
+            self.pending
+                .insert(transition, (notification, version.millis + wait));
+        }
+    }
+
+    /// Send the scheduled notifications that are due and were not
+    /// delivered by another agent.
+    pub fn tick(&mut self, now_millis: i64) {
+        let due: Vec<Transition> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, due))| *due <= now_millis)
+            .map(|(transition, _)| transition.clone())
+            .collect();
+        for transition in due {
+            if let Some((notification, _)) = self.pending.remove(&transition) {
+                warn!(
+                    "Notification of alert {} of {} on {} was not delivered, sending it",
+                    transition.rule, transition.device, transition.owner
+                );
+                self.send(transition, notification);
+            }
+        }
+    }
+
+    /// Handle that the notification of a transition was delivered,
+    /// so this agent does not need to send it.
+    pub fn delivered(&mut self, transition: &Transition) {
+        if self.pending.remove(transition).is_some() {
+            debug!(
+                "Notification of alert {} of {} on {} was delivered",
+                transition.rule, transition.device, transition.owner
+            );
+        }
+        self.remember(transition.clone());
+    }
+
+    fn send(&self, transition: Transition, notification: Notification) {
+        info!("Notifying: {}", notification.summary());
+        let remaining = Arc::new(AtomicUsize::new(self.workers.len()));
+        for worker in &self.workers {
+            let job = Job {
+                notification: notification.clone(),
+                transition: transition.clone(),
+                remaining: remaining.clone(),
+            };
+            if worker.send(job).is_err() {
+                error!("Notification worker stopped, dropping notification");
+            }
+        }
+    }
+
+    /// Remember a transition. Returns `false` if it was already seen.
+    fn remember(&mut self, transition: Transition) -> bool {
+        if !self.seen.insert(transition.clone()) {
+            return false;
+        }
+        self.recent.push_back(transition);
+        if self.recent.len() > RECENT_SIZE {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Rank the agents for sending a notification using rendezvous
+/// hashing over this agent and the members of the view that are alive.
+/// The first agent is responsible for the notification.
+pub fn ranking(uuid: &Uuid, view: &ServerView, key: &str) -> Vec<Uuid> {
+    let mut ranked: Vec<_> = view
+        .servers
+        .iter()
+        .filter(|(candidate, info)| *candidate != uuid && info.status == ServerStatus::Alive)
+        .map(|(candidate, _)| candidate)
+        .chain(std::iter::once(uuid))
+        .map(|candidate| {
+            let hash = Sha256::new()
+                .chain_update(candidate.as_bytes())
+                .chain_update(key.as_bytes())
+                .finalize();
+            (hash, *candidate)
+        })
+        .collect();
+    ranked.sort_unstable_by(|a, b| b.cmp(a));
+    ranked.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+/// Choose the agent responsible for a notification, see `ranking`.
+pub fn responsible(uuid: &Uuid, view: &ServerView, key: &str) -> Uuid {
+    ranking(uuid, view, key)[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::view::ViewUpdate;
+    use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+    use futures::Stream;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread::JoinHandle;
+
+    /// Serve a single HTTP request on a local port, replying with the
+    /// given status. The thread returns the request.
+    fn serve_once(status: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/chatter", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                let count = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..count]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .map(|(_, value)| value.trim().parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length || count == 0 {
+                        break;
+                    }
+                }
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    /// Sink passing the notifications on to a channel.
+    struct Recorder(Sender<Notification>);
+
+    impl Sink for Recorder {
+        fn name(&self) -> String {
+            "recorder".to_string()
+        }
+
+        fn send(&mut self, notification: &Notification) -> Result<(), Error> {
+            self.0.send(notification.clone()).unwrap();
+            Ok(())
+        }
+    }
+
+    /// Sink that always fails.
+    struct Broken;
+
+    impl Sink for Broken {
+        fn name(&self) -> String {
+            "broken".to_string()
+        }
+
+        fn send(&mut self, _notification: &Notification) -> Result<(), Error> {
+            Err(Error::NotifyError("broken".to_string()))
+        }
+    }
+
+    const NOW: i64 = 1_560_000_000_000;
+
+    fn notification() -> Notification {
+        Notification {
+            agent: Uuid::new_v4(),
+            owner: Uuid::new_v4(),
+            rule: "disk-full".to_string(),
+            device: "disk:/var".to_string(),
+            metric: "used_percent".to_string(),
+            state: AlertState::Firing,
+            value: 93.5,
+            time: DateTime::from_timestamp_millis(NOW).unwrap(),
+        }
+    }
+
+    fn firing(owner: Uuid) -> Event {
+        Event::AlertFiring {
+            owner,
+            rule: "disk-full".to_string(),
+            device: "disk:/var".to_string(),
+            metric: "used_percent".to_string(),
+            value: 93.5,
+            version: Version::new(NOW, 0),
+        }
+    }
+
+    fn transition(owner: Uuid) -> Transition {
+        Transition {
+            owner,
+            rule: "disk-full".to_string(),
+            device: "disk:/var".to_string(),
+            version: Version::new(NOW, 0),
+        }
+    }
+
+    /// View with two alive agents, ranked for notifying `firing(owner)`.
+    fn ranked_view(owner: Uuid) -> (ServerView, Vec<Uuid>) {
+        let mut view = ServerView::new();
+        for port in 0..2 {
+            let uuid = Uuid::new_v4();
+            let addr = SocketAddr::from(([127, 0, 0, 1], 9000 + port));
+            view.update(&ViewUpdate::ServerAdded { uuid, addr }, &uuid, 0);
+        }
+        let key = format!("{}/disk-full/disk:/var/{}", owner, Version::new(NOW, 0));
+        let any = *view.servers.keys().next().unwrap();
+        let ranked = ranking(&any, &view, &key);
+        (view, ranked)
+    }
+
+    fn notifier(
+        uuid: Uuid,
+        sinks: Vec<Box<dyn Sink>>,
+    ) -> (Notifier, UnboundedReceiver<Transition>) {
+        let (queue, delivered) = unbounded();
+        let mut notifier = Notifier::new(uuid, queue);
+        let policy = RetryPolicy {
+            retries: 0,
+            delay: Duration::from_millis(1),
+        };
+        notifier.set_sinks(sinks, policy);
+        (notifier, delivered)
+    }
+
+    #[test]
+    fn webhook_posts_notification_as_json() {
+        let (url, server) = serve_once("200 OK");
+        let mut sink = WebhookSink::new(&url, Duration::from_secs(5)).unwrap();
+        let notification = notification();
+        sink.send(&notification).unwrap();
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /chatter HTTP/1.1\r\n"));
+        assert!(request
+            .to_lowercase()
+            .contains("content-type: application/json\r\n"));
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body, serde_json::to_value(&notification).unwrap());
+        assert_eq!(body["state"], "firing");
+        assert_eq!(body["time"], "2019-06-08T13:20:00Z");
+    }
+
+    #[test]
+    fn webhook_fails_on_error_status() {
+        let (url, server) = serve_once("500 Internal Server Error");
+        let mut sink = WebhookSink::new(&url, Duration::from_secs(5)).unwrap();
+        assert!(sink.send(&notification()).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn webhook_requires_http_url() {
+        assert!(WebhookSink::new("https://example.com/", DEFAULT_TIMEOUT).is_err());
+        assert!(WebhookSink::new("not a url", DEFAULT_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn command_receives_notification_in_environment() {
+        let path = std::env::temp_dir().join(format!("chatter-test-{}.env", Uuid::new_v4()));
+        let command = format!(
+            "echo \"$CHATTER_ALERT_RULE $CHATTER_ALERT_STATE\" > {}",
+            path.display()
+        );
+        let mut sink = CommandSink::new(&command, DEFAULT_TIMEOUT);
+        sink.send(&notification()).unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output, "disk-full firing\n");
+
+        assert!(CommandSink::new("exit 1", DEFAULT_TIMEOUT)
+            .send(&notification())
+            .is_err());
+    }
+
+    #[test]
+    fn command_kills_processes_it_started_on_timeout() {
+        let path = std::env::temp_dir().join(format!("chatter-test-{}.pid", Uuid::new_v4()));
+        let command = format!("sleep 30 & echo $! > {}; wait", path.display());
+        let started = Instant::now();
+        let mut sink = CommandSink::new(&command, Duration::from_millis(500));
+        assert!(sink.send(&notification()).is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let pid: libc::pid_t = std::fs::read_to_string(&path)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // The killed process may linger until it is reaped.
+        let stat = format!("/proc/{}/stat", pid);
+        let alive = || {
+            std::fs::read_to_string(&stat)
+                .map(|stat| !stat.contains(") Z "))
+                .unwrap_or(false)
+        };
+        while alive() && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!alive());
+    }
+
+    #[test]
+    fn first_agent_notifies_and_reports_delivery() {
+        let owner = Uuid::new_v4();
+        let (view, ranked) = ranked_view(owner);
+        let (sender, receiver) = channel();
+        let (mut first, delivered) = notifier(
+            ranked[0],
+            vec![
+                Box::new(Recorder(sender.clone())),
+                Box::new(Recorder(sender)),
+            ],
+        );
+        first.handle(&firing(owner), &view, NOW);
+        first.handle(&firing(owner), &view, NOW);
+
+        for _ in 0..2 {
+            let notification = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(notification.agent, ranked[0]);
+            assert_eq!(notification.state, AlertState::Firing);
+        }
+        drop(first);
+        let delivered: Vec<_> = delivered.wait().map(|t| t.unwrap()).collect();
+        assert_eq!(delivered, vec![transition(owner)]);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn delivery_is_not_reported_when_a_sink_fails() {
+        let owner = Uuid::new_v4();
+        let (view, ranked) = ranked_view(owner);
+        let (sender, receiver) = channel();
+        let (mut first, delivered) = notifier(
+            ranked[0],
+            vec![Box::new(Recorder(sender)), Box::new(Broken)],
+        );
+        first.handle(&firing(owner), &view, NOW);
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(first);
+        assert_eq!(delivered.wait().count(), 0);
+    }
+
+    #[test]
+    fn next_agent_notifies_after_failover_delay() {
+        let owner = Uuid::new_v4();
+        let (view, ranked) = ranked_view(owner);
+        let (sender, receiver) = channel();
+        let (mut second, _delivered) = notifier(ranked[1], vec![Box::new(Recorder(sender))]);
+        second.set_failover_delay(Duration::from_secs(30));
+        second.handle(&firing(owner), &view, NOW);
+
+        second.tick(NOW + 29_999);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        second.tick(NOW + 30_000);
+        let notification = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(notification.agent, ranked[1]);
+        second.tick(NOW + 60_000);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn next_agent_does_not_notify_delivered_transition() {
+        let owner = Uuid::new_v4();
+        let (view, ranked) = ranked_view(owner);
+        let (sender, receiver) = channel();
+        let (mut second, _delivered) =
+            notifier(ranked[1], vec![Box::new(Recorder(sender.clone()))]);
+        second.handle(&firing(owner), &view, NOW);
+        second.delivered(&transition(owner));
+        second.tick(NOW + 3_600_000);
+
+        // A delivery that arrives before the transition also prevents
+        // the notification.
+        let (mut early, _delivered) = notifier(ranked[1], vec![Box::new(Recorder(sender))]);
+        early.delivered(&transition(owner));
+        early.handle(&firing(owner), &view, NOW);
+        early.tick(NOW + 3_600_000);
+
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn agents_ranked_beyond_max_age_do_not_notify() {
+        let owner = Uuid::new_v4();
+        let (view, ranked) = ranked_view(owner);
+        let (sender, receiver) = channel();
+        let (mut second, _delivered) = notifier(ranked[1], vec![Box::new(Recorder(sender))]);
+        second.set_max_age(Duration::from_secs(60));
+        second.set_failover_delay(Duration::from_secs(120));
+        second.handle(&firing(owner), &view, NOW);
+        second.tick(NOW + 3_600_000);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn ranking_is_the_same_for_all_agents() {
+        let owner = Uuid::new_v4();
+        let (view, ranked) = ranked_view(owner);
+        let key = format!("{}/disk-full/disk:/var/{}", owner, Version::new(NOW, 0));
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranking(&ranked[1], &view, &key), ranked);
+        assert_eq!(responsible(&ranked[1], &view, &key), ranked[0]);
+
+        // An agent that is not yet in its own view still ranks itself.
+        let outsider = Uuid::new_v4();
+        assert_eq!(ranking(&outsider, &view, &key).len(), 3);
+    }
+}
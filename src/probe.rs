@@ -0,0 +1,167 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for probing the devices on the machine of the agent.
+//!
+//! Each agent monitors the devices on its own machine using a set of
+//! probes. A probe samples the metrics of a single device, and the
+//! prober turns the samples into device updates owned by the agent,
+//! which are then applied locally and disseminated to the cluster.
+//!
+//! Probes for the CPU, memory, file systems, and network interfaces
+//! of the machine are available in the `system` module, and probes
+//! running external check commands in the `command` module. Other
+//! devices can be monitored by implementing the `Probe` trait.
+
+pub mod command;
+pub mod system;
+
+use crate::clock::SharedClock;
+use crate::devices::{DeviceUpdate, Metric};
+use crate::error::Error;
+use crate::gossip::Action;
+use crate::signing::Signer;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Probe sampling the metrics of a device.
+pub trait Probe: Send {
+    /// Name of the device that the probe monitors. The name has to be
+    /// unique among the probes of the agent.
+    fn name(&self) -> &str;
+
+    /// Description of the device.
+    fn description(&self) -> String;
+
+    /// Sample the current metrics of the device.
+    ///
+    /// A probe that has no new sample, for example because it is
+    /// still waiting for a check to complete, returns no metrics.
+    fn sample(&mut self) -> Result<HashMap<String, Metric>, Error>;
+}
+
+/// Runner of probes, producing device updates from the samples.
+///
+/// Each device is added the first time its probe is sampled
+/// successfully. After that, each sample is published as a status
+/// update of the device. All updates are versioned and signed by the
+/// agent.
+pub struct Prober {
+    signer: Arc<Signer>,
+    clock: SharedClock,
+    probes: Vec<Box<dyn Probe>>,
+    added: HashSet<String>,
+}
+
+impl Prober {
+    pub fn new(signer: Arc<Signer>, clock: SharedClock) -> Prober {
+        Prober {
+            signer,
+            clock,
+            probes: Vec::new(),
+            added: HashSet::new(),
+        }
+    }
+
+    /// Add a probe to the prober.
+    pub fn add(&mut self, probe: Box<dyn Probe>) {
+        info!("Probing device {}: {}", probe.name(), probe.description());
+        self.probes.push(probe);
+    }
+
+    /// Replace all probes of the prober.
+    ///
+    /// A new probe with the same name as a current probe takes over
+    /// the device of the current probe. Devices that are no longer
+    /// probed are removed, and the removals are returned as updates
+    /// to publish.
+    pub fn replace(&mut self, probes: Vec<Box<dyn Probe>>) -> Vec<Action> {
+        let names: HashSet<String> = probes
+            .iter()
+            .map(|probe| probe.name().to_string())
+            .collect();
+        let mut actions = Vec::new();
+        for name in self.added.iter().filter(|name| !names.contains(*name)) {
+            info!("No longer probing device {}", name);
+            let mut update = DeviceUpdate::DeviceRemoved {
+                origin: *self.signer.uuid(),
+                name: name.clone(),
+                version: self.clock.lock().expect("unable to lock clock").tick(),
+                signature: None,
+            };
+            self.signer.sign(&mut update);
+            actions.push(Action::Publish(update));
+        }
+        self.added.retain(|name| names.contains(name));
+        self.probes.clear();
+        for probe in probes {
+            self.add(probe);
+        }
+        actions
+    }
+
+    /// Number of probes.
+    pub fn len(&self) -> usize {
+        self.probes.len()
+    }
+
+    /// Check if there are no probes.
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty()
+    }
+
+    /// Sample all probes and return the device updates to publish.
+    ///
+    /// Probes that fail are logged and skipped, so that a failing
+    /// probe does not stop other devices from being monitored.
+    pub fn tick(&mut self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for probe in self.probes.iter_mut() {
+            let metrics = match probe.sample() {
+                Ok(metrics) => metrics,
+                Err(err) => {
+                    warn!("Unable to probe device {}: {}", probe.name(), err);
+                    continue;
+                }
+            };
+            if metrics.is_empty() {
+                continue;
+            }
+
+            if !self.added.contains(probe.name()) {
+                let mut update = DeviceUpdate::DeviceAdded {
+                    origin: *self.signer.uuid(),
+                    name: probe.name().to_string(),
+                    description: probe.description(),
+                    version: self.clock.lock().expect("unable to lock clock").tick(),
+                    signature: None,
+                };
+                self.signer.sign(&mut update);
+                actions.push(Action::Publish(update));
+                self.added.insert(probe.name().to_string());
+            }
+
+            let mut update = DeviceUpdate::DeviceStatus {
+                origin: *self.signer.uuid(),
+                name: probe.name().to_string(),
+                metrics,
+                version: self.clock.lock().expect("unable to lock clock").tick(),
+                signatures: HashMap::new(),
+            };
+            self.signer.sign(&mut update);
+            actions.push(Action::Publish(update));
+        }
+        actions
+    }
+}
@@ -0,0 +1,516 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Probes running external check commands.
+//!
+//! Each command probe runs a shell command periodically in a thread
+//! of its own, so that slow commands do not delay other probes, and
+//! parses the output of the command into metrics. Two output formats
+//! are supported:
+//!
+//! * `nagios`: the output of a Nagios plugin. The exit code gives the
+//!   status of the device and the performance data following `|` in
+//!   the output gives the metrics.
+//!
+//! * `json`: a JSON object with a metric for each member. Plain JSON
+//!   values are converted to the corresponding metric, while objects
+//!   are read as typed metrics, such as `{"Float": 1.5}`.
+
+use super::Probe;
+use crate::devices::Metric;
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Default time that a command may run before it is killed.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Names of the Nagios plugin return codes.
+const NAGIOS_STATUS: [&str; 4] = ["OK", "WARNING", "CRITICAL", "UNKNOWN"];
+
+/// Format of the output of a check command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Output of a Nagios plugin.
+    Nagios,
+
+    /// JSON object with a metric for each member.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nagios" => Ok(OutputFormat::Nagios),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::ProbeError(format!(
+                "unknown output format '{}', expected nagios or json",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OutputFormat::Nagios => write!(f, "nagios"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Result of the last run of the command that is not yet sampled.
+type Latest = Arc<Mutex<Option<Result<HashMap<String, Metric>, Error>>>>;
+
+/// Probe running an external check command.
+///
+/// The command is run using `/bin/sh`, so it can contain arguments
+/// and quotes. Each result is only sampled once, so if the command
+/// has not completed since the last sample, nothing is reported.
+pub struct CommandProbe {
+    name: String,
+    command: String,
+    format: OutputFormat,
+    interval: Duration,
+    timeout: Duration,
+    latest: Latest,
+    started: bool,
+
+    /// Flag telling the thread running the command to stop.
+    stopped: Arc<AtomicBool>,
+}
+
+impl CommandProbe {
+    /// Construct a probe running a command.
+    ///
+    /// # Parameters
+    ///
+    /// * `name` - Name of the device that the command checks.
+    ///
+    /// * `command` - Shell command to run.
+    ///
+    /// * `format` - Format of the output of the command.
+    ///
+    /// * `interval` - Time between runs of the command.
+    ///
+    /// * `timeout` - Time that the command may run before it is
+    ///   killed.
+    ///
+    pub fn new(
+        name: &str,
+        command: &str,
+        format: OutputFormat,
+        interval: Duration,
+        timeout: Duration,
+    ) -> CommandProbe {
+        CommandProbe {
+            name: name.to_string(),
+            command: command.to_string(),
+            format,
+            interval,
+            timeout,
+            latest: Arc::new(Mutex::new(None)),
+            started: false,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Start the thread running the command.
+    fn start(&mut self) -> Result<(), Error> {
+        let mut runner = Runner {
+            command: self.command.clone(),
+            format: self.format,
+            timeout: self.timeout,
+            counters: HashMap::new(),
+            started: Utc::now(),
+        };
+        let interval = self.interval;
+        let latest = self.latest.clone();
+        let stopped = self.stopped.clone();
+        thread::Builder::new()
+            .name(format!("probe {}", self.name))
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let result = runner.run();
+                    *latest.lock().expect("unable to lock command result") = Some(result);
+                    thread::sleep(interval);
+                }
+            })?;
+        self.started = true;
+        Ok(())
+    }
+}
+
+impl Probe for CommandProbe {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> String {
+        format!("Command {}", self.command)
+    }
+
+    fn sample(&mut self) -> Result<HashMap<String, Metric>, Error> {
+        if !self.started {
+            self.start()?;
+        }
+        self.latest
+            .lock()
+            .expect("unable to lock command result")
+            .take()
+            .unwrap_or_else(|| Ok(HashMap::new()))
+    }
+}
+
+impl Drop for CommandProbe {
+    /// Stop the thread running the command. A command that is running
+    /// is allowed to complete.
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// State of the thread running a command.
+struct Runner {
+    command: String,
+    format: OutputFormat,
+    timeout: Duration,
+
+    /// Last value and start of each counter, used to detect that a
+    /// counter was reset.
+    counters: HashMap<String, (u64, DateTime<Utc>)>,
+
+    /// Time that the probe started, used as start of counters.
+    started: DateTime<Utc>,
+}
+
+impl Runner {
+    /// Run the command and parse the output.
+    fn run(&mut self) -> Result<HashMap<String, Metric>, Error> {
+        let (code, output) = self.execute()?;
+        match self.format {
+            OutputFormat::Nagios => {
+                let mut metrics = parse_nagios(code, &output)?;
+                self.track_counters(&mut metrics);
+                Ok(metrics)
+            }
+            OutputFormat::Json => {
+                if code != 0 {
+                    return Err(Error::ProbeError(format!(
+                        "command exited with code {}",
+                        code
+                    )));
+                }
+                parse_json(&output)
+            }
+        }
+    }
+
+    /// Execute the command and return the exit code and the output.
+    ///
+    /// The output is read in a separate thread, so that the command
+    /// can be killed if it does not complete in time. The command runs
+    /// in a process group of its own, and the whole group is killed,
+    /// so that processes started by the shell do not keep the output
+    /// open and the reading thread can be joined.
+    fn execute(&self) -> Result<(i32, String), Error> {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()?;
+        let mut stdout = child.stdout.take().expect("stdout of command is piped");
+        let (sender, receiver) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            let mut output = String::new();
+            let _ = sender.send(stdout.read_to_string(&mut output).map(|_| output));
+        });
+        match receiver.recv_timeout(self.timeout) {
+            Ok(output) => {
+                let output = output?;
+                let status = child.wait()?;
+                let code = status.code().ok_or_else(|| {
+                    Error::ProbeError("command was killed by a signal".to_string())
+                })?;
+                Ok((code, output))
+            }
+            Err(_) => {
+                // The process group has the same id as the shell.
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                let _ = child.wait();
+                let _ = reader.join();
+                Err(Error::ProbeError(format!(
+                    "command did not complete in {} seconds",
+                    self.timeout.as_secs()
+                )))
+            }
+        }
+    }
+
+    /// Give the counters a start time. Nagios plugins only report the
+    /// value of counters, so a counter that decreased is assumed to
+    /// have been reset.
+    fn track_counters(&mut self, metrics: &mut HashMap<String, Metric>) {
+        for (name, metric) in metrics.iter_mut() {
+            if let Metric::Counter { value, start } = metric {
+                let started = self.started;
+                let entry = self
+                    .counters
+                    .entry(name.clone())
+                    .or_insert((*value, started));
+                if *value < entry.0 {
+                    entry.1 = Utc::now();
+                }
+                entry.0 = *value;
+                *start = entry.1;
+            }
+        }
+    }
+}
+
+/// Parse the output of a Nagios plugin.
+///
+/// The status of the device is given by the exit code, and the first
+/// line of the output up to the performance data is the status text.
+/// Performance data can also follow `|` on a later line and then
+/// continues to the end of the output.
+fn parse_nagios(code: i32, output: &str) -> Result<HashMap<String, Metric>, Error> {
+    let status = usize::try_from(code)
+        .ok()
+        .and_then(|code| NAGIOS_STATUS.get(code))
+        .ok_or_else(|| Error::ProbeError(format!("unknown Nagios return code {}", code)))?;
+
+    let mut lines = output.lines();
+    let first = lines.next().unwrap_or("");
+    let (text, mut perfdata) = match first.split_once('|') {
+        Some((text, perfdata)) => (text.trim(), perfdata.to_string()),
+        None => (first.trim(), String::new()),
+    };
+    let mut in_perfdata = false;
+    for line in lines {
+        if in_perfdata {
+            perfdata.push(' ');
+            perfdata.push_str(line);
+        } else if let Some((_, rest)) = line.split_once('|') {
+            in_perfdata = true;
+            perfdata.push(' ');
+            perfdata.push_str(rest);
+        }
+    }
+
+    let mut metrics = HashMap::new();
+    metrics.insert("status".to_string(), Metric::Text(status.to_string()));
+    metrics.insert("status_code".to_string(), Metric::Integer(i64::from(code)));
+    metrics.insert("output".to_string(), Metric::Text(text.to_string()));
+    for (label, value) in parse_perfdata(&perfdata)? {
+        if let Some(metric) = perfdata_metric(&value) {
+            metrics.insert(label, metric);
+        }
+    }
+    Ok(metrics)
+}
+
+/// Split performance data into labels and values.
+///
+/// Each item has the form `label=value[UOM];[warn];[crit];[min];[max]`
+/// and items are separated by whitespace. Labels containing
+/// whitespace or `=` are quoted with `'`, and a quote in a quoted
+/// label is written as `''`.
+fn parse_perfdata(perfdata: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut items = Vec::new();
+    let mut chars = perfdata.trim().chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut label = String::new();
+        if chars.peek() == Some(&'\'') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('\'') if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        label.push('\'');
+                    }
+                    Some('\'') => break,
+                    Some(c) => label.push(c),
+                    None => {
+                        return Err(Error::ProbeError(format!(
+                            "unterminated label in performance data: {}",
+                            perfdata
+                        )))
+                    }
+                }
+            }
+            if chars.next() != Some('=') {
+                return Err(Error::ProbeError(format!(
+                    "missing value for {} in performance data",
+                    label
+                )));
+            }
+        } else {
+            loop {
+                match chars.next() {
+                    Some('=') => break,
+                    Some(c) if !c.is_whitespace() => label.push(c),
+                    _ => {
+                        return Err(Error::ProbeError(format!(
+                            "missing value for {} in performance data",
+                            label
+                        )))
+                    }
+                }
+            }
+        }
+
+        let mut value = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            value.push(c);
+        }
+        items.push((label, value));
+    }
+    Ok(items)
+}
+
+/// Convert a performance data value to a metric, based on the unit of
+/// measurement. Thresholds and ranges are ignored, as are values that
+/// are unknown (`U`).
+fn perfdata_metric(value: &str) -> Option<Metric> {
+    let value = value.split(';').next().unwrap_or("");
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let bytes = |scale: f64| Metric::Integer((number * scale) as i64);
+    match unit {
+        "s" | "ms" | "us" if number >= 0.0 => {
+            let scale = match unit {
+                "s" => 1.0,
+                "ms" => 1e-3,
+                _ => 1e-6,
+            };
+            Some(Metric::Duration(Duration::from_secs_f64(number * scale)))
+        }
+        "B" => Some(bytes(1.0)),
+        "KB" => Some(bytes(1024.0)),
+        "MB" => Some(bytes(1024.0 * 1024.0)),
+        "GB" => Some(bytes(1024.0 * 1024.0 * 1024.0)),
+        "TB" => Some(bytes(1024.0 * 1024.0 * 1024.0 * 1024.0)),
+        // The start of the counter is filled in when the counters
+        // are tracked.
+        "c" if number >= 0.0 => Some(Metric::Counter {
+            value: number as u64,
+            start: Utc::now(),
+        }),
+        _ => Some(Metric::Float(number)),
+    }
+}
+
+/// Parse a JSON object into metrics.
+///
+/// Members that cannot be converted to a metric are logged and
+/// ignored.
+fn parse_json(output: &str) -> Result<HashMap<String, Metric>, Error> {
+    let object = match serde_json::from_str::<serde_json::Value>(output) {
+        Ok(serde_json::Value::Object(object)) => object,
+        Ok(_) => return Err(Error::ProbeError("output is not a JSON object".to_string())),
+        Err(err) => return Err(Error::ProbeError(format!("invalid JSON output: {}", err))),
+    };
+    let mut metrics = HashMap::new();
+    for (name, value) in object {
+        match Metric::from_json(value) {
+            Some(metric) => {
+                metrics.insert(name, metric);
+            }
+            None => warn!("Ignoring metric {} with invalid value", name),
+        }
+    }
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn runner(command: &str, timeout: Duration) -> Runner {
+        Runner {
+            command: command.to_string(),
+            format: OutputFormat::Json,
+            timeout,
+            counters: HashMap::new(),
+            started: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn returns_output_and_exit_code() {
+        let (code, output) = runner("echo hello; exit 2", DEFAULT_TIMEOUT)
+            .execute()
+            .unwrap();
+        assert_eq!(code, 2);
+        assert_eq!(output, "hello\n");
+    }
+
+    #[test]
+    fn kills_processes_started_by_command_on_timeout() {
+        let path = std::env::temp_dir().join(format!("chatter-test-{}.pid", uuid::Uuid::new_v4()));
+        let command = format!("sleep 30 & echo $! > {}; wait", path.display());
+        let started = Instant::now();
+        let result = runner(&command, Duration::from_millis(500)).execute();
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let pid: libc::pid_t = std::fs::read_to_string(&path)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // The killed process may linger until it is reaped.
+        let stat = format!("/proc/{}/stat", pid);
+        let alive = || {
+            std::fs::read_to_string(&stat)
+                .map(|stat| !stat.contains(") Z "))
+                .unwrap_or(false)
+        };
+        while alive() && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!alive());
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Probes for the devices of the machine, using the information in
+//! `/proc` and `/sys` on Linux.
+//!
+//! Counters in `/proc` count from when the machine booted, so the
+//! boot time is used as start of the counters.
+
+use super::Probe;
+use crate::devices::Metric;
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Clock ticks per second used when the system does not tell.
+const DEFAULT_CLOCK_TICKS: u64 = 100;
+
+/// Network interfaces that are not probed.
+const IGNORED_INTERFACES: &[&str] = &["lo"];
+
+/// Read the time that the machine booted from `/proc/stat`.
+fn boot_time() -> Result<DateTime<Utc>, Error> {
+    fs::read_to_string("/proc/stat")?
+        .lines()
+        .filter_map(|line| line.strip_prefix("btime "))
+        .filter_map(|secs| secs.trim().parse::<i64>().ok())
+        .find_map(|secs| DateTime::from_timestamp(secs, 0))
+        .ok_or_else(|| Error::ProbeError("no boot time in /proc/stat".to_string()))
+}
+
+/// Parse a whitespace separated list of numbers.
+fn parse_numbers(text: &str) -> Vec<u64> {
+    text.split_whitespace()
+        .map_while(|field| field.parse::<u64>().ok())
+        .collect()
+}
+
+/// Probe for the CPU of the machine.
+///
+/// The CPU time is reported as counters in milliseconds, together
+/// with the load averages and the uptime of the machine.
+pub struct CpuProbe {
+    description: String,
+    boot: DateTime<Utc>,
+    millis_per_tick: u64,
+}
+
+impl CpuProbe {
+    pub fn new() -> Result<CpuProbe, Error> {
+        let cpuinfo = fs::read_to_string("/proc/cpuinfo")?;
+        let models: Vec<&str> = cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("model name"))
+            .filter_map(|line| line.split(':').nth(1))
+            .map(str::trim)
+            .collect();
+        let description = match models.first() {
+            Some(model) => format!("{} ({} CPUs)", model, models.len()),
+            None => "CPU".to_string(),
+        };
+
+        // Safety: sysconf has no preconditions.
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let ticks = if ticks > 0 {
+            ticks as u64
+        } else {
+            DEFAULT_CLOCK_TICKS
+        };
+        Ok(CpuProbe {
+            description,
+            boot: boot_time()?,
+            millis_per_tick: 1000 / ticks,
+        })
+    }
+}
+
+impl Probe for CpuProbe {
+    fn name(&self) -> &str {
+        "cpu"
+    }
+
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+
+    fn sample(&mut self) -> Result<HashMap<String, Metric>, Error> {
+        let mut metrics = HashMap::new();
+
+        // The load averages are followed by the number of running and
+        // total processes, separated by a slash.
+        let loadavg = fs::read_to_string("/proc/loadavg")?;
+        let fields: Vec<&str> = loadavg.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(Error::ProbeError(format!(
+                "unexpected format of /proc/loadavg: {}",
+                loadavg.trim()
+            )));
+        }
+        for (name, field) in ["load1", "load5", "load15"].iter().zip(&fields) {
+            if let Ok(load) = field.parse::<f64>() {
+                metrics.insert(name.to_string(), Metric::Float(load));
+            }
+        }
+        if let Some((running, total)) = fields[3].split_once('/') {
+            if let (Ok(running), Ok(total)) = (running.parse::<i64>(), total.parse::<i64>()) {
+                metrics.insert("processes_running".to_string(), Metric::Integer(running));
+                metrics.insert("processes".to_string(), Metric::Integer(total));
+            }
+        }
+
+        // The first line of /proc/stat has the total CPU time in each
+        // mode, in clock ticks.
+        let stat = fs::read_to_string("/proc/stat")?;
+        let times = stat
+            .lines()
+            .find_map(|line| line.strip_prefix("cpu "))
+            .map(parse_numbers)
+            .ok_or_else(|| Error::ProbeError("no CPU times in /proc/stat".to_string()))?;
+        let modes = [
+            "user", "nice", "system", "idle", "iowait", "irq", "softirq", "steal",
+        ];
+        for (mode, ticks) in modes.iter().zip(times) {
+            metrics.insert(
+                format!("{}_ms", mode),
+                Metric::Counter {
+                    value: ticks * self.millis_per_tick,
+                    start: self.boot,
+                },
+            );
+        }
+
+        let uptime = fs::read_to_string("/proc/uptime")?;
+        if let Some(secs) = uptime
+            .split_whitespace()
+            .next()
+            .and_then(|secs| secs.parse::<f64>().ok())
+        {
+            metrics.insert(
+                "uptime".to_string(),
+                Metric::Duration(Duration::from_secs_f64(secs)),
+            );
+        }
+        Ok(metrics)
+    }
+}
+
+/// Probe for the memory of the machine.
+///
+/// All sizes are reported in bytes.
+pub struct MemoryProbe;
+
+impl MemoryProbe {
+    pub fn new() -> MemoryProbe {
+        MemoryProbe
+    }
+}
+
+impl Default for MemoryProbe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Probe for MemoryProbe {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn description(&self) -> String {
+        "Memory".to_string()
+    }
+
+    fn sample(&mut self) -> Result<HashMap<String, Metric>, Error> {
+        let fields = [
+            ("MemTotal", "total"),
+            ("MemFree", "free"),
+            ("MemAvailable", "available"),
+            ("Buffers", "buffers"),
+            ("Cached", "cached"),
+            ("SwapTotal", "swap_total"),
+            ("SwapFree", "swap_free"),
+        ];
+        let meminfo = fs::read_to_string("/proc/meminfo")?;
+        let mut metrics = HashMap::new();
+        for line in meminfo.lines() {
+            let (key, rest) = match line.split_once(':') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let name = match fields.iter().find(|(field, _)| *field == key) {
+                Some((_, name)) => name,
+                None => continue,
+            };
+            // Sizes are given in kibibytes, even though the unit says
+            // kB.
+            if let Some(kib) = parse_numbers(rest).first() {
+                metrics.insert(name.to_string(), Metric::Integer((kib * 1024) as i64));
+            }
+        }
+        if metrics.is_empty() {
+            return Err(Error::ProbeError(
+                "no memory information in /proc/meminfo".to_string(),
+            ));
+        }
+        Ok(metrics)
+    }
+}
+
+/// Probe for a file system.
+///
+/// The size of the file system and the space used and available are
+/// reported in bytes, together with the number of inodes.
+pub struct DiskProbe {
+    name: String,
+    path: PathBuf,
+}
+
+impl DiskProbe {
+    /// Construct a probe for the file system that the path is on,
+    /// usually the mount point of the file system.
+    pub fn new(path: &Path) -> DiskProbe {
+        DiskProbe {
+            name: format!("disk:{}", path.display()),
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+/// Get the statistics of the file system that a path is on.
+fn statvfs(path: &Path) -> Result<libc::statvfs, Error> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::ProbeError(format!("invalid path {}", path.display())))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: the path is a valid C string and the buffer is large
+    // enough for the statistics.
+    if unsafe { libc::statvfs(cpath.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Safety: statvfs succeeded, so the statistics are written.
+    Ok(unsafe { stat.assume_init() })
+}
+
+impl Probe for DiskProbe {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> String {
+        format!("File system on {}", self.path.display())
+    }
+
+    // The types of the fields of statvfs differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    fn sample(&mut self) -> Result<HashMap<String, Metric>, Error> {
+        let stat = statvfs(&self.path)?;
+        let block_size = stat.f_frsize as u64;
+        let total = stat.f_blocks as u64 * block_size;
+        let free = stat.f_bfree as u64 * block_size;
+        let available = stat.f_bavail as u64 * block_size;
+
+        let mut metrics = HashMap::new();
+        metrics.insert("total".to_string(), Metric::Integer(total as i64));
+        metrics.insert("used".to_string(), Metric::Integer((total - free) as i64));
+        metrics.insert("available".to_string(), Metric::Integer(available as i64));
+        if total > 0 {
+            metrics.insert(
+                "used_percent".to_string(),
+                Metric::Float(100.0 * (total - free) as f64 / total as f64),
+            );
+        }
+        metrics.insert("inodes".to_string(), Metric::Integer(stat.f_files as i64));
+        metrics.insert(
+            "inodes_free".to_string(),
+            Metric::Integer(stat.f_ffree as i64),
+        );
+        Ok(metrics)
+    }
+}
+
+/// Probe for a network interface.
+///
+/// The traffic of the interface is reported as counters, together
+/// with whether the interface is up.
+pub struct NetworkProbe {
+    name: String,
+    interface: String,
+    boot: DateTime<Utc>,
+}
+
+impl NetworkProbe {
+    pub fn new(interface: &str) -> Result<NetworkProbe, Error> {
+        Ok(NetworkProbe {
+            name: format!("net:{}", interface),
+            interface: interface.to_string(),
+            boot: boot_time()?,
+        })
+    }
+
+    /// Get the names of the network interfaces of the machine,
+    /// except the loopback interface.
+    pub fn interfaces() -> Result<Vec<String>, Error> {
+        Ok(fs::read_to_string("/proc/net/dev")?
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(interface, _)| interface.trim().to_string())
+            .filter(|interface| !IGNORED_INTERFACES.contains(&interface.as_str()))
+            .collect())
+    }
+}
+
+impl Probe for NetworkProbe {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> String {
+        format!("Network interface {}", self.interface)
+    }
+
+    fn sample(&mut self) -> Result<HashMap<String, Metric>, Error> {
+        let dev = fs::read_to_string("/proc/net/dev")?;
+        let counters = dev
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(interface, _)| interface.trim() == self.interface)
+            .map(|(_, rest)| parse_numbers(rest))
+            .ok_or_else(|| {
+                Error::ProbeError(format!("no interface {} in /proc/net/dev", self.interface))
+            })?;
+
+        // Position of the counters in /proc/net/dev.
+        let fields = [
+            (0, "rx_bytes"),
+            (1, "rx_packets"),
+            (2, "rx_errors"),
+            (3, "rx_dropped"),
+            (8, "tx_bytes"),
+            (9, "tx_packets"),
+            (10, "tx_errors"),
+            (11, "tx_dropped"),
+        ];
+        let mut metrics = HashMap::new();
+        for (pos, name) in fields.iter() {
+            if let Some(value) = counters.get(*pos) {
+                metrics.insert(
+                    name.to_string(),
+                    Metric::Counter {
+                        value: *value,
+                        start: self.boot,
+                    },
+                );
+            }
+        }
+
+        let operstate = Path::new("/sys/class/net")
+            .join(&self.interface)
+            .join("operstate");
+        if let Ok(state) = fs::read_to_string(operstate) {
+            metrics.insert("up".to_string(), Metric::Boolean(state.trim() == "up"));
+        }
+        Ok(metrics)
+    }
+}
+
+/// Construct probes for the CPU, memory, and network interfaces of
+/// the machine, and for the file systems that the given paths are on.
+pub fn probes(disks: &[PathBuf]) -> Result<Vec<Box<dyn Probe>>, Error> {
+    let mut probes: Vec<Box<dyn Probe>> =
+        vec![Box::new(CpuProbe::new()?), Box::new(MemoryProbe::new())];
+    for path in disks {
+        probes.push(Box::new(DiskProbe::new(path)));
+    }
+    for interface in NetworkProbe::interfaces()? {
+        probes.push(Box::new(NetworkProbe::new(&interface)?));
+    }
+    Ok(probes)
+}
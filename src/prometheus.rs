@@ -0,0 +1,474 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for exporting metrics in the Prometheus text format.
+//!
+//! Every numeric metric of the devices known to the agent is exported
+//! as a series labeled with the owner and name of the device, so that
+//! any agent can serve as a scrape target for the whole cluster. The
+//! name of the series is the name of the metric prefixed with
+//! `chatter_`, with characters not allowed by Prometheus replaced by
+//! `_`. Text metrics are not exported.
+//!
+//! The counters of the agent itself are exported with the prefix
+//! `chatterd_`.
+
+use crate::devices::Metric;
+use crate::state::State;
+use crate::view::ServerStatus;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Content type of the text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Prefix of the series exported for device metrics.
+const DEVICE_PREFIX: &str = "chatter_";
+
+/// Prefix of the series exported for the counters of the agent.
+const AGENT_PREFIX: &str = "chatterd_";
+
+/// Type of a metric family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+            Kind::Summary => "summary",
+        }
+    }
+}
+
+/// Series sharing a name and type.
+struct Family {
+    kind: Kind,
+    help: String,
+    samples: Vec<String>,
+}
+
+impl Family {
+    fn new(kind: Kind, help: String) -> Family {
+        Family {
+            kind,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut sample = name.to_string();
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+                .collect();
+            write!(sample, "{{{}}}", labels.join(",")).expect("writing to a string cannot fail");
+        }
+        write!(sample, " {}", format_value(value)).expect("writing to a string cannot fail");
+        self.samples.push(sample);
+    }
+}
+
+/// Metric families, ordered by name.
+#[derive(Default)]
+struct Families {
+    families: BTreeMap<String, Family>,
+}
+
+impl Families {
+    /// Get the family with a name, creating it if it does not exist.
+    /// Returns `None` if the family exists with another type.
+    fn family(
+        &mut self,
+        name: &str,
+        kind: Kind,
+        help: impl FnOnce() -> String,
+    ) -> Option<&mut Family> {
+        let family = self
+            .families
+            .entry(name.to_string())
+            .or_insert_with(|| Family::new(kind, help()));
+        if family.kind == kind {
+            Some(family)
+        } else {
+            None
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, family) in &self.families {
+            writeln!(text, "# HELP {} {}", name, escape_help(&family.help))
+                .and_then(|_| writeln!(text, "# TYPE {} {}", name, family.kind.as_str()))
+                .expect("writing to a string cannot fail");
+            for sample in &family.samples {
+                text.push_str(sample);
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
+
+/// Render the metrics of all devices and the counters of the agent.
+pub fn render(state: &State) -> String {
+    let mut families = Families::default();
+    add_devices(&mut families, state);
+    add_agent(&mut families, state);
+    families.render()
+}
+
+fn add_devices(families: &mut Families, state: &State) {
+    let collection = state
+        .devices
+        .lock()
+        .expect("unable to lock device collection for metrics");
+    let mut devices: Vec<_> = collection.iter().collect();
+    devices.sort_by(|a, b| (a.owner, &a.name).cmp(&(b.owner, &b.name)));
+    for info in devices {
+        let owner = info.owner.to_string();
+        let mut metrics: Vec<_> = info.metrics.iter().collect();
+        metrics.sort_by(|a, b| a.0.cmp(b.0));
+        for (metric, entry) in metrics {
+            let labels = [("owner", owner.as_str()), ("device", info.name.as_str())];
+            if !add_metric(families, metric, &entry.value, &labels) {
+                debug!(
+                    "Not exporting metric {} of device {} owned by {}",
+                    metric, info.name, info.owner
+                );
+            }
+        }
+    }
+}
+
+/// Add a device metric to its family. Returns `false` if the metric
+/// cannot be exported, either because it is not numeric or because a
+/// metric of another type has the same name.
+fn add_metric(
+    families: &mut Families,
+    metric: &str,
+    value: &Metric,
+    labels: &[(&str, &str)],
+) -> bool {
+    let base = format!("{}{}", DEVICE_PREFIX, sanitize(metric));
+    let help = || format!("Device metric {}.", metric);
+    match value {
+        Metric::Text(_) => false,
+
+        Metric::Counter { value, .. } => {
+            let name = if base.ends_with("_total") {
+                base
+            } else {
+                format!("{}_total", base)
+            };
+            match families.family(&name, Kind::Counter, help) {
+                Some(family) => {
+                    family.add(&name, labels, *value as f64);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        Metric::Histogram(histogram) => match families.family(&base, Kind::Histogram, help) {
+            Some(family) => {
+                let bucket = format!("{}_bucket", base);
+                let mut cumulative = 0;
+                for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+                    cumulative += count;
+                    let le = format_value(*bound);
+                    family.add(&bucket, &with_label(labels, "le", &le), cumulative as f64);
+                }
+                family.add(
+                    &bucket,
+                    &with_label(labels, "le", "+Inf"),
+                    histogram.count as f64,
+                );
+                family.add(&format!("{}_sum", base), labels, histogram.sum);
+                family.add(&format!("{}_count", base), labels, histogram.count as f64);
+                true
+            }
+            None => false,
+        },
+
+        Metric::Summary(summary) => match families.family(&base, Kind::Summary, help) {
+            Some(family) => {
+                for quantile in &summary.quantiles {
+                    let q = format_value(quantile.quantile);
+                    family.add(&base, &with_label(labels, "quantile", &q), quantile.value);
+                }
+                family.add(&format!("{}_sum", base), labels, summary.sum);
+                family.add(&format!("{}_count", base), labels, summary.count as f64);
+                true
+            }
+            None => false,
+        },
+
+        _ => match (value.as_f64(), families.family(&base, Kind::Gauge, help)) {
+            (Some(number), Some(family)) => {
+                family.add(&base, labels, number);
+                true
+            }
+            _ => false,
+        },
+    }
+}
+
+fn add_agent(families: &mut Families, state: &State) {
+    let stats = &state.stats;
+    let counters = [
+        (
+            "messages_received_total",
+            "Number of messages received.",
+            stats.received(),
+        ),
+        (
+            "messages_forwarded_total",
+            "Number of messages forwarded to other servers.",
+            stats.forwarded(),
+        ),
+        (
+            "messages_dropped_total",
+            "Number of messages dropped because they were already seen.",
+            stats.duplicates(),
+        ),
+        (
+            "decode_errors_total",
+            "Number of datagrams rejected because they could not be decoded.",
+            stats.rejected(),
+        ),
+    ];
+    for (name, help, value) in counters.iter() {
+        let name = format!("{}{}", AGENT_PREFIX, name);
+        if let Some(family) = families.family(&name, Kind::Counter, || help.to_string()) {
+            family.add(&name, &[], *value as f64);
+        }
+    }
+
+    let view = state.view.lock().expect("unable to lock view for metrics");
+    let name = format!("{}view_size", AGENT_PREFIX);
+    if let Some(family) = families.family(&name, Kind::Gauge, || {
+        "Number of servers in the view, by status.".to_string()
+    }) {
+        let statuses = [
+            ServerStatus::Alive,
+            ServerStatus::Suspect,
+            ServerStatus::Dead,
+            ServerStatus::Left,
+        ];
+        for status in statuses.iter() {
+            let count = view
+                .servers
+                .values()
+                .filter(|info| info.status == *status)
+                .count();
+            family.add(&name, &[("status", &status.to_string())], count as f64);
+        }
+    }
+}
+
+fn with_label<'a>(
+    labels: &[(&'a str, &'a str)],
+    label: &'a str,
+    value: &'a str,
+) -> Vec<(&'a str, &'a str)> {
+    let mut labels = labels.to_vec();
+    labels.push((label, value));
+    labels
+}
+
+/// Replace characters that are not allowed in a metric name.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Histogram, Quantile, Summary};
+    use chrono::{TimeZone, Utc};
+
+    const LABELS: [(&str, &str); 2] = [("owner", "o"), ("device", "d")];
+
+    fn rendered(metrics: &[(&str, Metric)]) -> Vec<String> {
+        let mut families = Families::default();
+        for (name, value) in metrics {
+            add_metric(&mut families, name, value, &LABELS);
+        }
+        families.render().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn counters_are_named_total() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let lines = rendered(&[
+            ("requests", Metric::Counter { value: 7, start }),
+            ("errors_total", Metric::Counter { value: 2, start }),
+        ]);
+        assert_eq!(
+            lines,
+            vec![
+                "# HELP chatter_errors_total Device metric errors_total.",
+                "# TYPE chatter_errors_total counter",
+                "chatter_errors_total{owner=\"o\",device=\"d\"} 2",
+                "# HELP chatter_requests_total Device metric requests.",
+                "# TYPE chatter_requests_total counter",
+                "chatter_requests_total{owner=\"o\",device=\"d\"} 7",
+            ]
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram {
+            bounds: vec![0.1, 1.0],
+            counts: vec![3, 4, 2],
+            sum: 12.5,
+            count: 9,
+            start: Utc.timestamp_opt(0, 0).unwrap(),
+        };
+        let lines = rendered(&[("latency", Metric::Histogram(histogram))]);
+        assert_eq!(
+            lines[1..],
+            [
+                "# TYPE chatter_latency histogram",
+                "chatter_latency_bucket{owner=\"o\",device=\"d\",le=\"0.1\"} 3",
+                "chatter_latency_bucket{owner=\"o\",device=\"d\",le=\"1\"} 7",
+                "chatter_latency_bucket{owner=\"o\",device=\"d\",le=\"+Inf\"} 9",
+                "chatter_latency_sum{owner=\"o\",device=\"d\"} 12.5",
+                "chatter_latency_count{owner=\"o\",device=\"d\"} 9",
+            ]
+        );
+    }
+
+    #[test]
+    fn summaries_have_quantile_labels() {
+        let summary = Summary {
+            quantiles: vec![
+                Quantile {
+                    quantile: 0.5,
+                    value: 0.25,
+                },
+                Quantile {
+                    quantile: 0.99,
+                    value: 2.0,
+                },
+            ],
+            sum: 30.0,
+            count: 40,
+            start: Utc.timestamp_opt(0, 0).unwrap(),
+        };
+        let lines = rendered(&[("latency", Metric::Summary(summary))]);
+        assert_eq!(
+            lines[1..],
+            [
+                "# TYPE chatter_latency summary",
+                "chatter_latency{owner=\"o\",device=\"d\",quantile=\"0.5\"} 0.25",
+                "chatter_latency{owner=\"o\",device=\"d\",quantile=\"0.99\"} 2",
+                "chatter_latency_sum{owner=\"o\",device=\"d\"} 30",
+                "chatter_latency_count{owner=\"o\",device=\"d\"} 40",
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_and_help_are_escaped() {
+        let mut families = Families::default();
+        let family = families
+            .family("x", Kind::Gauge, || "back\\slash\nnewline".to_string())
+            .unwrap();
+        family.add("x", &[("device", "a\"b\\c\nd")], 1.0);
+        assert_eq!(
+            families.render(),
+            "# HELP x back\\\\slash\\nnewline\n\
+             # TYPE x gauge\n\
+             x{device=\"a\\\"b\\\\c\\nd\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn metrics_of_conflicting_types_are_skipped() {
+        let mut families = Families::default();
+        assert!(add_metric(
+            &mut families,
+            "disk.used",
+            &Metric::Integer(10),
+            &LABELS
+        ));
+        assert!(!add_metric(
+            &mut families,
+            "disk_used",
+            &Metric::Summary(Summary {
+                quantiles: vec![],
+                sum: 0.0,
+                count: 0,
+                start: Utc.timestamp_opt(0, 0).unwrap(),
+            }),
+            &LABELS
+        ));
+        assert!(!add_metric(
+            &mut families,
+            "name",
+            &Metric::Text("text".to_string()),
+            &LABELS
+        ));
+        assert!(families
+            .family("chatter_disk_used", Kind::Summary, String::new)
+            .is_none());
+        assert_eq!(
+            families.render(),
+            "# HELP chatter_disk_used Device metric disk.used.\n\
+             # TYPE chatter_disk_used gauge\n\
+             chatter_disk_used{owner=\"o\",device=\"d\"} 10\n"
+        );
+    }
+}
@@ -0,0 +1,555 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for signing device updates.
+//!
+//! Each agent has an Ed25519 key pair and signs the device updates and
+//! alert transitions it originates. Instead of signing the update as
+//! a whole, each device, removal, and metric in the update is signed
+//! separately. The signatures are stored together with the state, so
+//! that they can be verified both when the update is relayed by other
+//! agents and when the state is exchanged during anti-entropy.
+//!
+//! Agents verifying updates have a set of trusted public keys, one
+//! for each agent in the cluster, and reject updates that are not
+//! signed by the agent owning the device.
+
+use crate::alert::{AlertState, AlertUpdate};
+use crate::clock::Version;
+use crate::devices::{DeviceUpdate, Metric};
+use crate::error::Error;
+use crate::hex;
+use crate::identity::write_atomically;
+use ed25519_dalek::{SigningKey, Verifier, VerifyingKey, SECRET_KEY_LENGTH};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub use ed25519_dalek::Signature;
+
+/// Name of the file in the data directory holding the signing key.
+pub const NODE_KEY_FILE: &str = "node-key";
+
+/// Extension of files holding public keys. The name of the file is
+/// the UUID of the agent owning the key.
+pub const PUBLIC_KEY_EXTENSION: &str = "pub";
+
+/// Signed part of the state.
+///
+/// The record is serialized to get the bytes that are signed. It
+/// contains the origin so that a signature cannot be reused for
+/// another agent.
+#[derive(Serialize)]
+pub(crate) enum Record<'a> {
+    Device {
+        origin: &'a Uuid,
+        name: &'a str,
+        description: &'a str,
+        version: Version,
+    },
+    Removed {
+        origin: &'a Uuid,
+        name: &'a str,
+        version: Version,
+    },
+    Metric {
+        origin: &'a Uuid,
+        device: &'a str,
+        metric: &'a str,
+        value: &'a Metric,
+        version: Version,
+    },
+    Alert {
+        origin: &'a Uuid,
+        rule: &'a str,
+        device: &'a str,
+        metric: &'a str,
+        state: AlertState,
+        value: f64,
+        version: Version,
+    },
+}
+
+impl<'a> Record<'a> {
+    fn origin(&self) -> &'a Uuid {
+        match self {
+            Record::Device { origin, .. }
+            | Record::Removed { origin, .. }
+            | Record::Metric { origin, .. }
+            | Record::Alert { origin, .. } => origin,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).expect("records can always be serialized")
+    }
+}
+
+/// Signer of device updates originating from this agent.
+pub struct Signer {
+    uuid: Uuid,
+    key: SigningKey,
+}
+
+impl Signer {
+    /// Construct a signer with a newly generated key.
+    pub fn generate(uuid: Uuid) -> Signer {
+        let mut secret = [0; SECRET_KEY_LENGTH];
+        OsRng.fill_bytes(&mut secret);
+        Signer {
+            uuid,
+            key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Load the signing key of the agent from the data directory, or
+    /// generate and store a new key if there is none.
+    ///
+    /// The public key is also stored in the data directory in a file
+    /// named after the UUID of the agent, which can be copied to the
+    /// trusted keys directory of the other agents.
+    pub fn load_or_create(uuid: Uuid, data_dir: &Path) -> Result<Signer, Error> {
+        let path = data_dir.join(NODE_KEY_FILE);
+        let signer = if path.exists() {
+            let bytes = hex::decode(fs::read_to_string(&path)?.trim())
+                .filter(|bytes| bytes.len() == SECRET_KEY_LENGTH)
+                .ok_or_else(|| {
+                    Error::KeyError(format!("invalid signing key in {}", path.display()))
+                })?;
+            let mut secret = [0; SECRET_KEY_LENGTH];
+            secret.copy_from_slice(&bytes);
+            debug!("Loaded signing key from {}", path.display());
+            Signer {
+                uuid,
+                key: SigningKey::from_bytes(&secret),
+            }
+        } else {
+            let signer = Signer::generate(uuid);
+            write_atomically(
+                &path,
+                &format!("{}\n", hex::encode(&signer.key.to_bytes())),
+                0o600,
+            )?;
+            info!("Generated signing key in {}", path.display());
+            signer
+        };
+        write_atomically(
+            &public_key_path(data_dir, &uuid),
+            &format!("{}\n", signer.public_key()),
+            0o644,
+        )?;
+        Ok(signer)
+    }
+
+    /// The UUID of the agent.
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
+    /// The public key of the agent as hexadecimal digits.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// The key used to verify signatures of the agent.
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    fn sign_record(&self, record: &Record) -> Signature {
+        ed25519_dalek::Signer::sign(&self.key, &record.to_bytes())
+    }
+
+    /// Sign a device update.
+    ///
+    /// The update should originate from this agent and have a
+    /// version, since both are covered by the signature.
+    pub fn sign(&self, update: &mut DeviceUpdate) {
+        match update {
+            DeviceUpdate::DeviceAdded {
+                origin,
+                name,
+                description,
+                version,
+                signature,
+            } => {
+                *signature = Some(self.sign_record(&Record::Device {
+                    origin,
+                    name,
+                    description,
+                    version: *version,
+                }));
+            }
+
+            DeviceUpdate::DeviceRemoved {
+                origin,
+                name,
+                version,
+                signature,
+            } => {
+                *signature = Some(self.sign_record(&Record::Removed {
+                    origin,
+                    name,
+                    version: *version,
+                }));
+            }
+
+            DeviceUpdate::DeviceStatus {
+                origin,
+                name,
+                metrics,
+                version,
+                signatures,
+            } => {
+                for (metric, value) in metrics.iter() {
+                    let signature = self.sign_record(&Record::Metric {
+                        origin,
+                        device: name,
+                        metric,
+                        value,
+                        version: *version,
+                    });
+                    signatures.insert(metric.clone(), signature);
+                }
+            }
+        }
+    }
+
+    /// Sign an alert transition.
+    pub fn sign_alert(&self, update: &mut AlertUpdate) {
+        update.signature = Some(self.sign_record(&Record::from(&*update)));
+    }
+}
+
+impl<'a> From<&'a AlertUpdate> for Record<'a> {
+    fn from(update: &'a AlertUpdate) -> Record<'a> {
+        Record::Alert {
+            origin: &update.origin,
+            rule: &update.rule,
+            device: &update.device,
+            metric: &update.metric,
+            state: update.state,
+            value: update.value,
+            version: update.version,
+        }
+    }
+}
+
+/// Path of the file holding the public key of an agent.
+pub fn public_key_path(dir: &Path, uuid: &Uuid) -> PathBuf {
+    dir.join(format!("{}.{}", uuid, PUBLIC_KEY_EXTENSION))
+}
+
+/// Public keys of the agents whose updates are accepted.
+#[derive(Debug, Default, Clone)]
+pub struct TrustedKeys {
+    keys: HashMap<Uuid, VerifyingKey>,
+}
+
+impl TrustedKeys {
+    pub fn new() -> TrustedKeys {
+        TrustedKeys::default()
+    }
+
+    /// Load all public keys in a directory.
+    ///
+    /// Each key is stored as hexadecimal digits in a file named after
+    /// the UUID of the agent with the extension `pub`. Other files
+    /// are ignored.
+    pub fn load(dir: &Path) -> Result<TrustedKeys, Error> {
+        let mut trusted = TrustedKeys::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(PUBLIC_KEY_EXTENSION) {
+                continue;
+            }
+            let uuid = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => Uuid::parse_str(stem)?,
+                None => continue,
+            };
+            let key = hex::decode(fs::read_to_string(&path)?.trim())
+                .and_then(|bytes| VerifyingKey::try_from(bytes.as_slice()).ok())
+                .ok_or_else(|| {
+                    Error::KeyError(format!("invalid public key in {}", path.display()))
+                })?;
+            debug!("Trusting key of {} from {}", uuid, path.display());
+            trusted.insert(uuid, key);
+        }
+        Ok(trusted)
+    }
+
+    /// Trust a key for an agent, replacing any previous key.
+    pub fn insert(&mut self, uuid: Uuid, key: VerifyingKey) {
+        self.keys.insert(uuid, key);
+    }
+
+    /// Number of trusted keys.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Check if there are no trusted keys.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Verify that a record is signed by the agent it originates from.
+    pub(crate) fn verify(
+        &self,
+        record: &Record,
+        signature: Option<&Signature>,
+    ) -> Result<(), Error> {
+        let origin = record.origin();
+        let key = self
+            .keys
+            .get(origin)
+            .ok_or_else(|| Error::AuthenticationError(format!("no trusted key for {}", origin)))?;
+        let signature = signature
+            .ok_or_else(|| Error::AuthenticationError("update is not signed".to_string()))?;
+        key.verify(&record.to_bytes(), signature)
+            .map_err(|_| Error::AuthenticationError(format!("invalid signature for {}", origin)))
+    }
+
+    /// Verify that a device update is signed by the agent it
+    /// originates from.
+    pub fn verify_update(&self, update: &DeviceUpdate) -> Result<(), Error> {
+        if update.version().is_zero() {
+            return Err(Error::AuthenticationError(
+                "update has no version".to_string(),
+            ));
+        }
+        match update {
+            DeviceUpdate::DeviceAdded {
+                origin,
+                name,
+                description,
+                version,
+                signature,
+            } => self.verify(
+                &Record::Device {
+                    origin,
+                    name,
+                    description,
+                    version: *version,
+                },
+                signature.as_ref(),
+            ),
+
+            DeviceUpdate::DeviceRemoved {
+                origin,
+                name,
+                version,
+                signature,
+            } => self.verify(
+                &Record::Removed {
+                    origin,
+                    name,
+                    version: *version,
+                },
+                signature.as_ref(),
+            ),
+
+            DeviceUpdate::DeviceStatus {
+                origin,
+                name,
+                metrics,
+                version,
+                signatures,
+            } => metrics.iter().try_for_each(|(metric, value)| {
+                self.verify(
+                    &Record::Metric {
+                        origin,
+                        device: name,
+                        metric,
+                        value,
+                        version: *version,
+                    },
+                    signatures.get(metric),
+                )
+            }),
+        }
+    }
+
+    /// Verify that an alert transition is signed by the agent it
+    /// originates from.
+    pub fn verify_alert(&self, update: &AlertUpdate) -> Result<(), Error> {
+        if update.version.is_zero() {
+            return Err(Error::AuthenticationError(
+                "alert has no version".to_string(),
+            ));
+        }
+        self.verify(&Record::from(update), update.signature.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusting(signers: &[&Signer]) -> TrustedKeys {
+        let mut trusted_keys = TrustedKeys::new();
+        for signer in signers {
+            trusted_keys.insert(*signer.uuid(), signer.verifying_key());
+        }
+        trusted_keys
+    }
+
+    fn status(origin: Uuid, value: i64) -> DeviceUpdate {
+        let mut metrics = HashMap::new();
+        metrics.insert("used".to_string(), Metric::Integer(value));
+        DeviceUpdate::DeviceStatus {
+            origin,
+            name: "disk".to_string(),
+            metrics,
+            version: Version::new(1000, 1),
+            signatures: HashMap::new(),
+        }
+    }
+
+    fn alert(origin: Uuid) -> AlertUpdate {
+        AlertUpdate {
+            origin,
+            rule: "disk-full".to_string(),
+            device: "disk".to_string(),
+            metric: "used".to_string(),
+            state: AlertState::Firing,
+            value: 95.0,
+            version: Version::new(1000, 1),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn signed_updates_are_verified() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let origin = *signer.uuid();
+        let trusted_keys = trusting(&[&signer]);
+        let mut updates = vec![
+            DeviceUpdate::DeviceAdded {
+                origin,
+                name: "disk".to_string(),
+                description: "Root disk".to_string(),
+                version: Version::new(1000, 1),
+                signature: None,
+            },
+            DeviceUpdate::DeviceRemoved {
+                origin,
+                name: "disk".to_string(),
+                version: Version::new(1000, 2),
+                signature: None,
+            },
+            status(origin, 10),
+        ];
+        for update in &mut updates {
+            assert!(trusted_keys.verify_update(update).is_err());
+            signer.sign(update);
+            trusted_keys.verify_update(update).unwrap();
+        }
+
+        let mut update = alert(origin);
+        assert!(trusted_keys.verify_alert(&update).is_err());
+        signer.sign_alert(&mut update);
+        trusted_keys.verify_alert(&update).unwrap();
+    }
+
+    #[test]
+    fn tampered_updates_are_rejected() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let trusted_keys = trusting(&[&signer]);
+
+        let mut update = status(*signer.uuid(), 10);
+        signer.sign(&mut update);
+        if let DeviceUpdate::DeviceStatus { metrics, .. } = &mut update {
+            metrics.insert("used".to_string(), Metric::Integer(99));
+        }
+        assert!(trusted_keys.verify_update(&update).is_err());
+
+        let mut update = status(*signer.uuid(), 10);
+        signer.sign(&mut update);
+        if let DeviceUpdate::DeviceStatus { version, .. } = &mut update {
+            version.counter += 1;
+        }
+        assert!(trusted_keys.verify_update(&update).is_err());
+
+        let mut update = alert(*signer.uuid());
+        signer.sign_alert(&mut update);
+        update.state = AlertState::Resolved;
+        assert!(trusted_keys.verify_alert(&update).is_err());
+    }
+
+    #[test]
+    fn signatures_of_other_agents_are_rejected() {
+        let owner = Signer::generate(Uuid::new_v4());
+        let other = Signer::generate(Uuid::new_v4());
+        let trusted_keys = trusting(&[&owner, &other]);
+
+        // Signed with the key of another trusted agent.
+        let mut update = status(*owner.uuid(), 10);
+        other.sign(&mut update);
+        assert!(trusted_keys.verify_update(&update).is_err());
+        let mut update = alert(*owner.uuid());
+        other.sign_alert(&mut update);
+        assert!(trusted_keys.verify_alert(&update).is_err());
+
+        // A signature cannot be reused for another origin.
+        let mut update = status(*other.uuid(), 10);
+        other.sign(&mut update);
+        if let DeviceUpdate::DeviceStatus { origin, .. } = &mut update {
+            *origin = *owner.uuid();
+        }
+        assert!(trusted_keys.verify_update(&update).is_err());
+
+        // Agents without a trusted key are rejected.
+        let stranger = Signer::generate(Uuid::new_v4());
+        let mut update = status(*stranger.uuid(), 10);
+        stranger.sign(&mut update);
+        assert!(trusted_keys.verify_update(&update).is_err());
+    }
+
+    #[test]
+    fn updates_without_version_are_rejected() {
+        let signer = Signer::generate(Uuid::new_v4());
+        let trusted_keys = trusting(&[&signer]);
+        let mut update = status(*signer.uuid(), 10);
+        if let DeviceUpdate::DeviceStatus { version, .. } = &mut update {
+            *version = Version::default();
+        }
+        signer.sign(&mut update);
+        assert!(trusted_keys.verify_update(&update).is_err());
+    }
+
+    #[test]
+    fn stored_keys_are_loaded_and_trusted() {
+        let data_dir = std::env::temp_dir().join(format!("chatter-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_dir).unwrap();
+        let uuid = Uuid::new_v4();
+        let signer = Signer::load_or_create(uuid, &data_dir).unwrap();
+        let reloaded = Signer::load_or_create(uuid, &data_dir).unwrap();
+        assert_eq!(reloaded.public_key(), signer.public_key());
+
+        let trusted_keys = TrustedKeys::load(&data_dir).unwrap();
+        assert_eq!(trusted_keys.len(), 1);
+        let mut update = status(uuid, 10);
+        reloaded.sign(&mut update);
+        trusted_keys.verify_update(&update).unwrap();
+
+        fs::write(public_key_path(&data_dir, &Uuid::new_v4()), "not a key\n").unwrap();
+        assert!(TrustedKeys::load(&data_dir).is_err());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
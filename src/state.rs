@@ -1,5 +1,11 @@
+use crate::alert::{AlertCollection, AlertUpdate};
 use crate::devices::{DeviceCollection, DeviceUpdate};
+use crate::stats::Statistics;
+use crate::sync::{Delta, Digest};
 use crate::view::{ServerView, ViewUpdate};
+use crate::watch::{Event, Watchers};
+use chrono::Utc;
+use futures::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
@@ -7,16 +13,38 @@ use uuid::Uuid;
 pub struct State {
     pub devices: Arc<Mutex<DeviceCollection>>,
     pub view: Arc<Mutex<ServerView>>,
+    pub alerts: Arc<Mutex<AlertCollection>>,
+
+    /// Counters for the messages received by the agent.
+    pub stats: Arc<Statistics>,
+
+    /// Subscribers to changes of the devices and the view.
+    pub watchers: Arc<Watchers>,
 }
 
 impl State {
     pub fn new() -> State {
+        let watchers = Arc::new(Watchers::new());
+        let mut devices = DeviceCollection::new();
+        devices.set_watchers(watchers.clone());
+        let mut view = ServerView::new();
+        view.set_watchers(watchers.clone());
+        let mut alerts = AlertCollection::new();
+        alerts.set_watchers(watchers.clone());
         State {
-            devices: Arc::new(Mutex::new(DeviceCollection::new())),
-            view: Arc::new(Mutex::new(ServerView::new())),
+            devices: Arc::new(Mutex::new(devices)),
+            view: Arc::new(Mutex::new(view)),
+            alerts: Arc::new(Mutex::new(alerts)),
+            stats: Arc::new(Statistics::new()),
+            watchers,
         }
     }
 
+    /// Subscribe to changes of the devices and the view.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.watchers.subscribe()
+    }
+
     pub fn update_devices(&mut self, update: &DeviceUpdate, sender: &Uuid, timestamp_millis: i64) {
         self.devices
             .lock()
@@ -30,4 +58,98 @@ impl State {
             .expect("unable to lock view for update")
             .update(update, sender, timestamp_millis);
     }
+
+    pub fn update_alerts(&mut self, update: &AlertUpdate, sender: &Uuid) {
+        self.alerts
+            .lock()
+            .expect("unable to lock alerts for update")
+            .update(update, sender);
+    }
+
+    /// Remove state that is no longer needed, such as old tombstones
+    /// and resolved alerts.
+    pub fn collect_garbage(&mut self) {
+        let now = Utc::now().timestamp_millis();
+        self.devices
+            .lock()
+            .expect("unable to lock device collection for garbage collection")
+            .collect_garbage(now);
+        self.alerts
+            .lock()
+            .expect("unable to lock alerts for garbage collection")
+            .collect_garbage(now);
+    }
+
+    /// Compute a digest of the state.
+    pub fn digest(&self) -> Digest {
+        let (devices, device_fingerprints) = {
+            let devices = self
+                .devices
+                .lock()
+                .expect("unable to lock device collection for digest");
+            (devices.digest(), devices.fingerprints())
+        };
+        let (alerts, alert_fingerprints) = {
+            let alerts = self
+                .alerts
+                .lock()
+                .expect("unable to lock alerts for digest");
+            (alerts.digest(), alerts.fingerprints())
+        };
+        Digest {
+            devices,
+            servers: self
+                .view
+                .lock()
+                .expect("unable to lock view for digest")
+                .digest(),
+            alerts,
+            device_fingerprints,
+            alert_fingerprints,
+        }
+    }
+
+    /// Compute the entries of the state that are newer than in the
+    /// digest.
+    pub fn delta(&self, digest: &Digest) -> Delta {
+        Delta {
+            devices: self
+                .devices
+                .lock()
+                .expect("unable to lock device collection for delta")
+                .delta(&digest.devices, &digest.device_fingerprints),
+            servers: self
+                .view
+                .lock()
+                .expect("unable to lock view for delta")
+                .delta(&digest.servers),
+            alerts: self
+                .alerts
+                .lock()
+                .expect("unable to lock alerts for delta")
+                .delta(&digest.alerts, &digest.alert_fingerprints),
+        }
+    }
+
+    /// Merge entries sent by another agent into the state.
+    pub fn merge(&mut self, delta: &Delta, sender: &Uuid) {
+        self.devices
+            .lock()
+            .expect("unable to lock device collection for merge")
+            .merge(&delta.devices, sender);
+        self.view
+            .lock()
+            .expect("unable to lock view for merge")
+            .merge(&delta.servers);
+        self.alerts
+            .lock()
+            .expect("unable to lock alerts for merge")
+            .merge(&delta.alerts, sender);
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
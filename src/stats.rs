@@ -0,0 +1,85 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for counting what happens to the messages handled by the
+//! agent.
+//!
+//! The counters are updated by the tasks of the agent as they
+//! process messages and can be read at any time without locking.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for received and forwarded messages.
+#[derive(Debug, Default)]
+pub struct Statistics {
+    /// Number of messages successfully decoded.
+    received: AtomicU64,
+
+    /// Number of datagrams rejected because they could not be decoded
+    /// or did not follow the protocol.
+    rejected: AtomicU64,
+
+    /// Number of messages dropped because they were already seen.
+    duplicates: AtomicU64,
+
+    /// Number of messages forwarded to other servers, counting each
+    /// copy sent.
+    forwarded: AtomicU64,
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics::default()
+    }
+
+    /// Count a received message.
+    pub fn record_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a rejected datagram.
+    pub fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a duplicate message.
+    pub fn record_duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count copies of a message forwarded to other servers.
+    pub fn record_forwarded(&self, copies: u64) {
+        self.forwarded.fetch_add(copies, Ordering::Relaxed);
+    }
+
+    /// Number of messages successfully decoded.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Number of datagrams rejected.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Number of duplicate messages dropped.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages forwarded to other servers.
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+}
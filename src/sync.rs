@@ -0,0 +1,628 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for anti-entropy state synchronization.
+//!
+//! Since gossip is sent over UDP, updates can be lost. In addition, an
+//! agent that joins the cluster late will not see updates made before
+//! it joined. To make all agents converge to the same state, each
+//! agent periodically synchronizes its state with a random peer using
+//! a push-pull exchange:
+//!
+//! 1. The initiator sends a digest of its state to the peer. The
+//!    digest contains the version and a fingerprint of the devices
+//!    and the alerts for each agent and the version of each server
+//!    entry in the view.
+//!
+//! 2. The peer replies with all entries that differ from the digest,
+//!    together with its own digest.
+//!
+//! 3. The initiator merges the entries and replies with all entries
+//!    that differ from the digest of the peer.
+//!
+//! The version of the devices of an agent only tells the latest
+//! update seen, so an agent that missed an older update while seeing
+//! a newer one would look up to date. The fingerprint covers the
+//! version of every entry, so such gaps are detected and repaired.
+//!
+//! Without trusted keys, the entries cannot be verified, so the
+//! entries of an agent are only merged when they are sent by that
+//! agent.
+//!
+//! The entries are split over as many datagrams as needed, so each
+//! reply fits in a UDP datagram. The digest itself is sent in a
+//! single datagram.
+
+use crate::alert::AlertUpdate;
+use crate::clock::Version;
+use crate::devices::{DeviceInfo, DeviceSnapshot};
+use crate::gossip::Gossip;
+use crate::state::State;
+use crate::view::ServerSnapshot;
+use crate::wire::ALERTS_PROTOCOL_VERSION;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Maximum size of the entries sent in one datagram. This leaves
+/// room for the message, authentication, and encryption around the
+/// entries.
+pub const MAX_DELTA_SIZE: usize = 60_000;
+
+/// Compute a fingerprint of a set of entries, each given by a key and
+/// a version. The fingerprint does not depend on the order of the
+/// entries.
+pub(crate) fn fingerprint<I>(entries: I) -> u64
+where
+    I: IntoIterator<Item = (String, Version)>,
+{
+    let mut entries: Vec<(String, Version)> = entries.into_iter().collect();
+    entries.sort();
+    let mut hasher = Sha256::new();
+    for (key, version) in entries {
+        hasher.update(key.as_bytes());
+        hasher.update([0]);
+        hasher.update(version.millis.to_be_bytes());
+        hasher.update(version.counter.to_be_bytes());
+    }
+    let hash = hasher.finalize();
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Size of an entry when serialized.
+fn encoded_size<T: Serialize>(entry: &T) -> usize {
+    serde_cbor::to_vec(entry)
+        .map(|bytes| bytes.len())
+        .unwrap_or(0)
+}
+
+/// Digest of the state of an agent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Digest {
+    /// Version of the devices of each agent.
+    pub devices: HashMap<Uuid, Version>,
+
+    /// Version of each server entry in the view.
+    pub servers: HashMap<Uuid, i64>,
+
+    /// Version of the alerts of each agent.
+    #[serde(default)]
+    pub alerts: HashMap<Uuid, Version>,
+
+    /// Fingerprint of the devices of each agent.
+    #[serde(default)]
+    pub device_fingerprints: HashMap<Uuid, u64>,
+
+    /// Fingerprint of the alerts of each agent.
+    #[serde(default)]
+    pub alert_fingerprints: HashMap<Uuid, u64>,
+}
+
+/// Entries of the state that are newer than in a digest.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Delta {
+    pub devices: Vec<DeviceSnapshot>,
+    pub servers: Vec<ServerSnapshot>,
+    #[serde(default)]
+    pub alerts: Vec<AlertUpdate>,
+}
+
+impl Delta {
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty() && self.servers.is_empty() && self.alerts.is_empty()
+    }
+
+    /// Get the delta in a form that agents speaking an older protocol
+    /// version can decode.
+    pub fn downgrade(self, protocol_version: u8) -> Delta {
+        let alerts = if protocol_version < ALERTS_PROTOCOL_VERSION {
+            Vec::new()
+        } else {
+            self.alerts
+        };
+        Delta {
+            devices: self
+                .devices
+                .into_iter()
+                .map(|snapshot| snapshot.downgrade(protocol_version))
+                .collect(),
+            servers: self.servers,
+            alerts,
+        }
+    }
+
+    /// Split the entries into deltas that are at most `max_size`
+    /// bytes when serialized.
+    ///
+    /// Snapshots of the devices of an agent that are too large are
+    /// split into snapshots of a part of the devices, each with the
+    /// version of the newest entry in the part, so that a lost part
+    /// does not make the devices look up to date. A single entry that
+    /// is larger than `max_size` is sent in a delta of its own.
+    pub fn split(self, max_size: usize) -> Vec<Delta> {
+        let mut parts = Parts::new(max_size);
+        for server in self.servers {
+            parts.next(encoded_size(&server)).servers.push(server);
+        }
+        for alert in self.alerts {
+            parts.next(encoded_size(&alert)).alerts.push(alert);
+        }
+        for snapshot in self.devices {
+            for snapshot in split_snapshot(snapshot, max_size) {
+                parts.next(encoded_size(&snapshot)).devices.push(snapshot);
+            }
+        }
+        parts.parts
+    }
+}
+
+/// Deltas being filled with entries.
+struct Parts {
+    max_size: usize,
+    size: usize,
+    parts: Vec<Delta>,
+}
+
+impl Parts {
+    fn new(max_size: usize) -> Parts {
+        Parts {
+            max_size,
+            size: 0,
+            parts: vec![Delta::default()],
+        }
+    }
+
+    /// Get the delta to add an entry of the given size to, starting a
+    /// new delta if the entry does not fit in the current one.
+    fn next(&mut self, entry_size: usize) -> &mut Delta {
+        if self.size > 0 && self.size + entry_size > self.max_size {
+            self.parts.push(Delta::default());
+            self.size = 0;
+        }
+        self.size += entry_size;
+        self.parts.last_mut().expect("there is always a delta")
+    }
+}
+
+/// Split a snapshot of the devices of an agent into snapshots that
+/// are at most `max_size` bytes, if possible.
+fn split_snapshot(snapshot: DeviceSnapshot, max_size: usize) -> Vec<DeviceSnapshot> {
+    if encoded_size(&snapshot) <= max_size {
+        return vec![snapshot];
+    }
+    let part = |devices: Vec<DeviceInfo>, tombstones| {
+        let mut part = DeviceSnapshot {
+            origin: snapshot.origin,
+            version: Version::default(),
+            devices,
+            tombstones,
+        };
+        part.version = part.newest();
+        part
+    };
+    let mut parts = vec![part(Vec::new(), snapshot.tombstones.clone())];
+    for mut info in snapshot.devices.iter().cloned() {
+        if encoded_size(&info) <= max_size {
+            parts.push(part(vec![info], Vec::new()));
+            continue;
+        }
+        // Split the metrics of a device that is too large on its own.
+        let mut metrics: Vec<_> = info.metrics.drain().collect();
+        while !metrics.is_empty() {
+            let mut device = info.clone();
+            while let Some((name, entry)) = metrics.pop() {
+                device.metrics.insert(name.clone(), entry);
+                if device.metrics.len() > 1 && encoded_size(&device) > max_size {
+                    let entry = device
+                        .metrics
+                        .remove(&name)
+                        .expect("metric was just inserted");
+                    metrics.push((name, entry));
+                    break;
+                }
+            }
+            parts.push(part(vec![device], Vec::new()));
+        }
+    }
+    parts.retain(|part| !part.devices.is_empty() || !part.tombstones.is_empty());
+    parts
+}
+
+/// Handle anti-entropy gossip received from a peer.
+///
+/// Returns the gossip to send back to the peer, which is empty if
+/// there is nothing to send.
+pub fn handle(gossip: &Gossip, sender: &Uuid, state: &mut State) -> Vec<Gossip> {
+    match gossip {
+        Gossip::SyncRequest(digest) => respond(state.delta(digest), Some(state.digest())),
+
+        Gossip::SyncResponse { delta, digest } => {
+            state.merge(delta, sender);
+            match digest {
+                Some(digest) => respond(state.delta(digest), None),
+                None => Vec::new(),
+            }
+        }
+
+        _ => Vec::new(),
+    }
+}
+
+/// Split a reply into responses that fit in datagrams. The digest, if
+/// any, is sent with the last part so that the peer has merged as
+/// much as possible before replying.
+fn respond(delta: Delta, digest: Option<Digest>) -> Vec<Gossip> {
+    if delta.is_empty() && digest.is_none() {
+        return Vec::new();
+    }
+    let digest_size = digest.as_ref().map(encoded_size).unwrap_or(0);
+    let mut parts = delta.split(MAX_DELTA_SIZE);
+    let last_fits = parts
+        .last()
+        .is_some_and(|last| encoded_size(last) + digest_size <= MAX_DELTA_SIZE);
+    if !last_fits {
+        parts.push(Delta::default());
+    }
+    let count = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, delta)| Gossip::SyncResponse {
+            delta,
+            digest: if index + 1 == count {
+                digest.clone()
+            } else {
+                None
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{DeviceUpdate, Metric};
+
+    fn added(origin: Uuid, name: &str, millis: i64) -> DeviceUpdate {
+        DeviceUpdate::DeviceAdded {
+            origin,
+            name: name.to_string(),
+            description: format!("device {}", name),
+            version: Version::new(millis, 0),
+            signature: None,
+        }
+    }
+
+    fn status(origin: Uuid, name: &str, metric: &str, value: i64, millis: i64) -> DeviceUpdate {
+        let mut metrics = HashMap::new();
+        metrics.insert(metric.to_string(), Metric::Integer(value));
+        DeviceUpdate::DeviceStatus {
+            origin,
+            name: name.to_string(),
+            metrics,
+            version: Version::new(millis, 0),
+            signatures: HashMap::new(),
+        }
+    }
+
+    fn apply(state: &mut State, updates: &[&DeviceUpdate]) {
+        for update in updates {
+            state.update_devices(update, update.origin(), 0);
+        }
+    }
+
+    fn metric(state: &State, origin: &Uuid, device: &str, metric: &str) -> Option<Metric> {
+        state
+            .devices
+            .lock()
+            .unwrap()
+            .get(origin, device)
+            .and_then(|info| info.metrics.get(metric))
+            .map(|entry| entry.value.clone())
+    }
+
+    /// Run a full push-pull exchange from `initiator` to `peer`, which
+    /// are the states of the agents with the given UUIDs.
+    fn exchange(initiator: &mut State, initiator_uuid: &Uuid, peer: &mut State, peer_uuid: &Uuid) {
+        let mut replies = handle(
+            &Gossip::SyncRequest(initiator.digest()),
+            initiator_uuid,
+            peer,
+        );
+        while !replies.is_empty() {
+            let mut answers = Vec::new();
+            for reply in &replies {
+                answers.extend(handle(reply, peer_uuid, initiator));
+            }
+            replies = Vec::new();
+            for answer in &answers {
+                replies.extend(handle(answer, initiator_uuid, peer));
+            }
+        }
+    }
+
+    #[test]
+    fn repairs_missed_update_behind_newer_update() {
+        let origin = Uuid::new_v4();
+        let add = added(origin, "disk", 1);
+        let older = status(origin, "disk", "used", 10, 2);
+        let newer = status(origin, "disk", "free", 90, 3);
+
+        let mut complete = State::new();
+        apply(&mut complete, &[&add, &older, &newer]);
+        let mut gap = State::new();
+        apply(&mut gap, &[&add, &newer]);
+        assert_eq!(gap.digest().devices, complete.digest().devices);
+
+        exchange(&mut gap, &Uuid::new_v4(), &mut complete, &origin);
+        assert_eq!(
+            metric(&gap, &origin, "disk", "used"),
+            Some(Metric::Integer(10))
+        );
+        assert_eq!(
+            gap.digest().device_fingerprints,
+            complete.digest().device_fingerprints
+        );
+    }
+
+    #[test]
+    fn repairs_missed_alert_behind_newer_alert() {
+        use crate::alert::{AlertState, AlertUpdate};
+        let origin = Uuid::new_v4();
+        let alert = |rule: &str, millis| AlertUpdate {
+            origin,
+            rule: rule.to_string(),
+            device: "disk".to_string(),
+            metric: "used".to_string(),
+            state: AlertState::Firing,
+            value: 95.0,
+            version: Version::new(millis, 0),
+            signature: None,
+        };
+        let mut complete = State::new();
+        complete.update_alerts(&alert("full", 1), &origin);
+        complete.update_alerts(&alert("slow", 2), &origin);
+        let mut gap = State::new();
+        gap.update_alerts(&alert("slow", 2), &origin);
+
+        exchange(&mut gap, &Uuid::new_v4(), &mut complete, &origin);
+        assert!(gap
+            .alerts
+            .lock()
+            .unwrap()
+            .get(&origin, "full", "disk")
+            .is_some());
+    }
+
+    #[test]
+    fn merges_only_entries_of_sender_without_trusted_keys() {
+        use crate::alert::{AlertState, AlertUpdate};
+        let origin = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let mut source = State::new();
+        apply(
+            &mut source,
+            &[
+                &added(origin, "disk", 1),
+                &added(other, "disk", 1),
+                &status(other, "disk", "used", 10, 2),
+            ],
+        );
+        for uuid in &[origin, other] {
+            let alert = AlertUpdate {
+                origin: *uuid,
+                rule: "full".to_string(),
+                device: "disk".to_string(),
+                metric: "used".to_string(),
+                state: AlertState::Firing,
+                value: 95.0,
+                version: Version::new(3, 0),
+                signature: None,
+            };
+            source.update_alerts(&alert, uuid);
+        }
+
+        let mut target = State::new();
+        target.merge(&source.delta(&Digest::default()), &origin);
+        let devices = target.devices.lock().unwrap();
+        assert!(devices.get(&origin, "disk").is_some());
+        assert!(devices.get(&other, "disk").is_none());
+        let alerts = target.alerts.lock().unwrap();
+        assert!(alerts.get(&origin, "full", "disk").is_some());
+        assert!(alerts.get(&other, "full", "disk").is_none());
+    }
+
+    #[test]
+    fn synchronized_states_send_nothing() {
+        let origin = Uuid::new_v4();
+        let mut first = State::new();
+        let mut second = State::new();
+        for state in [&mut first, &mut second] {
+            apply(
+                state,
+                &[
+                    &added(origin, "disk", 1),
+                    &status(origin, "disk", "used", 1, 2),
+                ],
+            );
+        }
+        assert!(first.delta(&second.digest()).is_empty());
+    }
+
+    #[test]
+    fn replicas_of_unchanged_counter_converge() {
+        use crate::signing::{Signer, TrustedKeys};
+        use chrono::{TimeZone, Utc};
+        let signer = Signer::generate(Uuid::new_v4());
+        let origin = *signer.uuid();
+        let mut trusted_keys = TrustedKeys::new();
+        trusted_keys.insert(origin, signer.verifying_key());
+        let start = Utc.timestamp_opt(1000, 0).unwrap();
+        let updates: Vec<DeviceUpdate> = (2..5)
+            .map(|millis| {
+                let mut metrics = HashMap::new();
+                metrics.insert("steal_ms".to_string(), Metric::Counter { value: 7, start });
+                let mut update = DeviceUpdate::DeviceStatus {
+                    origin,
+                    name: "cpu".to_string(),
+                    metrics,
+                    version: Version::new(millis, 0),
+                    signatures: HashMap::new(),
+                };
+                signer.sign(&mut update);
+                update
+            })
+            .collect();
+        let mut add = added(origin, "cpu", 1);
+        signer.sign(&mut add);
+
+        let replica = |updates: &[&DeviceUpdate]| {
+            let mut state = State::new();
+            state
+                .devices
+                .lock()
+                .unwrap()
+                .set_trusted_keys(Some(trusted_keys.clone()));
+            apply(&mut state, &[&add]);
+            apply(&mut state, updates);
+            state
+        };
+        let mut first = replica(&[&updates[0], &updates[2]]);
+        let mut second = replica(&[&updates[1]]);
+
+        exchange(&mut first, &Uuid::new_v4(), &mut second, &Uuid::new_v4());
+        assert_eq!(
+            first.digest().device_fingerprints,
+            second.digest().device_fingerprints
+        );
+        assert!(first.delta(&second.digest()).is_empty());
+        assert!(second.delta(&first.digest()).is_empty());
+        let version = |state: &State| {
+            state
+                .devices
+                .lock()
+                .unwrap()
+                .get(&origin, "cpu")
+                .map(|info| info.metrics["steal_ms"].version)
+        };
+        assert_eq!(version(&first), Some(Version::new(4, 0)));
+        assert_eq!(version(&second), Some(Version::new(4, 0)));
+    }
+
+    fn large_state(origin: Uuid) -> State {
+        let mut state = State::new();
+        for device in 0..50 {
+            let name = format!("device-{}", device);
+            state.update_devices(&added(origin, &name, 1), &origin, 0);
+            let mut metrics = HashMap::new();
+            for metric in 0..20 {
+                metrics.insert(format!("metric-{}", metric), Metric::Text("x".repeat(100)));
+            }
+            state.update_devices(
+                &DeviceUpdate::DeviceStatus {
+                    origin,
+                    name,
+                    metrics,
+                    version: Version::new(2 + device, 0),
+                    signatures: HashMap::new(),
+                },
+                &origin,
+                0,
+            );
+        }
+        state
+    }
+
+    #[test]
+    fn splits_large_delta_into_parts_that_fit() {
+        let origin = Uuid::new_v4();
+        let source = large_state(origin);
+        let delta = source.delta(&Digest::default());
+        assert!(encoded_size(&delta) > MAX_DELTA_SIZE);
+
+        let parts = delta.split(MAX_DELTA_SIZE);
+        assert!(parts.len() > 1);
+        let mut target = State::new();
+        for part in &parts {
+            assert!(encoded_size(part) <= MAX_DELTA_SIZE);
+            target.merge(part, &origin);
+        }
+        assert_eq!(target.digest().devices, source.digest().devices);
+        assert_eq!(
+            target.digest().device_fingerprints,
+            source.digest().device_fingerprints
+        );
+    }
+
+    #[test]
+    fn splits_device_with_too_many_metrics() {
+        let origin = Uuid::new_v4();
+        let source = large_state(origin);
+        let delta = source.delta(&Digest::default());
+        let parts = delta.split(1000);
+        let mut target = State::new();
+        for part in &parts {
+            assert!(part
+                .devices
+                .iter()
+                .all(|snapshot| snapshot.devices.len() <= 1));
+            target.merge(part, &origin);
+        }
+        assert_eq!(
+            target.digest().device_fingerprints,
+            source.digest().device_fingerprints
+        );
+    }
+
+    #[test]
+    fn lost_part_is_sent_again() {
+        let origin = Uuid::new_v4();
+        let source = large_state(origin);
+        let parts = source.delta(&Digest::default()).split(MAX_DELTA_SIZE);
+        let mut target = State::new();
+        for part in &parts[1..] {
+            target.merge(part, &origin);
+        }
+        let missing = source.delta(&target.digest());
+        assert!(!missing.is_empty());
+        target.merge(&missing, &origin);
+        assert_eq!(
+            target.digest().device_fingerprints,
+            source.digest().device_fingerprints
+        );
+    }
+
+    #[test]
+    fn digest_is_sent_with_last_part() {
+        let origin = Uuid::new_v4();
+        let mut source = large_state(origin);
+        let replies = handle(
+            &Gossip::SyncRequest(Digest::default()),
+            &Uuid::new_v4(),
+            &mut source,
+        );
+        assert!(replies.len() > 1);
+        for (index, reply) in replies.iter().enumerate() {
+            match reply {
+                Gossip::SyncResponse { digest, .. } => {
+                    assert_eq!(digest.is_some(), index + 1 == replies.len())
+                }
+                other => panic!("expected a sync response, got {:?}", other),
+            }
+        }
+    }
+}
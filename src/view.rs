@@ -12,70 +12,355 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
+use crate::watch::{Event, Watchers};
 use chrono::prelude::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use uuid::Uuid;
 
+/// Status of a server, as seen by the failure detector.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerStatus {
+    /// The server responds to probes.
+    Alive,
+
+    /// The server did not respond to probes and is suspected to have
+    /// failed.
+    Suspect,
+
+    /// The server was suspected for too long and is considered
+    /// failed.
+    Dead,
+
+    /// The server left the cluster.
+    Left,
+}
+
+impl ServerStatus {
+    /// Check if the server is an active member of the cluster, that
+    /// is, it has not failed or left.
+    pub fn is_active(&self) -> bool {
+        matches!(self, ServerStatus::Alive | ServerStatus::Suspect)
+    }
+
+    /// Precedence of the status over other statuses with the same
+    /// incarnation number.
+    fn precedence(&self) -> u8 {
+        match self {
+            ServerStatus::Alive => 0,
+            ServerStatus::Suspect => 1,
+            ServerStatus::Dead => 2,
+            ServerStatus::Left => 3,
+        }
+    }
+}
+
+impl fmt::Display for ServerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServerStatus::Alive => write!(f, "alive"),
+            ServerStatus::Suspect => write!(f, "suspect"),
+            ServerStatus::Dead => write!(f, "dead"),
+            ServerStatus::Left => write!(f, "left"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ServerInfo {
     pub address: SocketAddr,
     pub last_seen: NaiveDateTime,
+
+    /// Current status of the server.
+    pub status: ServerStatus,
+
+    /// Incarnation number of the server. It is increased by the
+    /// server itself to refute suspicions about it.
+    pub incarnation: u64,
+
+    /// Version of the entry, which is the timestamp of the latest
+    /// update of the entry.
+    pub version: i64,
 }
 
 impl ServerInfo {
     pub fn new(address: SocketAddr, last_seen: NaiveDateTime) -> ServerInfo {
         ServerInfo {
-            address: address,
-            last_seen: last_seen,
+            address,
+            last_seen,
+            status: ServerStatus::Alive,
+            incarnation: 0,
+            version: last_seen.and_utc().timestamp_millis(),
         }
     }
+
+    fn bump(&mut self, timestamp_millis: i64) {
+        self.version = std::cmp::max(self.version, timestamp_millis);
+    }
+
+    /// Order of the entry when merging, see `ServerView::merge`.
+    fn rank(&self) -> (u64, u8, i64) {
+        (self.incarnation, self.status.precedence(), self.version)
+    }
 }
 
 impl fmt::Display for ServerInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.address)
+        write!(f, "{} ({})", self.address, self.status)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ViewUpdate {
-    ServerAdded { uuid: Uuid, addr: SocketAddr },
+    ServerAdded {
+        uuid: Uuid,
+        addr: SocketAddr,
+    },
+
+    ServerRemoved {
+        uuid: Uuid,
+    },
+
+    /// Server is alive. Overrides a suspicion with a lower
+    /// incarnation number.
+    ServerAlive {
+        uuid: Uuid,
+        incarnation: u64,
+    },
+
+    /// Server is suspected to have failed.
+    ServerSuspect {
+        uuid: Uuid,
+        incarnation: u64,
+    },
 
-    ServerRemoved { uuid: Uuid },
+    /// Server is considered failed.
+    ServerDead {
+        uuid: Uuid,
+        incarnation: u64,
+    },
+}
+
+/// Snapshot of a server entry in the view.
+///
+/// Snapshots are exchanged during anti-entropy to bring agents that
+/// missed updates up to date.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerSnapshot {
+    pub uuid: Uuid,
+    pub address: SocketAddr,
+    pub status: ServerStatus,
+    pub incarnation: u64,
+    pub version: i64,
+}
+
+impl ServerSnapshot {
+    fn rank(&self) -> (u64, u8, i64) {
+        (self.incarnation, self.status.precedence(), self.version)
+    }
+}
+
+fn datetime_from_millis(timestamp_millis: i64) -> NaiveDateTime {
+    DateTime::from_timestamp_millis(timestamp_millis)
+        .unwrap_or_default()
+        .naive_utc()
 }
 
 pub struct ServerView {
     pub servers: HashMap<Uuid, ServerInfo>,
+
+    /// Subscribers to changes of the status of servers.
+    watchers: Arc<Watchers>,
 }
 
 impl ServerView {
     pub fn new() -> ServerView {
         ServerView {
             servers: HashMap::new(),
+            watchers: Arc::new(Watchers::new()),
+        }
+    }
+
+    /// Set the subscribers that changes of the status of servers are
+    /// published to.
+    pub fn set_watchers(&mut self, watchers: Arc<Watchers>) {
+        self.watchers = watchers;
+    }
+
+    /// Publish the change of status of a server, if it changed.
+    fn publish(&self, uuid: &Uuid, old: Option<ServerStatus>) {
+        if let Some(info) = self.servers.get(uuid) {
+            if let Some(event) = Event::member(*uuid, info.address, old, info.status) {
+                self.watchers.publish(event);
+            }
         }
     }
 
     pub fn update(&mut self, gossip: &ViewUpdate, _sender: &Uuid, timestamp_millis: i64) {
         match gossip {
             ViewUpdate::ServerAdded { uuid, addr } => {
-                let ts = NaiveDateTime::from_timestamp(
-                    timestamp_millis / 1000,
-                    (1_000_000 * timestamp_millis % 1000) as u32,
-                );
-                info!("Adding server {} with address {} to view", uuid, addr);
-                self.servers
-                    .insert(uuid.clone(), ServerInfo::new(addr.clone(), ts));
+                if self
+                    .servers
+                    .get(uuid)
+                    .is_none_or(|info| info.version <= timestamp_millis)
+                {
+                    let ts = datetime_from_millis(timestamp_millis);
+                    info!("Adding server {} with address {} to view", uuid, addr);
+                    let mut info = ServerInfo::new(*addr, ts);
+                    // A server that joins again gets a higher
+                    // incarnation, so that it is not overridden by
+                    // snapshots of its old entry during anti-entropy.
+                    if let Some(old) = self.servers.get(uuid) {
+                        info.incarnation = old.incarnation + 1;
+                    }
+                    let old = self.servers.insert(*uuid, info);
+                    self.publish(uuid, old.map(|info| info.status));
+                }
             }
 
+            // Servers that left are kept in the view, so that it is
+            // possible to distinguish them from failed servers and so
+            // that anti-entropy does not bring them back.
             ViewUpdate::ServerRemoved { uuid } => {
-                info!("Removing server {} from view", uuid);
-                self.servers.remove(&uuid);
+                if let Some(info) = self.servers.get_mut(uuid) {
+                    if info.status != ServerStatus::Left && info.version <= timestamp_millis {
+                        info!("Server {} left the cluster", uuid);
+                        let old = info.status;
+                        info.status = ServerStatus::Left;
+                        info.bump(timestamp_millis);
+                        self.publish(uuid, Some(old));
+                    }
+                }
+            }
+
+            ViewUpdate::ServerAlive { uuid, incarnation } => {
+                if let Some(info) = self.servers.get_mut(uuid) {
+                    if info.status != ServerStatus::Left && *incarnation > info.incarnation {
+                        info!("Server {} is alive (incarnation {})", uuid, incarnation);
+                        let old = info.status;
+                        info.status = ServerStatus::Alive;
+                        info.incarnation = *incarnation;
+                        info.last_seen = datetime_from_millis(timestamp_millis);
+                        info.bump(timestamp_millis);
+                        self.publish(uuid, Some(old));
+                    }
+                }
+            }
+
+            ViewUpdate::ServerSuspect { uuid, incarnation } => {
+                if let Some(info) = self.servers.get_mut(uuid) {
+                    let overrides = match info.status {
+                        ServerStatus::Alive => *incarnation >= info.incarnation,
+                        ServerStatus::Suspect => *incarnation > info.incarnation,
+                        ServerStatus::Dead | ServerStatus::Left => false,
+                    };
+                    if overrides {
+                        warn!("Server {} is suspected (incarnation {})", uuid, incarnation);
+                        let old = info.status;
+                        info.status = ServerStatus::Suspect;
+                        info.incarnation = *incarnation;
+                        info.bump(timestamp_millis);
+                        self.publish(uuid, Some(old));
+                    }
+                }
+            }
+
+            ViewUpdate::ServerDead { uuid, incarnation } => {
+                if let Some(info) = self.servers.get_mut(uuid) {
+                    if info.status.is_active() && *incarnation >= info.incarnation {
+                        error!("Server {} is dead (incarnation {})", uuid, incarnation);
+                        let old = info.status;
+                        info.status = ServerStatus::Dead;
+                        info.incarnation = *incarnation;
+                        info.bump(timestamp_millis);
+                        self.publish(uuid, Some(old));
+                    }
+                }
             }
         }
         info!("View updated: {}", *self);
     }
+
+    /// Get the version of each server entry.
+    pub fn digest(&self) -> HashMap<Uuid, i64> {
+        self.servers
+            .iter()
+            .map(|(uuid, info)| (*uuid, info.version))
+            .collect()
+    }
+
+    /// Get snapshots of all server entries with a version that differs
+    /// from the digest.
+    ///
+    /// Entries with an older version are also sent, since they can
+    /// still take precedence when merged.
+    pub fn delta(&self, digest: &HashMap<Uuid, i64>) -> Vec<ServerSnapshot> {
+        self.servers
+            .iter()
+            .filter(|(uuid, info)| digest.get(uuid) != Some(&info.version))
+            .map(|(uuid, info)| ServerSnapshot {
+                uuid: *uuid,
+                address: info.address,
+                status: info.status,
+                incarnation: info.incarnation,
+                version: info.version,
+            })
+            .collect()
+    }
+
+    /// Merge snapshots into the view.
+    ///
+    /// Snapshots are ordered like the updates of the failure
+    /// detector: by incarnation number first, then by the precedence
+    /// of the status, so that a refutation with a higher incarnation
+    /// number overrides a suspicion regardless of the clocks of the
+    /// servers. The version only breaks ties. A snapshot that is
+    /// ordered after the server entry replaces the entry, but the time
+    /// the server was last heard from is kept.
+    pub fn merge(&mut self, snapshots: &[ServerSnapshot]) {
+        for snapshot in snapshots {
+            let last_seen = match self.servers.get(&snapshot.uuid) {
+                Some(info) if info.rank() >= snapshot.rank() => continue,
+                Some(info) => info.last_seen,
+                None => Utc::now().naive_utc(),
+            };
+            info!(
+                "Merging server {} with address {} ({}) into view",
+                snapshot.uuid, snapshot.address, snapshot.status
+            );
+            let mut info = ServerInfo::new(snapshot.address, last_seen);
+            info.status = snapshot.status;
+            info.incarnation = snapshot.incarnation;
+            info.version = snapshot.version;
+            let old = self.servers.insert(snapshot.uuid, info);
+            self.publish(&snapshot.uuid, old.map(|info| info.status));
+        }
+    }
+
+    /// Record that a server was heard from.
+    pub fn touch(&mut self, uuid: &Uuid) {
+        if let Some(info) = self.servers.get_mut(uuid) {
+            info.last_seen = Utc::now().naive_utc();
+        }
+    }
+
+    /// Get the status of a server, if it is part of the view.
+    pub fn status(&self, uuid: &Uuid) -> Option<(ServerStatus, u64)> {
+        self.servers
+            .get(uuid)
+            .map(|info| (info.status, info.incarnation))
+    }
+}
+
+impl Default for ServerView {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl fmt::Display for ServerView {
@@ -86,3 +371,199 @@ impl fmt::Display for ServerView {
         Ok(())
     }
 }
+
+/// Strategy for selecting the servers to forward gossip to.
+pub trait PeerSelector {
+    /// Select the addresses of the servers to forward a message to.
+    ///
+    /// # Parameters
+    ///
+    /// * `view` - The current view of the cluster.
+    ///
+    /// * `exclude` - The UUIDs of servers that should not be
+    ///   selected, for example the server that sent the message and
+    ///   this agent.
+    ///
+    /// * `peer` - The address that the message arrived from, if any.
+    ///
+    fn select(
+        &mut self,
+        view: &ServerView,
+        exclude: &[Uuid],
+        peer: Option<&SocketAddr>,
+    ) -> Vec<SocketAddr>;
+}
+
+/// Select a fixed number of random servers from the view.
+///
+/// Excluded servers, the server that the message arrived from, and
+/// servers that failed or left are never selected.
+pub struct RandomFanout<R: Rng> {
+    fanout: usize,
+    rng: R,
+}
+
+impl RandomFanout<StdRng> {
+    /// Construct a random fan-out with a random generator seeded from
+    /// the operating system.
+    pub fn new(fanout: usize) -> RandomFanout<StdRng> {
+        RandomFanout::with_rng(fanout, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> RandomFanout<R> {
+    /// Construct a random fan-out using the provided random
+    /// generator.
+    ///
+    /// This is useful to get a deterministic selection, for example
+    /// by using a seeded generator.
+    pub fn with_rng(fanout: usize, rng: R) -> RandomFanout<R> {
+        RandomFanout { fanout, rng }
+    }
+}
+
+impl<R: Rng> PeerSelector for RandomFanout<R> {
+    fn select(
+        &mut self,
+        view: &ServerView,
+        exclude: &[Uuid],
+        peer: Option<&SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        // The candidates are sorted so that the selection only depends
+        // on the random generator and not the iteration order of the
+        // hash map.
+        let mut candidates: Vec<(&Uuid, &ServerInfo)> = view
+            .servers
+            .iter()
+            .filter(|(uuid, info)| {
+                !exclude.contains(uuid) && Some(&info.address) != peer && info.status.is_active()
+            })
+            .collect();
+        candidates.sort_by_key(|(uuid, _)| **uuid);
+        candidates
+            .choose_multiple(&mut self.rng, self.fanout)
+            .map(|(_, info)| info.address)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view_with(count: usize) -> (ServerView, Vec<Uuid>) {
+        let mut view = ServerView::new();
+        let mut uuids = Vec::new();
+        for port in 0..count {
+            let uuid = Uuid::new_v4();
+            let addr = SocketAddr::from(([127, 0, 0, 1], 9000 + port as u16));
+            view.update(&ViewUpdate::ServerAdded { uuid, addr }, &uuid, 0);
+            uuids.push(uuid);
+        }
+        (view, uuids)
+    }
+
+    #[test]
+    fn fanout_is_deterministic_with_seeded_rng() {
+        let (view, _) = view_with(10);
+        let mut first = RandomFanout::with_rng(3, StdRng::seed_from_u64(17));
+        let mut second = RandomFanout::with_rng(3, StdRng::seed_from_u64(17));
+        for _ in 0..5 {
+            let selected = first.select(&view, &[], None);
+            assert_eq!(selected.len(), 3);
+            assert_eq!(selected, second.select(&view, &[], None));
+        }
+    }
+
+    #[test]
+    fn fanout_skips_excluded_peer_and_inactive_servers() {
+        let (mut view, uuids) = view_with(5);
+        let peer = view.servers[&uuids[1]].address;
+        view.update(&ViewUpdate::ServerRemoved { uuid: uuids[2] }, &uuids[2], 1);
+        view.update(
+            &ViewUpdate::ServerDead {
+                uuid: uuids[3],
+                incarnation: 0,
+            },
+            &uuids[0],
+            1,
+        );
+        let mut fanout = RandomFanout::with_rng(5, StdRng::seed_from_u64(1));
+        let selected = fanout.select(&view, &[uuids[0]], Some(&peer));
+        assert_eq!(selected, vec![view.servers[&uuids[4]].address]);
+    }
+
+    #[test]
+    fn fanout_is_limited_by_view_size() {
+        let (view, _) = view_with(2);
+        let mut fanout = RandomFanout::with_rng(3, StdRng::seed_from_u64(5));
+        let mut selected = fanout.select(&view, &[], None);
+        selected.sort();
+        let mut expected: Vec<SocketAddr> =
+            view.servers.values().map(|info| info.address).collect();
+        expected.sort();
+        assert_eq!(selected, expected);
+    }
+
+    fn snapshot(
+        uuid: Uuid,
+        status: ServerStatus,
+        incarnation: u64,
+        version: i64,
+    ) -> ServerSnapshot {
+        ServerSnapshot {
+            uuid,
+            address: SocketAddr::from(([127, 0, 0, 1], 9000)),
+            status,
+            incarnation,
+            version,
+        }
+    }
+
+    #[test]
+    fn merge_prefers_higher_incarnation_over_newer_version() {
+        let (mut view, uuids) = view_with(1);
+        let uuid = uuids[0];
+        view.merge(&[snapshot(uuid, ServerStatus::Alive, 1, 100)]);
+        assert_eq!(view.status(&uuid), Some((ServerStatus::Alive, 1)));
+
+        // A stale failure from a server with a fast clock.
+        view.merge(&[snapshot(uuid, ServerStatus::Dead, 0, 200)]);
+        assert_eq!(view.status(&uuid), Some((ServerStatus::Alive, 1)));
+
+        view.merge(&[snapshot(uuid, ServerStatus::Suspect, 1, 50)]);
+        assert_eq!(view.status(&uuid), Some((ServerStatus::Suspect, 1)));
+    }
+
+    #[test]
+    fn merge_keeps_last_seen() {
+        let (mut view, uuids) = view_with(1);
+        let uuid = uuids[0];
+        let last_seen = view.servers[&uuid].last_seen;
+        view.merge(&[snapshot(uuid, ServerStatus::Suspect, 0, 1_000_000)]);
+        assert_eq!(view.servers[&uuid].last_seen, last_seen);
+    }
+
+    #[test]
+    fn stronger_entry_with_older_version_is_sent() {
+        let (mut view, uuids) = view_with(1);
+        let uuid = uuids[0];
+        view.merge(&[snapshot(uuid, ServerStatus::Alive, 1, 100)]);
+        let mut digest = HashMap::new();
+        digest.insert(uuid, 200);
+        assert_eq!(view.delta(&digest).len(), 1);
+        digest.insert(uuid, 100);
+        assert!(view.delta(&digest).is_empty());
+    }
+
+    #[test]
+    fn rejoining_server_overrides_left_entry() {
+        let (mut view, uuids) = view_with(1);
+        let uuid = uuids[0];
+        let addr = view.servers[&uuid].address;
+        view.update(&ViewUpdate::ServerRemoved { uuid }, &uuid, 10);
+        view.update(&ViewUpdate::ServerAdded { uuid, addr }, &uuid, 20);
+        view.merge(&[snapshot(uuid, ServerStatus::Left, 0, 10)]);
+        assert_eq!(view.status(&uuid), Some((ServerStatus::Alive, 1)));
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for watching changes to the state of the agent.
+//!
+//! The device collection, the view, and the alerts publish an event
+//! each time a device is added or removed, a metric changes, the
+//! status of a server changes, or an alert fires or is resolved,
+//! whether the change comes from gossip, from anti-entropy, or from
+//! the agent itself. Subscribers get the events as a stream, so tools
+//! can react to changes instead of polling.
+//!
+//! Each subscriber has a bounded buffer. A subscriber that does not
+//! keep up is disconnected rather than silently missing events, so it
+//! can subscribe again and read the current state.
+
+use crate::clock::Version;
+use crate::metrics::Metric;
+use crate::view::ServerStatus;
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Number of events buffered for each subscriber.
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Change to the state of the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    DeviceAdded {
+        owner: Uuid,
+        name: String,
+        description: String,
+    },
+
+    DeviceRemoved {
+        owner: Uuid,
+        name: String,
+    },
+
+    MetricChanged {
+        owner: Uuid,
+        device: String,
+        metric: String,
+        value: Metric,
+        version: Version,
+    },
+
+    /// Server joined the cluster, or came back after leaving or
+    /// failing.
+    MemberJoined {
+        uuid: Uuid,
+        address: SocketAddr,
+    },
+
+    /// Suspected server refuted the suspicion.
+    MemberAlive {
+        uuid: Uuid,
+    },
+
+    MemberSuspected {
+        uuid: Uuid,
+    },
+
+    MemberFailed {
+        uuid: Uuid,
+    },
+
+    MemberLeft {
+        uuid: Uuid,
+    },
+
+    AlertFiring {
+        owner: Uuid,
+        rule: String,
+        device: String,
+        metric: String,
+        value: f64,
+        version: Version,
+    },
+
+    AlertResolved {
+        owner: Uuid,
+        rule: String,
+        device: String,
+        metric: String,
+        value: f64,
+        version: Version,
+    },
+}
+
+impl Event {
+    /// Name of the event, as used in the `event` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::DeviceAdded { .. } => "device-added",
+            Event::DeviceRemoved { .. } => "device-removed",
+            Event::MetricChanged { .. } => "metric-changed",
+            Event::MemberJoined { .. } => "member-joined",
+            Event::MemberAlive { .. } => "member-alive",
+            Event::MemberSuspected { .. } => "member-suspected",
+            Event::MemberFailed { .. } => "member-failed",
+            Event::MemberLeft { .. } => "member-left",
+            Event::AlertFiring { .. } => "alert-firing",
+            Event::AlertResolved { .. } => "alert-resolved",
+        }
+    }
+
+    /// Event for a change of the status of a server, if the status
+    /// changed.
+    ///
+    /// # Parameters
+    ///
+    /// * `uuid` - The UUID of the server.
+    ///
+    /// * `address` - The address of the server.
+    ///
+    /// * `old` - The status before the change, or `None` if the
+    ///   server was not part of the view.
+    ///
+    /// * `new` - The status after the change.
+    ///
+    pub fn member(
+        uuid: Uuid,
+        address: SocketAddr,
+        old: Option<ServerStatus>,
+        new: ServerStatus,
+    ) -> Option<Event> {
+        if old == Some(new) {
+            return None;
+        }
+        let event = match new {
+            ServerStatus::Alive => match old {
+                Some(ServerStatus::Suspect) => Event::MemberAlive { uuid },
+                _ => Event::MemberJoined { uuid, address },
+            },
+            ServerStatus::Suspect => Event::MemberSuspected { uuid },
+            ServerStatus::Dead => Event::MemberFailed { uuid },
+            ServerStatus::Left => Event::MemberLeft { uuid },
+        };
+        Some(event)
+    }
+}
+
+/// Subscribers to the events of the agent.
+#[derive(Debug, Default)]
+pub struct Watchers {
+    subscribers: Mutex<Vec<Sender<Event>>>,
+}
+
+impl Watchers {
+    pub fn new() -> Watchers {
+        Watchers::default()
+    }
+
+    /// Subscribe to events. The subscription ends when the receiver
+    /// is dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel(SUBSCRIBER_BUFFER);
+        self.subscribers
+            .lock()
+            .expect("unable to lock subscribers")
+            .push(sender);
+        receiver
+    }
+
+    /// Number of subscribers.
+    pub fn len(&self) -> usize {
+        self.subscribers
+            .lock()
+            .expect("unable to lock subscribers")
+            .len()
+    }
+
+    /// Check if there are no subscribers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send an event to all subscribers. Subscribers that are gone or
+    /// do not keep up are removed.
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().expect("unable to lock subscribers");
+        if subscribers.is_empty() {
+            return;
+        }
+        trace!("Publishing event {:?}", event);
+        subscribers.retain_mut(|sender| match sender.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(err) if err.is_full() => {
+                warn!("Disconnecting subscriber that does not keep up with events");
+                false
+            }
+            Err(_) => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+
+    fn changed(metric: usize) -> Event {
+        Event::MetricChanged {
+            owner: Uuid::nil(),
+            device: "gateway".to_string(),
+            metric: metric.to_string(),
+            value: Metric::Integer(0),
+            version: Version::new(0, 0),
+        }
+    }
+
+    #[test]
+    fn member_transitions_are_mapped_to_events() {
+        use ServerStatus::*;
+        let uuid = Uuid::new_v4();
+        let address: SocketAddr = "127.0.0.1:2428".parse().unwrap();
+        let joined = Some(Event::MemberJoined { uuid, address });
+        let cases = vec![
+            (None, Alive, joined.clone()),
+            (Some(Dead), Alive, joined.clone()),
+            (Some(Left), Alive, joined),
+            (Some(Suspect), Alive, Some(Event::MemberAlive { uuid })),
+            (Some(Alive), Suspect, Some(Event::MemberSuspected { uuid })),
+            (None, Suspect, Some(Event::MemberSuspected { uuid })),
+            (Some(Suspect), Dead, Some(Event::MemberFailed { uuid })),
+            (Some(Alive), Left, Some(Event::MemberLeft { uuid })),
+            (Some(Alive), Alive, None),
+            (Some(Dead), Dead, None),
+        ];
+        for (old, new, expected) in cases {
+            assert_eq!(
+                Event::member(uuid, address, old, new),
+                expected,
+                "{:?} -> {:?}",
+                old,
+                new
+            );
+        }
+    }
+
+    #[test]
+    fn subscribers_that_fall_behind_are_disconnected() {
+        let watchers = Watchers::new();
+        let mut fast = watchers.subscribe().wait();
+        let slow = watchers.subscribe();
+        let dropped = watchers.subscribe();
+        drop(dropped);
+
+        for metric in 0..SUBSCRIBER_BUFFER {
+            watchers.publish(changed(metric));
+        }
+        assert_eq!(watchers.len(), 2);
+        for metric in 0..SUBSCRIBER_BUFFER {
+            assert_eq!(fast.next().unwrap().unwrap(), changed(metric));
+        }
+
+        // The channel of the slow subscriber holds one event more than
+        // the buffer, so the second event overflows it.
+        watchers.publish(changed(SUBSCRIBER_BUFFER));
+        assert_eq!(watchers.len(), 2);
+        watchers.publish(changed(SUBSCRIBER_BUFFER + 1));
+        assert_eq!(watchers.len(), 1);
+        assert_eq!(fast.next().unwrap().unwrap(), changed(SUBSCRIBER_BUFFER));
+        assert_eq!(
+            fast.next().unwrap().unwrap(),
+            changed(SUBSCRIBER_BUFFER + 1)
+        );
+
+        // The slow subscriber gets the events it had room for, and then
+        // the end of the stream, so that it can subscribe again.
+        let received: Vec<Event> = slow.wait().map(Result::unwrap).collect();
+        let expected: Vec<Event> = (0..=SUBSCRIBER_BUFFER).map(changed).collect();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Module for the wire format of datagrams.
+//!
+//! Each datagram is wrapped in an envelope consisting of a header
+//! followed by the payload. The header contains:
+//!
+//! * A magic number, the bytes `CHTR`, identifying the datagram as
+//!   gossip.
+//!
+//! * A protocol version, which tells the receiver how to interpret
+//!   the payload.
+//!
+//! * A payload kind, which tells what the payload contains.
+//!
+//! An encrypted payload contains another envelope when decrypted. The
+//! inner envelope has the same protocol version as the outer envelope,
+//! so it consists of only the payload kind followed by the payload.
+//!
+//! Agents from before the envelope was introduced send the CBOR
+//! encoded message without any header. This is treated as the
+//! legacy protocol version 0, which allows a cluster to be upgraded
+//! one agent at a time.
+
+use crate::error::Error;
+use bytes::{BufMut, BytesMut};
+
+/// Magic number at the start of each envelope.
+pub const MAGIC: [u8; 4] = *b"CHTR";
+
+/// Size of the envelope header.
+pub const HEADER_SIZE: usize = MAGIC.len() + 2;
+
+/// Protocol version of datagrams without an envelope.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 0;
+
+/// Protocol version spoken by this agent.
+///
+/// The version has to be increased whenever the encoding of the
+/// payload changes in a way that older agents cannot decode, for
+/// example when adding a variant to `Gossip` or `DeviceUpdate`.
+///
+/// * Version 1 introduced the envelope.
+///
+/// * Version 2 added typed metrics.
+///
+/// * Version 3 added alerts.
+///
+/// * Version 4 added deliveries of notifications.
+///
+/// Messages sent using an older protocol version are downgraded so
+/// that agents speaking that version can decode them, see
+/// `Message::downgrade`.
+pub const PROTOCOL_VERSION: u8 = 4;
+
+/// Protocol version that added typed metrics.
+pub const TYPED_METRICS_PROTOCOL_VERSION: u8 = 2;
+
+/// Protocol version that added alerts.
+pub const ALERTS_PROTOCOL_VERSION: u8 = 3;
+
+/// Protocol version that added deliveries of notifications.
+pub const NOTIFICATIONS_PROTOCOL_VERSION: u8 = 4;
+
+/// Oldest protocol version that this agent can decode.
+pub const MIN_PROTOCOL_VERSION: u8 = LEGACY_PROTOCOL_VERSION;
+
+/// Kind of payload carried in an envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// CBOR encoded message.
+    Message,
+
+    /// Authentication tag followed by a CBOR encoded message.
+    Authenticated,
+
+    /// Encrypted inner envelope.
+    Encrypted,
+}
+
+impl Kind {
+    /// Get the kind for a byte in the header, if it is known.
+    pub fn from_u8(byte: u8) -> Option<Kind> {
+        match byte {
+            1 => Some(Kind::Message),
+            2 => Some(Kind::Authenticated),
+            3 => Some(Kind::Encrypted),
+            _ => None,
+        }
+    }
+
+    /// Get the byte used for the kind in the header.
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Kind::Message => 1,
+            Kind::Authenticated => 2,
+            Kind::Encrypted => 3,
+        }
+    }
+}
+
+/// Check that this agent can speak a protocol version.
+pub fn check_version(version: u8) -> Result<(), Error> {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(Error::ProtocolError(format!(
+            "unsupported protocol version {}, expected {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )))
+    }
+}
+
+/// Get the header of an envelope.
+pub fn header(version: u8, kind: Kind) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()] = version;
+    header[MAGIC.len() + 1] = kind.as_u8();
+    header
+}
+
+/// Envelope of a datagram.
+#[derive(Debug)]
+pub struct Envelope<'a> {
+    pub version: u8,
+    pub kind: Kind,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(version: u8, kind: Kind, payload: &'a [u8]) -> Envelope<'a> {
+        Envelope {
+            version,
+            kind,
+            payload,
+        }
+    }
+
+    /// Parse the envelope of a datagram.
+    ///
+    /// Datagrams that do not start with the magic number are treated
+    /// as legacy datagrams containing a message.
+    pub fn parse(buf: &'a [u8]) -> Result<Envelope<'a>, Error> {
+        if !buf.starts_with(&MAGIC) {
+            return Ok(Envelope::new(LEGACY_PROTOCOL_VERSION, Kind::Message, buf));
+        }
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::ProtocolError(
+                "truncated envelope header".to_string(),
+            ));
+        }
+        let version = buf[MAGIC.len()];
+        if version == LEGACY_PROTOCOL_VERSION {
+            return Err(Error::ProtocolError(
+                "envelope with legacy protocol version".to_string(),
+            ));
+        }
+        check_version(version)?;
+        let kind = Kind::from_u8(buf[MAGIC.len() + 1]).ok_or_else(|| {
+            Error::ProtocolError(format!("unknown payload kind {}", buf[MAGIC.len() + 1]))
+        })?;
+        Ok(Envelope::new(version, kind, &buf[HEADER_SIZE..]))
+    }
+
+    /// Parse an envelope nested inside an encrypted envelope.
+    pub fn parse_inner(version: u8, buf: &'a [u8]) -> Result<Envelope<'a>, Error> {
+        let (byte, payload) = buf
+            .split_first()
+            .ok_or_else(|| Error::ProtocolError("empty inner envelope".to_string()))?;
+        match Kind::from_u8(*byte) {
+            Some(Kind::Encrypted) => Err(Error::ProtocolError(
+                "nested encrypted envelope".to_string(),
+            )),
+            Some(kind) => Ok(Envelope::new(version, kind, payload)),
+            None => Err(Error::ProtocolError(format!(
+                "unknown payload kind {}",
+                byte
+            ))),
+        }
+    }
+
+    /// Write the envelope as an inner envelope, that is, without the
+    /// magic number and protocol version.
+    pub fn write_inner(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + self.payload.len());
+        buf.push(self.kind.as_u8());
+        buf.extend_from_slice(self.payload);
+        buf
+    }
+
+    /// Size of the envelope when written.
+    pub fn len(&self) -> usize {
+        if self.version == LEGACY_PROTOCOL_VERSION {
+            self.payload.len()
+        } else {
+            HEADER_SIZE + self.payload.len()
+        }
+    }
+
+    /// Check if the envelope is empty, which it is only for a legacy
+    /// envelope without payload.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the envelope to a buffer.
+    ///
+    /// Legacy envelopes are written without a header, so they can
+    /// only carry messages.
+    pub fn write(&self, buf: &mut BytesMut) {
+        buf.reserve(self.len());
+        if self.version != LEGACY_PROTOCOL_VERSION {
+            buf.put_slice(&header(self.version, self.kind));
+        }
+        buf.put_slice(self.payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_written_envelope() {
+        let mut buf = BytesMut::new();
+        Envelope::new(PROTOCOL_VERSION, Kind::Authenticated, b"payload").write(&mut buf);
+        let envelope = Envelope::parse(&buf).unwrap();
+        assert_eq!(envelope.version, PROTOCOL_VERSION);
+        assert_eq!(envelope.kind, Kind::Authenticated);
+        assert_eq!(envelope.payload, b"payload");
+    }
+
+    #[test]
+    fn datagrams_without_magic_are_legacy() {
+        let envelope = Envelope::parse(b"payload").unwrap();
+        assert_eq!(envelope.version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(envelope.kind, Kind::Message);
+        assert_eq!(envelope.payload, b"payload");
+
+        let mut buf = BytesMut::new();
+        envelope.write(&mut buf);
+        assert_eq!(&buf[..], b"payload");
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let unknown_kind = [&MAGIC[..], &[PROTOCOL_VERSION, 0]].concat();
+        let datagrams = vec![
+            MAGIC.to_vec(),
+            header(LEGACY_PROTOCOL_VERSION, Kind::Message).to_vec(),
+            header(PROTOCOL_VERSION + 1, Kind::Message).to_vec(),
+            header(u8::MAX, Kind::Message).to_vec(),
+            unknown_kind,
+        ];
+        for datagram in datagrams {
+            assert!(
+                Envelope::parse(&datagram).is_err(),
+                "accepted datagram {:?}",
+                datagram
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_inner_envelopes() {
+        assert!(Envelope::parse_inner(PROTOCOL_VERSION, b"").is_err());
+        assert!(Envelope::parse_inner(PROTOCOL_VERSION, &[0]).is_err());
+        let nested = [Kind::Encrypted.as_u8()];
+        assert!(Envelope::parse_inner(PROTOCOL_VERSION, &nested).is_err());
+
+        let inner = Envelope::new(PROTOCOL_VERSION, Kind::Message, b"payload").write_inner();
+        let envelope = Envelope::parse_inner(PROTOCOL_VERSION, &inner).unwrap();
+        assert_eq!(envelope.kind, Kind::Message);
+        assert_eq!(envelope.payload, b"payload");
+    }
+}